@@ -0,0 +1,126 @@
+use std::thread;
+use std::time::Duration;
+use kvs::{IndexMode, KvStore, KvStoreOptions, KvsEngine, SledKvsEngine, SyncPolicy};
+use rand::{FromEntropy, Rng, SeedableRng, random};
+use rand::rngs::SmallRng;
+use criterion::{criterion_group, criterion_main, Criterion};
+use criterion::{BenchmarkId, Throughput};
+use tempfile::TempDir;
+
+const POLICIES: &[(&str, SyncPolicy)] = &[
+    ("never", SyncPolicy::Never),
+    ("every_write", SyncPolicy::EveryWrite),
+    ("interval_10ms", SyncPolicy::Interval(Duration::from_millis(10))),
+    ("every_64KiB", SyncPolicy::EveryNBytes(64 * 1024)),
+];
+
+fn write_100<E: KvsEngine>(store: &E, key_rng: &mut SmallRng, value_rng: &mut SmallRng) {
+    for _ in 0..100 {
+        store.set(key_rng.gen_range(1, 100000).to_string(),
+                  value_rng.gen_range(1, 100000).to_string()).unwrap();
+    }
+}
+
+pub fn sync_benchmark(c: &mut Criterion) {
+    let mut c = c.benchmark_group("sync-policy");
+    c.sample_size(10);
+    let seed: u64 = random();
+
+    for (name, policy) in POLICIES {
+        c.bench_with_input(BenchmarkId::new("kvs_write", name), policy,
+        |b, policy| {
+            let dir = TempDir::new().expect("unable to create temporary working directory");
+            let mut key_rng = SmallRng::seed_from_u64(seed);
+            let mut value_rng = SmallRng::from_entropy();
+            let store = KvStore::open_with(dir.path(), KvStoreOptions::new().sync_policy(*policy)).unwrap();
+            b.iter(|| write_100(&store, &mut key_rng, &mut value_rng));
+        });
+
+        c.bench_with_input(BenchmarkId::new("sled_write", name), policy,
+        |b, policy| {
+            let dir = TempDir::new().expect("unable to create temporary working directory");
+            let mut key_rng = SmallRng::seed_from_u64(seed);
+            let mut value_rng = SmallRng::from_entropy();
+            let store = SledKvsEngine::open_with(dir.path(), *policy).unwrap();
+            b.iter(|| write_100(&store, &mut key_rng, &mut value_rng));
+        });
+    }
+}
+
+// Group commit lets concurrent writers share one flush and sync,
+// compare the throughput with the single writer case.
+pub fn concurrent_benchmark(c: &mut Criterion) {
+    let mut c = c.benchmark_group("concurrent-write");
+    c.sample_size(10);
+
+    for (name, policy) in &[POLICIES[0], POLICIES[1]] {
+        for threads in [1, 4, 8].iter() {
+            c.throughput(Throughput::Elements(*threads as u64 * 100));
+            c.bench_with_input(BenchmarkId::new(format!("kvs_{}", name), threads), threads,
+            |b, &threads| {
+                let dir = TempDir::new().expect("unable to create temporary working directory");
+                let store = KvStore::open_with(dir.path(), KvStoreOptions::new().sync_policy(*policy)).unwrap();
+                b.iter(|| {
+                    let handles: Vec<_> = (0..threads).map(|_| {
+                        let store = store.clone();
+                        thread::spawn(move || {
+                            write_100(&store, &mut SmallRng::from_entropy(), &mut SmallRng::from_entropy())
+                        })
+                    }).collect();
+                    for handle in handles {
+                        handle.join().unwrap();
+                    }
+                });
+            });
+        }
+    }
+}
+
+pub fn read_benchmark(c: &mut Criterion) {
+    let mut c = c.benchmark_group("engine-read");
+    let seed: u64 = random();
+    let kvs_dir = TempDir::new().expect("unable to create temporary working directory");
+    let hashed_dir = TempDir::new().expect("unable to create temporary working directory");
+    let sled_dir = TempDir::new().expect("unable to create temporary working directory");
+
+    c.bench_with_input(BenchmarkId::new("kvs_read", seed), &seed,
+    |b, &seed| {
+        let store = KvStore::open(kvs_dir.path()).unwrap();
+        write_100(&store, &mut SmallRng::seed_from_u64(seed), &mut SmallRng::from_entropy());
+        let mut key_rng = SmallRng::seed_from_u64(seed);
+        b.iter(|| {
+            for _ in 0..1000 {
+                store.get(key_rng.gen_range(1, 100000).to_string()).unwrap();
+            }
+        });
+    });
+
+    // a hashed index reads the key of the record found before its value
+    c.bench_with_input(BenchmarkId::new("kvs_hashed_read", seed), &seed,
+    |b, &seed| {
+        let options = KvStoreOptions::new().index_mode(IndexMode::Hashed);
+        let store = KvStore::open_with(hashed_dir.path(), options).unwrap();
+        write_100(&store, &mut SmallRng::seed_from_u64(seed), &mut SmallRng::from_entropy());
+        let mut key_rng = SmallRng::seed_from_u64(seed);
+        b.iter(|| {
+            for _ in 0..1000 {
+                store.get(key_rng.gen_range(1, 100000).to_string()).unwrap();
+            }
+        });
+    });
+
+    c.bench_with_input(BenchmarkId::new("sled_read", seed), &seed,
+    |b, &seed| {
+        let store = SledKvsEngine::open(sled_dir.path()).unwrap();
+        write_100(&store, &mut SmallRng::seed_from_u64(seed), &mut SmallRng::from_entropy());
+        let mut key_rng = SmallRng::seed_from_u64(seed);
+        b.iter(|| {
+            for _ in 0..1000 {
+                store.get(key_rng.gen_range(1, 100000).to_string()).unwrap();
+            }
+        });
+    });
+}
+
+criterion_group!(benches, sync_benchmark, concurrent_benchmark, read_benchmark);
+criterion_main!(benches);
@@ -0,0 +1,60 @@
+//! Memory taken by the index of a `KvStore` per key, for every index mode
+//!
+//! Run with `cargo bench --bench index_memory`, `KVS_BENCH_KEYS` sets the number of keys.
+//! The heap in use is measured before and after opening a store, which rebuilds its index.
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use kvs::{IndexMode, KvStore, KvStoreOptions, KvsEngine, WriteBatch};
+use tempfile::TempDir;
+
+struct Counting;
+
+static IN_USE: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for Counting {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        IN_USE.fetch_add(layout.size(), Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        IN_USE.fetch_sub(layout.size(), Ordering::Relaxed);
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: Counting = Counting;
+
+/// Bytes of heap per key held by a store of `keys` keys of `key_len` bytes
+fn bytes_per_key(mode: IndexMode, keys: usize, key_len: usize) -> f64 {
+    let dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = || KvStoreOptions::new().index_mode(mode);
+    {
+        let store = KvStore::open_with(dir.path(), options()).unwrap();
+        for chunk in (0..keys).collect::<Vec<_>>().chunks(1000) {
+            let mut batch = WriteBatch::new();
+            for i in chunk {
+                batch.set(format!("{:0width$}", i, width = key_len).into_bytes(), b"value".to_vec());
+            }
+            store.apply_batch(batch).unwrap();
+        }
+    }
+    let before = IN_USE.load(Ordering::Relaxed);
+    let store = KvStore::open_with(dir.path(), options()).unwrap();
+    let after = IN_USE.load(Ordering::Relaxed);
+    assert_eq!(store.get("0".repeat(key_len)).unwrap(), Some("value".to_owned()));
+    (after - before) as f64 / keys as f64
+}
+
+fn main() {
+    let keys = std::env::var("KVS_BENCH_KEYS").ok()
+        .and_then(|keys| keys.parse().ok())
+        .unwrap_or(200_000);
+    println!("{:>8} {:>8} {:>14}", "mode", "key_len", "bytes_per_key");
+    for key_len in [16, 64, 256].iter() {
+        for (name, mode) in [("ordered", IndexMode::Ordered), ("hashed", IndexMode::Hashed)].iter() {
+            println!("{:>8} {:>8} {:>14.1}", name, key_len, bytes_per_key(*mode, keys, *key_len));
+        }
+    }
+}
@@ -1,13 +1,21 @@
 use failure::err_msg;
 use structopt::StructOpt;
-use std::{net::TcpStream, net::Shutdown};
+use std::{net::TcpStream, net::Shutdown, path::PathBuf};
 use kvs::*;
 
+const FORMATS: &[&str] = &["text", "hex", "base64"];
+
 #[derive(StructOpt)]
 #[structopt(name = "basic")]
 struct Opt {
     #[structopt(long, global = true, default_value = "127.0.0.1:4000")]
     addr: String,
+    /// How KEY and VALUE arguments are encoded
+    #[structopt(long, global = true, default_value = "text", possible_values(FORMATS))]
+    input: String,
+    /// How values are printed
+    #[structopt(long, global = true, default_value = "text", possible_values(FORMATS))]
+    output: String,
     #[structopt(subcommand)]
     cmd: OptKvs,
 }
@@ -19,7 +27,10 @@ enum OptKvs {
         #[structopt(name = "KEY")]
         key: String,
         #[structopt(name = "VALUE")]
-        value: String
+        value: String,
+        /// Seconds after which the key expires, down to a millisecond
+        #[structopt(long, name = "SECONDS")]
+        ttl: Option<f64>
     },
     Get {
         #[structopt(name = "KEY")]
@@ -28,6 +39,24 @@ enum OptKvs {
     Rm {
         #[structopt(name = "KEY")]
         key: String
+    },
+    /// Replace the value of KEY only if it is the expected one
+    Cas {
+        #[structopt(name = "KEY")]
+        key: String,
+        /// Value expected before, the key should not exist when omitted
+        #[structopt(long)]
+        expected: Option<String>,
+        /// Value to set, the key is removed when omitted
+        #[structopt(long)]
+        new: Option<String>
+    },
+    /// Copy the store into DEST while the server keeps running,
+    /// if it was started with `--allow-checkpoints`
+    Backup {
+        /// Empty or missing directory on the server host, relative to its backup directory
+        #[structopt(name = "DEST")]
+        dest: PathBuf
     }
 }
 
@@ -38,13 +67,22 @@ fn main() -> Result<()> {
     
     request_once(&mut stream, Request::Ping(0), |res| 
         match res {
-            Response::Pong(c) if c == 0 => Ok(()),
+            Response::Pong(0) => Ok(()),
             _ => Err(err_msg("protocol error"))
     })?;
 
+    let output = opt.output;
     match opt.cmd {
-        OptKvs::Set {key , value} => {
-            request_once(&mut stream, Request::Set{key, value}, |res| 
+        OptKvs::Set {key , value, ttl} => {
+            let (key, value) = (decode(&opt.input, &key)?, decode(&opt.input, &value)?);
+            let req = match ttl {
+                // a ttl rounding down to 0 ms would store a key already expired
+                Some(ttl) if ttl.is_finite() && ttl * 1000.0 >= 1.0 =>
+                    Request::SetEx{key, value, ttl_ms: (ttl * 1000.0) as u64},
+                Some(_) => return Err(err_msg("TTL must be a number of seconds of at least 0.001")),
+                None => Request::Set{key, value},
+            };
+            request_once(&mut stream, req, |res| 
                 match res {
                     Response::Success{value:_} => Ok(()),
                     Response::Error{msg: e} =>
@@ -53,10 +91,11 @@ fn main() -> Result<()> {
             })?;
         },
         OptKvs::Get {key} => {
+            let key = decode(&opt.input, &key)?;
             request_once(&mut stream, Request::Get{key}, |res| {
                 match res {
                     Response::Success{value: Some(v)} => 
-                        println!("{}", v),
+                        println!("{}", encode(&output, v)?),
                     Response::Success{value: None} =>
                         println!("Key not found"),
                     Response::Error{msg: e} =>
@@ -67,12 +106,37 @@ fn main() -> Result<()> {
             })?;
         },
         OptKvs::Rm {key} => {
+            let key = decode(&opt.input, &key)?;
             request_once(&mut stream, Request::Rm{key}, |res| 
                 match res {
                     Response::Success{value: _} => Ok(()),
                     Response::Error{msg: e} =>
-                        return Err(err_msg(e)),
-                    _ => return Err(err_msg("Unexpected response"))
+                        Err(err_msg(e)),
+                    _ => Err(err_msg("Unexpected response"))
+            })?;
+        },
+        OptKvs::Cas {key, expected, new} => {
+            let input = opt.input;
+            let key = decode(&input, &key)?;
+            let expected = expected.map(|v| decode(&input, &v)).transpose()?;
+            let new = new.map(|v| decode(&input, &v)).transpose()?;
+            request_once(&mut stream, Request::CompareAndSwap{key, expected, new}, |res| 
+                match res {
+                    Response::Success{value: _} => Ok(()),
+                    Response::ConditionFailed{current: _} =>
+                        Err(err_msg("Condition failed")),
+                    Response::Error{msg: e} =>
+                        Err(err_msg(e)),
+                    _ => Err(err_msg("Unexpected response"))
+            })?;
+        },
+        OptKvs::Backup {dest} => {
+            request_once(&mut stream, Request::Checkpoint{dest}, |res| 
+                match res {
+                    Response::Success{value: _} => Ok(()),
+                    Response::Error{msg: e} =>
+                        Err(err_msg(e)),
+                    _ => Err(err_msg("Unexpected response"))
             })?;
         }
     };
@@ -89,4 +153,24 @@ fn request_once<F: FnMut(Response) -> Result<()>>(stream: &mut TcpStream, req: R
         Ok(true)
     })?;
     Ok(())
-}
\ No newline at end of file
+}
+/// Read a command line argument in given format
+fn decode(format: &str, arg: &str) -> Result<Vec<u8>> {
+    match format {
+        "text" => Ok(arg.as_bytes().to_vec()),
+        "hex" => Ok(hex::decode(arg)?),
+        "base64" => Ok(base64::decode(arg)?),
+        _ => unreachable!()
+    }
+}
+
+/// Print bytes in given format
+fn encode(format: &str, bytes: Vec<u8>) -> Result<String> {
+    match format {
+        "text" => String::from_utf8(bytes)
+            .map_err(|_| err_msg("Value is not valid UTF-8, use `--output hex` or `--output base64`")),
+        "hex" => Ok(hex::encode(bytes)),
+        "base64" => Ok(base64::encode(bytes)),
+        _ => unreachable!()
+    }
+}
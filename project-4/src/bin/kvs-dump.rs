@@ -0,0 +1,52 @@
+use failure::err_msg;
+use structopt::StructOpt;
+use std::{fs::{self, File}, io::{self, BufWriter, Write}, path::PathBuf};
+use kvs::*;
+use kvs::dump::{self, Format};
+
+const ENGINES: &[&str] = &["kvs", "sled"];
+
+/// Write every live pair of a store to a file
+#[derive(StructOpt)]
+#[structopt(name = "kvs-dump")]
+struct Opt {
+    /// Directory of the store
+    #[structopt(name = "DIR")]
+    dir: PathBuf,
+    #[structopt(long, default_value = "kvs", possible_values(ENGINES))]
+    engine: String,
+    #[structopt(long, default_value = "jsonl", possible_values(Format::NAMES))]
+    format: Format,
+    /// File to write, standard output when omitted
+    #[structopt(long)]
+    output: Option<PathBuf>,
+}
+
+fn main() -> Result<()> {
+    let opt = Opt::from_args();
+    // opening sled writes to its directory, so only an existing store is opened
+    let empty = match fs::read_dir(&opt.dir) {
+        Ok(mut entries) => entries.next().is_none(),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => true,
+        Err(e) => return Err(e.into()),
+    };
+    if opt.engine == "sled" && empty {
+        return Err(err_msg(format!("No sled store in {}", opt.dir.display())));
+    }
+    let writer: Box<dyn Write> = match &opt.output {
+        Some(path) => Box::new(File::create(path)?),
+        None => Box::new(io::stdout()),
+    };
+    let writer = BufWriter::new(writer);
+
+    let count = match opt.engine.as_str() {
+        "kvs" => {
+            let store = KvStore::open_read_only(&opt.dir)?;
+            dump::dump(&store, opt.format, writer)?
+        },
+        "sled" => dump::dump(&SledKvsEngine::open(&opt.dir)?, opt.format, writer)?,
+        _ => unreachable!()
+    };
+    eprintln!("{} pairs dumped", count);
+    Ok(())
+}
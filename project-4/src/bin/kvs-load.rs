@@ -0,0 +1,41 @@
+use structopt::StructOpt;
+use std::{fs::File, io::{self, Read}, path::PathBuf};
+use kvs::*;
+use kvs::dump::{self, Format};
+
+const ENGINES: &[&str] = &["kvs", "sled"];
+
+/// Set every pair of a dump written by `kvs-dump` in a store
+#[derive(StructOpt)]
+#[structopt(name = "kvs-load")]
+struct Opt {
+    /// Directory of the store, created when missing
+    #[structopt(name = "DIR")]
+    dir: PathBuf,
+    #[structopt(long, default_value = "kvs", possible_values(ENGINES))]
+    engine: String,
+    #[structopt(long, default_value = "jsonl", possible_values(Format::NAMES))]
+    format: Format,
+    /// File to read, standard input when omitted
+    #[structopt(long)]
+    input: Option<PathBuf>,
+    /// Number of pairs written by each atomic batch
+    #[structopt(long, default_value = "1000")]
+    batch_size: usize,
+}
+
+fn main() -> Result<()> {
+    let opt = Opt::from_args();
+    let reader: Box<dyn Read> = match &opt.input {
+        Some(path) => Box::new(File::open(path)?),
+        None => Box::new(io::stdin()),
+    };
+
+    let count = match opt.engine.as_str() {
+        "kvs" => dump::load(&KvStore::open(&opt.dir)?, opt.format, reader, opt.batch_size)?,
+        "sled" => dump::load(&SledKvsEngine::open(&opt.dir)?, opt.format, reader, opt.batch_size)?,
+        _ => unreachable!()
+    };
+    eprintln!("{} pairs loaded", count);
+    Ok(())
+}
@@ -1,7 +1,7 @@
-use failure::err_msg;
+use failure::{Error, err_msg};
 use structopt::StructOpt;
 use thread_pool::ThreadPool;
-use std::{fs::OpenOptions, net::Shutdown, str};
+use std::{fs::{self, OpenOptions}, io, net::Shutdown, path::{Component, Path, PathBuf}, str, time::Duration};
 use std::env::current_dir;
 use serde::{Serialize, Deserialize};
 #[macro_use]
@@ -11,8 +11,15 @@ extern crate slog_term;
 use slog::Drain;
 use std::net::{TcpListener, TcpStream};
 use kvs::*;
+use kvs::dump;
 
 const ENGINES: &[&str] = &["kvs", "sled"];
+/// Number of pairs written by each batch of a migration
+const MIGRATE_BATCH: usize = 1000;
+/// Default directory of checkpoints, relative to the server directory
+const BACKUP_DIR: &str = "backups";
+/// File marking a migration destination until `kvs.conf` points to it
+const MIGRATING: &str = "MIGRATING";
 
 #[derive(StructOpt)]
 #[structopt(name = "basic")]
@@ -21,6 +28,13 @@ struct Opt {
     addr: String,
     #[structopt(long, default_value = "kvs", possible_values(ENGINES))]
     engine: String,
+    /// Copy the data of the engine recorded in `kvs.conf` to `--engine` before serving
+    #[structopt(long)]
+    migrate: bool,
+    /// Accept the checkpoint requests of clients, each one writes a copy of the store
+    /// in the backup directory
+    #[structopt(long)]
+    allow_checkpoints: bool,
 }
 
 fn main() -> Result<()> {
@@ -36,34 +50,133 @@ fn main() -> Result<()> {
     let listener = TcpListener::bind(opt.addr.clone())?;
     info!(log, "{}", opt.addr);
 
-    if let Ok(conf) = conf_get() {
-        if conf.engine != opt.engine {
-            return Err(err_msg("wrong engine"));
+    // configuration of the engine to migrate from
+    let mut old = None;
+    let conf = match conf_get()? {
+        Some(conf) if conf.engine == opt.engine => conf,
+        Some(conf) if opt.migrate => {
+            let mut new = conf.clone();
+            new.engine = opt.engine.clone();
+            // the engines may not share a directory
+            new.data_dir = Some(PathBuf::from(&opt.engine));
+            let dir = data_dir(&new)?;
+            if dir.join(MIGRATING).exists() {
+                // left by an interrupted migration, `kvs.conf` still points to the source
+                warn!(log, "removing the destination of an interrupted migration");
+                fs::remove_dir_all(&dir)?;
+            }
+            if dir.exists() && fs::read_dir(&dir)?.next().is_some() {
+                return Err(err_msg(format!("Migration destination {} is not empty", dir.display())));
+            }
+            mark_migrating(&dir)?;
+            old = Some(conf);
+            new
+        },
+        Some(_) => return Err(err_msg("wrong engine")),
+        None => {
+            let conf = ServerConf {
+                engine: opt.engine.clone(),
+                data_dir: None,
+                backup_dir: None,
+                kvs: KvStoreOptions::default(),
+                sled: SledConf::default(),
+            };
+            conf_set(Path::new("."), &conf)?;
+            conf
         }
-    } else {
-        conf_set(ServerConf {
-            engine: opt.engine.clone()
-        })?;
-    }
+    };
 
     info!(log, "{}", opt.engine);
 
+    let dir = data_dir(&conf)?;
     match opt.engine.as_str() {
-        "kvs" => serve(&log, KvStore::open(current_dir()?)?, thread_pool::SharedQueueThreadPool::new(10)?, listener)?,
-        "sled" => serve(&log, SledKvsEngine::open(current_dir()?)?, thread_pool::SharedQueueThreadPool::new(10)?, listener)?,
+        "kvs" => {
+            let store = KvStore::open_with(dir, conf.kvs.clone())?;
+            log_recovery(&log, store.recovery_report());
+            if let Some(old) = &old {
+                migrate(&log, old, &store, &conf)?;
+            }
+            serve(&log, store, thread_pool::SharedQueueThreadPool::new(10)?, listener, opt.allow_checkpoints)?
+        },
+        "sled" => {
+            let store = SledKvsEngine::open_with(dir, conf.sled.sync_policy)?;
+            if let Some(old) = &old {
+                migrate(&log, old, &store, &conf)?;
+            }
+            serve(&log, store, thread_pool::SharedQueueThreadPool::new(10)?, listener, opt.allow_checkpoints)?
+        },
         _ => unreachable!()
     };
 
     Ok(())
 }
 
-fn serve<E: KvsEngine, T: ThreadPool>(log: &slog::Logger, engine: E, threads: T, listener: TcpListener) -> Result<()> {
+/// Copy every pair of the store described by `old` into `dest` and check them,
+/// then switch `kvs.conf` to `conf`. Keys set with a ttl keep the time they have left.
+///
+/// A kvs source is read through an ordered index whatever its `index_mode`, as a hashed one
+/// does not scan, and `dest` is checked by lookups of the source keys for the same reason.
+fn migrate<E: KvsEngine>(log: &slog::Logger, old: &ServerConf, dest: &E, conf: &ServerConf) -> Result<()> {
+    info!(log, "migrating from {}", old.engine);
+    let dir = data_dir(old)?;
+    let (count, source, copied) = match old.engine.as_str() {
+        "kvs" => {
+            let options = old.kvs.clone().read_only(true).index_mode(IndexMode::Ordered);
+            let source = KvStore::open_with(dir, options)?;
+            let count = dump::copy(&source, dest, MIGRATE_BATCH)?;
+            (count, dump::checksum(&source)?, dump::checksum_of(&source, dest)?)
+        },
+        "sled" => {
+            let source = SledKvsEngine::open_with(dir, old.sled.sync_policy)?;
+            let count = dump::copy(&source, dest, MIGRATE_BATCH)?;
+            (count, dump::checksum(&source)?, dump::checksum_of(&source, dest)?)
+        },
+        engine => return Err(err_msg(format!("Unknown engine {} in kvs.conf", engine)))
+    };
+    if copied != source {
+        return Err(err_msg(format!("Migration check failed: {} pairs with checksum {:08x} copied, {} with {:08x} expected",
+            copied.0, copied.1, source.0, source.1)));
+    }
+    // the new engine has every pair on disk before `kvs.conf` points to it
+    dest.sync()?;
+    conf_set(Path::new("."), conf)?;
+    fs::remove_file(data_dir(conf)?.join(MIGRATING))?;
+    info!(log, "{} pairs migrated, checksum {:08x}", count, copied.1);
+    Ok(())
+}
+
+/// Create the migration destination `dir` with its `MIGRATING` marker on disk before any data,
+/// so a destination left by a crash is known to be partial
+fn mark_migrating(dir: &Path) -> Result<()> {
+    fs::create_dir_all(dir)?;
+    fs::File::create(dir.join(MIGRATING))?.sync_all()?;
+    fs::File::open(dir)?.sync_all()?;
+    if let Some(parent) = dir.parent() {
+        fs::File::open(parent)?.sync_all()?;
+    }
+    Ok(())
+}
+
+fn log_recovery(log: &slog::Logger, report: &RecoveryReport) {
+    if let Some(tail) = &report.truncated {
+        warn!(log, "dropped torn record: {} bytes at offset {} of {}", tail.dropped, tail.offset, tail.file.display());
+    }
+    if let Some(compaction) = report.compaction {
+        warn!(log, "interrupted compaction recovered: {:?}", compaction);
+    }
+    if let Some(version) = report.upgraded_from {
+        info!(log, "store upgraded from format version {}", version);
+    }
+    info!(log, "{} records restored", report.records);
+}
+
+fn serve<E: KvsEngine, T: ThreadPool>(log: &slog::Logger, engine: E, threads: T, listener: TcpListener, allow_checkpoints: bool) -> Result<()> {
     // accept connections and process them serially
     for stream in listener.incoming() {
         match stream {
             Ok(stream) => {
                 info!(log, "new client");
-                match handle(&log, engine.clone(), &threads, stream) {
+                match handle(log, engine.clone(), &threads, stream, allow_checkpoints) {
                     Ok(_) => 
                         info!(log, "client offline"),
                     Err(e) => 
@@ -76,31 +189,36 @@ fn serve<E: KvsEngine, T: ThreadPool>(log: &slog::Logger, engine: E, threads: T,
     Ok(())
 }
 
-fn handle<E: KvsEngine, T: ThreadPool>(_log: &slog::Logger, engine: E, threads: &T, stream: TcpStream) -> Result<()> {
+fn handle<E: KvsEngine, T: ThreadPool>(_log: &slog::Logger, engine: E, threads: &T, stream: TcpStream, allow_checkpoints: bool) -> Result<()> {
     let mut stream = stream;
     
     threads.spawn(move || {
+        // transaction of the session, rolled back if the connection closes first
+        let mut txn: Option<Transaction<E>> = None;
         Protocol::listen(&mut stream.try_clone().unwrap(), |data: Protocol<Request>| {
             let data = match data.payload {
                 Request::Ping(code) =>  Response::Pong(code),
                 Request::Shutdown => return Ok(true),
-                Request::Set{key,value} => {
-                    match engine.set(key, value) {
-                        Ok(_) => Response::Success{value: None},
-                        Err(e) => Response::Error{msg: e.to_string()}
-                    }
+                // they fill the disk of the server, so an admin has to enable them
+                Request::Checkpoint{..} if !allow_checkpoints =>
+                    Response::Error{msg: "Checkpoints are disabled, start the server with --allow-checkpoints".to_owned()},
+                Request::Begin if txn.is_some() =>
+                    Response::Error{msg: "Transaction already started".to_owned()},
+                Request::Begin => {
+                    txn = Some(engine.begin());
+                    Response::Success{value: None}
                 },
-                Request::Get{key} => {
-                    match engine.get(key) {
-                        Ok(v) => Response::Success{value: v},
-                        Err(e) => Response::Error{msg: e.to_string()}
-                    }
+                Request::Commit => match txn.take() {
+                    Some(txn) => conditional(txn.commit()),
+                    None => Response::Error{msg: "No transaction started".to_owned()}
                 },
-                Request::Rm{key} => {
-                    match engine.remove(key) {
-                        Ok(_) => Response::Success{value: None},
-                        Err(e) => Response::Error{msg: e.to_string()}
-                    }
+                Request::Rollback => match txn.take() {
+                    Some(_) => Response::Success{value: None},
+                    None => Response::Error{msg: "No transaction started".to_owned()}
+                },
+                request => match &mut txn {
+                    Some(txn) => in_transaction(txn, request),
+                    None => execute(&engine, request),
                 },
             };
             Protocol::send(&mut stream, Protocol::new(data))?;
@@ -114,36 +232,186 @@ fn handle<E: KvsEngine, T: ThreadPool>(_log: &slog::Logger, engine: E, threads:
     Ok(())
 }
 
-#[derive(Serialize, Deserialize)]
+/// Run a command request on the engine
+fn execute<E: KvsEngine>(engine: &E, request: Request) -> Response {
+    match request {
+        Request::Set{key,value} => {
+            match engine.set_bytes(key, value) {
+                Ok(_) => Response::Success{value: None},
+                Err(e) => Response::Error{msg: e.to_string()}
+            }
+        },
+        Request::SetEx{key,value,ttl_ms} => {
+            match engine.set_bytes_with_ttl(key, value, Duration::from_millis(ttl_ms)) {
+                Ok(_) => Response::Success{value: None},
+                Err(e) => Response::Error{msg: e.to_string()}
+            }
+        },
+        Request::Get{key} => {
+            match engine.get_bytes(key) {
+                Ok(v) => Response::Success{value: v},
+                Err(e) => Response::Error{msg: e.to_string()}
+            }
+        },
+        Request::Rm{key} => {
+            match engine.remove_bytes(key) {
+                Ok(_) => Response::Success{value: None},
+                Err(e) => Response::Error{msg: e.to_string()}
+            }
+        },
+        Request::Batch{batch} => {
+            match engine.apply_batch(batch) {
+                Ok(_) => Response::Success{value: None},
+                Err(e) => Response::Error{msg: e.to_string()}
+            }
+        },
+        Request::Checkpoint{dest} => {
+            match checkpoint(engine, &dest) {
+                Ok(_) => Response::Success{value: None},
+                Err(e) => Response::Error{msg: e.to_string()}
+            }
+        },
+        Request::CompareAndSwap{key,expected,new} =>
+            conditional(engine.compare_and_swap_bytes(key, expected, new)),
+        Request::SetIfAbsent{key,value} =>
+            conditional(engine.set_if_absent_bytes(key, value)),
+        Request::RemoveIfEquals{key,expected} =>
+            conditional(engine.remove_if_equals_bytes(key, expected)),
+        _ => Response::Error{msg: "Unexpected request".to_owned()}
+    }
+}
+
+/// Run a command request in the transaction of the session
+fn in_transaction<E: KvsEngine>(txn: &mut Transaction<E>, request: Request) -> Response {
+    match request {
+        Request::Get{key} => {
+            match txn.get_bytes(key) {
+                Ok(v) => Response::Success{value: v},
+                Err(e) => error(e)
+            }
+        },
+        Request::Set{key,value} => conditional(txn.set_bytes(key, value)),
+        Request::Rm{key} => conditional(txn.remove_bytes(key)),
+        _ => Response::Error{msg: "Request not allowed in a transaction".to_owned()}
+    }
+}
+
+/// Checkpoint the store with the server configuration into `dest` in the backup directory,
+/// so a server can start from the copy
+fn checkpoint<E: KvsEngine>(engine: &E, dest: &Path) -> Result<()> {
+    let mut conf = conf_get()?.ok_or_else(|| err_msg("kvs.conf not found"))?;
+    let dest = backup_path(&conf, dest)?;
+    engine.checkpoint(&dest)?;
+    // the data is right in the copy
+    conf.data_dir = None;
+    conf_set(&dest, &conf)
+}
+
+/// Path of a checkpoint requested by a client into `dest`, which may not leave the backup directory
+fn backup_path(conf: &ServerConf, dest: &Path) -> Result<PathBuf> {
+    let inside = dest.components().all(|part| matches!(part, Component::Normal(_) | Component::CurDir))
+        && dest.components().any(|part| matches!(part, Component::Normal(_)));
+    if !inside {
+        return Err(err_msg(format!(
+            "Checkpoint destination {} is not a relative path inside the backup directory", dest.display())));
+    }
+    let dir = conf.backup_dir.clone().unwrap_or_else(|| PathBuf::from(BACKUP_DIR));
+    fs::create_dir_all(&dir)?;
+    Ok(dir.join(dest))
+}
+
+#[derive(Serialize, Deserialize, Clone)]
 struct ServerConf {
-    engine: String
+    engine: String,
+    /// Directory of the data, relative to the server directory, which is used when `None`
+    #[serde(default)]
+    data_dir: Option<PathBuf>,
+    /// Directory of the checkpoints requested by clients, relative to the server directory,
+    /// `BACKUP_DIR` when `None`
+    #[serde(default)]
+    backup_dir: Option<PathBuf>,
+    /// Options of the `kvs` engine
+    #[serde(default)]
+    kvs: KvStoreOptions,
+    /// Options of the `sled` engine
+    #[serde(default)]
+    sled: SledConf,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(default)]
+struct SledConf {
+    sync_policy: SyncPolicy,
 }
 
-fn conf_get() -> Result<ServerConf> {
+impl Default for SledConf {
+    fn default() -> Self {
+        SledConf {
+            sync_policy: SyncPolicy::EveryWrite,
+        }
+    }
+}
+
+/// Read `kvs.conf`, `None` when the server directory has none yet
+fn conf_get() -> Result<Option<ServerConf>> {
     let file_options = OpenOptions::new()
     .read(true)
     .open("kvs.conf");
 
     match file_options {
         Ok(file) => {
-            let conf: ServerConf = ron::de::from_reader(file)?;
-            Ok(conf)
+            let conf: ServerConf = ron::de::from_reader(file)
+                .map_err(|e| err_msg(format!("Invalid kvs.conf: {}", e)))?;
+            Ok(Some(conf))
         },
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
         Err(e) => Err(err_msg(e))
     }
 }
 
-fn conf_set(conf: ServerConf) -> Result<()> {
+/// Write `kvs.conf` in `dir` through a temporary file, so it is either the old or the new one after a crash
+fn conf_set(dir: &Path, conf: &ServerConf) -> Result<()> {
+    let tmp_path = dir.join("kvs.conf.tmp");
     let file_options = OpenOptions::new()
     .create(true)
     .write(true)
-    .open("kvs.conf");
+    .truncate(true)
+    .open(&tmp_path);
     
     match file_options {
         Ok(file) => {
-            ron::ser::to_writer(file, &conf)?;
+            ron::ser::to_writer(&file, conf)?;
+            file.sync_all()?;
+            fs::rename(tmp_path, dir.join("kvs.conf"))?;
             Ok(())
         },
         Err(e) => Err(err_msg(e))
     }
-}
\ No newline at end of file
+}
+
+/// Directory of the data of the engine in `conf`
+fn data_dir(conf: &ServerConf) -> Result<PathBuf> {
+    let dir = current_dir()?;
+    Ok(match &conf.data_dir {
+        Some(data_dir) => dir.join(data_dir),
+        None => dir,
+    })
+}
+
+/// Response of a conditional write or a commit, a failed condition is not an error
+fn conditional(result: Result<()>) -> Response {
+    match result {
+        Ok(_) => Response::Success{value: None},
+        Err(e) => error(e)
+    }
+}
+
+/// Response of a failed request, with a distinct one for failed conditions and conflicts
+fn error(e: Error) -> Response {
+    match e.downcast::<KvsError>() {
+        Ok(KvsError::ConditionFailed{current}) => Response::ConditionFailed{current},
+        Ok(KvsError::Conflict{key}) => Response::Conflict{key},
+        Ok(e) => Response::Error{msg: e.to_string()},
+        Err(e) => Response::Error{msg: e.to_string()}
+    }
+}
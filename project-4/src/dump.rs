@@ -0,0 +1,228 @@
+//! Logical dump and load of a store, used by `kvs-dump`, `kvs-load`
+//! and the migration of `kvs-server` between engines
+//!
+//! A dump holds every live pair of a store in key order, with the time left of keys
+//! set with a ttl when it was written, so they expire that much later after a load.
+//! It does not depend on the engine, so it can be loaded into the other one.
+use std::{io::{BufRead, BufReader, Read, Write}, str::FromStr, time::Duration};
+use serde::{Serialize, Deserialize};
+use crate::engine::{KvsEngine, WriteBatch};
+use crate::error::*;
+
+/// File format of a dump
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    /// One JSON object per line
+    JsonLines,
+    /// One RON struct per line
+    Ron,
+    /// BSON documents one after the other
+    Bson,
+}
+
+/// A pair of a dump
+///
+/// Keys and values which are not both UTF-8 are written in base64.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq)]
+struct Pair {
+    key: String,
+    value: String,
+    #[serde(default, skip_serializing_if = "Encoding::is_text")]
+    encoding: Encoding,
+    /// Milliseconds left before the key expires, at least 1, signed as BSON has no unsigned integers
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    ttl_ms: Option<i64>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+enum Encoding {
+    #[default]
+    Text,
+    Base64,
+}
+
+impl Format {
+    /// Names accepted by `from_str`
+    pub const NAMES: &'static [&'static str] = &["jsonl", "ron", "bson"];
+}
+
+impl FromStr for Format {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "jsonl" => Ok(Format::JsonLines),
+            "ron" => Ok(Format::Ron),
+            "bson" => Ok(Format::Bson),
+            _ => Err(err_msg(format!("Unknown dump format {}", s))),
+        }
+    }
+}
+
+impl Encoding {
+    fn is_text(&self) -> bool {
+        *self == Encoding::Text
+    }
+}
+
+impl Pair {
+    fn new(key: Vec<u8>, value: Vec<u8>) -> Self {
+        match (String::from_utf8(key), String::from_utf8(value)) {
+            (Ok(key), Ok(value)) => Pair { key, value, encoding: Encoding::Text, ttl_ms: None },
+            (key, value) => {
+                let key = key.map_or_else(|e| e.into_bytes(), String::into_bytes);
+                let value = value.map_or_else(|e| e.into_bytes(), String::into_bytes);
+                Pair { key: base64::encode(key), value: base64::encode(value), encoding: Encoding::Base64, ttl_ms: None }
+            },
+        }
+    }
+
+    fn into_bytes(self) -> Result<(Vec<u8>, Vec<u8>)> {
+        match self.encoding {
+            Encoding::Text => Ok((self.key.into_bytes(), self.value.into_bytes())),
+            Encoding::Base64 => Ok((base64::decode(self.key)?, base64::decode(self.value)?)),
+        }
+    }
+}
+
+/// Write every live pair of `engine` to `writer`, return the number of pairs
+///
+/// The pairs are read by a single scan, see `KvsEngine::scan_bytes`.
+pub fn dump<E: KvsEngine, W: Write>(engine: &E, format: Format, mut writer: W) -> Result<u64> {
+    let mut count = 0;
+    for pair in engine.scan_bytes(..) {
+        let (key, value) = pair?;
+        let ttl = engine.ttl_bytes(key.clone())?;
+        let mut pair = Pair::new(key, value);
+        // rounded up, a key loaded with no time left would never be seen
+        pair.ttl_ms = ttl.map(|ttl| ttl.as_millis().clamp(1, i64::MAX as u128) as i64);
+        match format {
+            Format::JsonLines => {
+                serde_json::to_writer(&mut writer, &pair)?;
+                writer.write_all(b"\n")?;
+            },
+            Format::Ron => {
+                writer.write_all(ron::ser::to_string(&pair)?.as_bytes())?;
+                writer.write_all(b"\n")?;
+            },
+            Format::Bson => bson::to_document(&pair)?.to_writer(&mut writer)?,
+        }
+        count += 1;
+    }
+    writer.flush()?;
+    Ok(count)
+}
+
+/// Set every pair read from `reader` in `engine`, return the number of pairs
+///
+/// Pairs are applied by batches of `batch_size`, each one atomically, but those with a ttl
+/// are set one by one, as batches hold no ttl.
+/// A dump which fails to parse leaves the batches before the error applied.
+pub fn load<E: KvsEngine, R: Read>(engine: &E, format: Format, reader: R, batch_size: usize) -> Result<u64> {
+    if batch_size == 0 {
+        return Err(err_msg("Batch size must be positive"));
+    }
+    let mut reader = BufReader::new(reader);
+    let mut batch = WriteBatch::new();
+    let mut count = 0;
+    let mut line = String::new();
+    loop {
+        let pair: Pair = match format {
+            Format::JsonLines | Format::Ron => {
+                line.clear();
+                if reader.read_line(&mut line)? == 0 {
+                    break;
+                }
+                if line.trim().is_empty() {
+                    continue;
+                }
+                if format == Format::JsonLines {
+                    serde_json::from_str(&line)?
+                } else {
+                    ron::de::from_str(&line)?
+                }
+            },
+            Format::Bson => {
+                if reader.fill_buf()?.is_empty() {
+                    break;
+                }
+                bson::from_document(bson::Document::from_reader(&mut reader)?)?
+            },
+        };
+        let ttl = match pair.ttl_ms {
+            Some(ttl_ms) if ttl_ms <= 0 => return Err(err_msg(format!("Invalid ttl_ms {} in dump", ttl_ms))),
+            ttl_ms => ttl_ms.map(|ttl_ms| Duration::from_millis(ttl_ms as u64)),
+        };
+        let (key, value) = pair.into_bytes()?;
+        count += 1;
+        if let Some(ttl) = ttl {
+            engine.set_bytes_with_ttl(key, value, ttl)?;
+            continue;
+        }
+        batch.set(key, value);
+        if batch.len() == batch_size {
+            engine.apply_batch(std::mem::take(&mut batch))?;
+        }
+    }
+    engine.apply_batch(batch)?;
+    Ok(count)
+}
+
+/// Set every live pair of `source` in `dest` by batches of `batch_size`, return the number of pairs
+///
+/// As with a load, keys set with a ttl are copied one by one with the time they have left.
+pub fn copy<S: KvsEngine, D: KvsEngine>(source: &S, dest: &D, batch_size: usize) -> Result<u64> {
+    if batch_size == 0 {
+        return Err(err_msg("Batch size must be positive"));
+    }
+    let mut batch = WriteBatch::new();
+    let mut count = 0;
+    for pair in source.scan_bytes(..) {
+        let (key, value) = pair?;
+        count += 1;
+        if let Some(ttl) = source.ttl_bytes(key.clone())? {
+            dest.set_bytes_with_ttl(key, value, ttl)?;
+            continue;
+        }
+        batch.set(key, value);
+        if batch.len() == batch_size {
+            dest.apply_batch(std::mem::take(&mut batch))?;
+        }
+    }
+    dest.apply_batch(batch)?;
+    Ok(count)
+}
+
+/// Number of live pairs of `engine` without ttl and a CRC32 of them in key order,
+/// equal for stores with the same pairs
+///
+/// Keys set with a ttl are left out, as they may expire in one store and not yet in the other.
+pub fn checksum<E: KvsEngine>(engine: &E) -> Result<(u64, u32)> {
+    checksum_of(engine, engine)
+}
+
+/// Like `checksum` of `engine`, for the keys of `keys` only, whose values are read with `get_bytes`
+///
+/// `engine` need not support scans, but its pairs missing from `keys` are not counted.
+pub fn checksum_of<K: KvsEngine, E: KvsEngine>(keys: &K, engine: &E) -> Result<(u64, u32)> {
+    let mut hasher = crc32fast::Hasher::new();
+    let mut count = 0;
+    for pair in keys.scan_bytes(..) {
+        let (key, _) = pair?;
+        if keys.ttl_bytes(key.clone())?.is_some() {
+            continue;
+        }
+        let value = match engine.get_bytes(key.clone())? {
+            Some(value) => value,
+            None => continue,
+        };
+        // lengths keep apart pairs whose concatenations are equal
+        hasher.update(&(key.len() as u64).to_le_bytes());
+        hasher.update(&key);
+        hasher.update(&(value.len() as u64).to_le_bytes());
+        hasher.update(&value);
+        count += 1;
+    }
+    Ok((count, hasher.finalize()))
+}
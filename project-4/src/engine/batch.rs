@@ -0,0 +1,68 @@
+use serde::{Serialize, Deserialize};
+
+/// Writes applied together by `KvsEngine::apply_batch`, either all or none of them
+///
+/// ```no_run
+/// # use kvs::{KvStore, KvsEngine, WriteBatch};
+/// let store = KvStore::open("db")?;
+/// let mut batch = WriteBatch::new();
+/// batch.set(b"to".to_vec(), b"value".to_vec());
+/// batch.remove(b"from".to_vec());
+/// store.apply_batch(batch)?;
+/// # Ok::<(), failure::Error>(())
+/// ```
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct WriteBatch {
+    pub(super) ops: Vec<BatchOp>,
+}
+
+/// A single write of a `WriteBatch`
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub enum BatchOp {
+    /// Set key to value
+    Set {
+        /// key to set
+        key: Vec<u8>,
+        /// value to set
+        value: Vec<u8>,
+    },
+    /// Remove key, nothing is done if it does not exist
+    Remove {
+        /// key to remove
+        key: Vec<u8>,
+    },
+}
+
+impl WriteBatch {
+    /// Create an empty batch
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Set key to value when the batch is applied
+    pub fn set(&mut self, key: Vec<u8>, value: Vec<u8>) {
+        self.ops.push(BatchOp::Set { key, value });
+    }
+
+    /// Remove key when the batch is applied
+    ///
+    /// Unlike `KvsEngine::remove_bytes`, a missing key is not an error.
+    pub fn remove(&mut self, key: Vec<u8>) {
+        self.ops.push(BatchOp::Remove { key });
+    }
+
+    /// Writes of the batch, in the order they are applied
+    pub fn ops(&self) -> &[BatchOp] {
+        &self.ops
+    }
+
+    /// Number of writes in the batch
+    pub fn len(&self) -> usize {
+        self.ops.len()
+    }
+
+    /// Whether the batch has no write
+    pub fn is_empty(&self) -> bool {
+        self.ops.is_empty()
+    }
+}
@@ -0,0 +1,273 @@
+//! Large values kept out of the log, in `N.blob` files
+//!
+//! A `Set` whose value reaches `KvStoreOptions::blob_threshold` writes the value as a plain
+//! `Set` record to the active blob file first, then an `OP_BLOB` record to the log holding
+//! its position, with the same sequence number. The index points to the log record as usual,
+//! so compaction only copies the small pointer records.
+//!
+//! Blob files are numbered on their own and only ever appended to. A new one is started
+//! when the active one reaches `KvStoreOptions::blob_file_size`, and on every `open`,
+//! so a torn tail is never written after. They are listed in the manifest like log files.
+//!
+//! A blob record is live while the index points to a log record pointing to it.
+//! After a compaction, sealed blob files whose share of live bytes is under
+//! `KvStoreOptions::blob_gc_ratio` are collected: their live values are written again,
+//! with new pointer records of the same sequence number, and the file is deleted.
+//! A file holding a version read by a snapshot is left for a later compaction.
+use std::{convert::TryInto, fs::{self, OpenOptions}, io::Write, path::{Path, PathBuf}, sync::{Arc, RwLock, atomic::Ordering}};
+use dashmap::DashMap;
+use crate::error::*;
+use super::{Cmd, CmdPos, CmdReader, CmdWriter, Core, Readers, lock, read_lock};
+use super::record::{HEADER_LEN, Record, parse_header};
+
+/// Position of a blob record
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub(super) struct BlobPos {
+    pub(super) id: u64,
+    pub(super) pos: u64,
+    pub(super) len: u64,
+}
+
+/// The blob file receiving new values, created on the first one
+pub(super) struct BlobWriter {
+    file: Option<CmdWriter>,
+    next_id: u64,
+}
+
+/// A record of a blob file, as listed by `Core::blob_records`
+struct BlobRecord {
+    pos: u64,
+    len: u64,
+    seq: u64,
+    key: Vec<u8>,
+}
+
+impl BlobPos {
+    pub(super) fn to_bytes(self) -> [u8; 24] {
+        let mut buf = [0u8; 24];
+        buf[..8].copy_from_slice(&self.id.to_le_bytes());
+        buf[8..16].copy_from_slice(&self.pos.to_le_bytes());
+        buf[16..].copy_from_slice(&self.len.to_le_bytes());
+        buf
+    }
+
+    /// `buf` holds exactly 24 bytes
+    pub(super) fn from_bytes(buf: &[u8]) -> Self {
+        BlobPos {
+            id: u64::from_le_bytes(buf[..8].try_into().unwrap()),
+            pos: u64::from_le_bytes(buf[8..16].try_into().unwrap()),
+            len: u64::from_le_bytes(buf[16..].try_into().unwrap()),
+        }
+    }
+}
+
+impl BlobWriter {
+    /// Id of the active blob file, if any value was written since `open`
+    pub(super) fn active(&self) -> Option<(u64, u64)> {
+        self.file.as_ref().map(|file| (file.id, file.pos))
+    }
+
+    /// Flush the active blob file, and sync it if `sync`
+    pub(super) fn flush(&mut self, sync: bool) -> Result<()> {
+        if let Some(file) = &mut self.file {
+            file.flush()?;
+            if sync {
+                file.writer.get_ref().sync_data()?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Core {
+    /// Move the value of a large `Set` of sequence number `seq` to the active blob file,
+    /// return the command to write to the log instead. The caller holds `self.writer` lock.
+    pub(super) fn separate(&self, blobs: &mut BlobWriter, seq: u64, cmd: Cmd) -> Result<Cmd> {
+        let threshold = self.options.blob_threshold.unwrap_or(u64::MAX);
+        let (key, value, expires) = match cmd {
+            Cmd::Set { key, value, expires } if value.len() as u64 >= threshold => (key, value, expires),
+            cmd => return Ok(cmd),
+        };
+        let record = Record::new(seq, Cmd::set(key, value, None));
+        let blob = self.write_blob(blobs, &self.encode_counted(&record))?;
+        Ok(Cmd::Blob { key: record.cmd.key().to_vec(), blob, expires })
+    }
+
+    /// Append an encoded record to the active blob file, starting a new one when it is full
+    fn write_blob(&self, blobs: &mut BlobWriter, buf: &[u8]) -> Result<BlobPos> {
+        let full = blobs.file.as_ref().is_none_or(|file| file.pos >= self.options.blob_file_size);
+        if full {
+            // values written to the previous file must be as durable as the log records
+            // pointing to them, which are only synced with the active file
+            blobs.flush(true)?;
+            let id = blobs.next_id;
+            let path = blob_path(&self.dir_path, id);
+            self.update_manifest(|manifest| manifest.blobs.push(id))?;
+            let file = OpenOptions::new().create_new(true).write(true).open(&path)?;
+            self.blob_readers.insert(id, Arc::new(RwLock::new(CmdReader::new(path, id)?)));
+            blobs.file = Some(CmdWriter::new(file, id)?);
+            blobs.next_id += 1;
+        }
+        let file = blobs.file.as_mut().expect("No active blob file");
+        let pos = file.pos;
+        file.write_all(buf)?;
+        Ok(BlobPos { id: file.id, pos, len: buf.len() as u64 })
+    }
+
+    /// Read the value of the blob record at `blob`
+    pub(super) fn read_blob(&self, blob: &BlobPos) -> Result<Vec<u8>> {
+        let reader = self.blob_readers.get(&blob.id)
+            .ok_or_else(|| err_msg(format!("Blob file {} not found", blob.id)))?;
+        let reader = read_lock(&*reader);
+        let mut buf = vec![0u8; blob.len.try_into()?];
+        reader.read_exact_at(&mut buf, blob.pos)?;
+        let record = Record::decode(&buf).map_err(|kind| KvsError::Corruption {
+            file: blob_path(&self.dir_path, blob.id),
+            offset: blob.pos,
+            kind,
+        })?;
+        match record.cmd {
+            Cmd::Set { value, .. } => Ok(value),
+            _ => Err(err_msg("Unexpected Command")),
+        }
+    }
+
+    /// Blob position of the log record at `pos`, `None` if its value is in the log
+    fn blob_of(&self, pos: &CmdPos) -> Result<Option<BlobPos>> {
+        match self.read_record(pos)?.cmd {
+            Cmd::Blob { blob, .. } => Ok(Some(blob)),
+            _ => Ok(None),
+        }
+    }
+
+    /// Collect the sealed blob files with too few live bytes, see module documentation.
+    ///
+    /// Runs after a compaction, with `self.compaction` marked running.
+    pub(super) fn collect_blobs(&self) -> Result<()> {
+        let active = lock(&self.blob_writer).active().map(|(id, _)| id);
+        let mut ids = lock(&self.manifest).blobs.clone();
+        ids.retain(|id| Some(*id) != active);
+        for id in ids {
+            self.collect_blob(id)?;
+        }
+        Ok(())
+    }
+
+    fn collect_blob(&self, id: u64) -> Result<()> {
+        let file_len = fs::metadata(blob_path(&self.dir_path, id))?.len();
+        let records = self.blob_records(id, file_len)?;
+        let mut live = Vec::new();
+        let mut live_bytes = 0;
+        for record in records.iter() {
+            let pos = match self.index.get(&record.key)? {
+                Some(pos) if pos.version == record.seq => pos,
+                _ => continue,
+            };
+            if self.blob_of(&pos)? == Some(BlobPos { id, pos: record.pos, len: record.len }) {
+                live_bytes += record.len;
+                live.push((record, pos));
+            }
+        }
+        if live_bytes > 0 && live_bytes as f64 >= self.options.blob_gc_ratio * file_len as f64 {
+            return Ok(());
+        }
+
+        // values are read before taking the lock, writers are only blocked to write them again
+        let mut values = Vec::new();
+        for (record, pos) in live {
+            let value = self.read_blob(&BlobPos { id, pos: record.pos, len: record.len })?;
+            values.push((record, pos, value));
+        }
+        let mut writer = lock(&self.writer);
+        let mut blobs = lock(&self.blob_writer);
+        // history only changes with `self.writer` lock, an older version may be read by a snapshot
+        let retained = records.iter().any(|record| {
+            self.history.get(&(record.key.clone(), record.seq)).is_some_and(|entry| entry.value().is_some())
+        });
+        if retained {
+            return Ok(());
+        }
+        let start = writer.pos;
+        let mut moved = Vec::new();
+        for (record, pos, value) in values {
+            // a key written since is no longer live here
+            let current = self.index.get(&record.key)?;
+            if current.map(|current| (current.id, current.pos)) != Some((pos.id, pos.pos)) {
+                continue;
+            }
+            let set = Record::new(record.seq, Cmd::set(record.key.clone(), value, None));
+            let blob = self.write_blob(&mut blobs, &set.encode(self.options.compression))?;
+            let log_pos = writer.pos;
+            let pointer = Record::new(record.seq, Cmd::Blob { key: record.key.clone(), blob, expires: pos.expires });
+            writer.write_all(&pointer.encode(self.options.compression))?;
+            let new_pos = CmdPos::new(writer.id, log_pos, writer.pos - log_pos, pos.expires, record.seq);
+            moved.push((record.key.clone(), new_pos));
+        }
+        blobs.flush(true)?;
+        writer.flush()?;
+        writer.writer.get_ref().sync_data()?;
+        self.uncompacted.fetch_add(writer.pos - start, Ordering::Relaxed);
+        // same version, so history and transactions are not concerned
+        for (key, pos) in moved {
+            self.index.insert(key, pos)?;
+        }
+        drop(blobs);
+        drop(writer);
+        // readers holding an older position still read the deleted file through its open handle,
+        // dropped by the next compaction
+        self.update_manifest(|manifest| manifest.blobs.retain(|blob| *blob != id))?;
+        fs::remove_file(blob_path(&self.dir_path, id))?;
+        lock(&self.compaction).retired_blobs.push(id);
+        Ok(())
+    }
+
+    /// List the records of a blob file from their headers, stopping at a torn tail
+    fn blob_records(&self, id: u64, file_len: u64) -> Result<Vec<BlobRecord>> {
+        let reader = self.blob_readers.get(&id)
+            .ok_or_else(|| err_msg(format!("Blob file {} not found", id)))?;
+        let reader = read_lock(&*reader);
+        let mut records = Vec::new();
+        let mut pos = 0;
+        let mut header = [0u8; HEADER_LEN];
+        while pos + HEADER_LEN as u64 <= file_len {
+            reader.read_exact_at(&mut header, pos)?;
+            let (seq, key_len, len) = match parse_header(&header) {
+                Some(parsed) if pos + parsed.2 <= file_len => parsed,
+                _ => break,
+            };
+            let mut key = vec![0u8; key_len];
+            reader.read_exact_at(&mut key, pos + HEADER_LEN as u64)?;
+            records.push(BlobRecord { pos, len, seq, key });
+            pos += len;
+        }
+        Ok(records)
+    }
+}
+
+/// Open readers of the blob files `ids` listed in the manifest of `dir`,
+/// return them with the id of the next blob file, after any file of `dir`
+pub(super) fn open_blobs(dir: &Path, ids: &[u64]) -> Result<(Readers, BlobWriter)> {
+    let readers = DashMap::new();
+    for id in ids.iter() {
+        readers.insert(*id, Arc::new(RwLock::new(CmdReader::new(blob_path(dir, *id), *id)?)));
+    }
+    let last = ids.iter().chain(blob_ids(dir)?.iter()).max().copied();
+    let next_id = last.map_or(1, |id| id + 1);
+    Ok((readers, BlobWriter { file: None, next_id }))
+}
+
+/// Ids of the blob files in `dir`, in order
+pub(super) fn blob_ids(dir: &Path) -> Result<Vec<u64>> {
+    let mut ids: Vec<u64> = fs::read_dir(dir)?.flatten()
+        .map(|entry| entry.path())
+        .filter(|path| path.is_file() && path.extension() == Some("blob".as_ref()))
+        .filter_map(|path| path.file_stem()?.to_str()?.parse().ok())
+        .collect();
+    ids.sort_unstable();
+    Ok(ids)
+}
+
+/// Path of the blob file with given id
+pub(super) fn blob_path(dir: &Path, id: u64) -> PathBuf {
+    dir.join(id.to_string() + ".blob")
+}
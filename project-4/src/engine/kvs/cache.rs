@@ -0,0 +1,130 @@
+//! Cache of recently read values, see `KvStoreOptions::cache_size`
+//!
+//! Entries are keyed by key and tagged with the version they were read at, a lookup
+//! only hits when the version matches the one found in the index. A write or a removal
+//! makes older entries unreachable even if a reader inserts one after it, and compaction
+//! or blob collection move records without changing their version or value.
+//! Writes still drop the entry of their key so it does not hold memory.
+//!
+//! Eviction follows the CLOCK algorithm: a hit marks its entry, the hand sweeping for
+//! room clears the marks and evicts the first entry it finds unmarked.
+use std::{collections::HashMap, sync::{Mutex, atomic::{AtomicU64, Ordering}}};
+
+/// Bytes accounted for an entry besides its key and value
+const ENTRY_OVERHEAD: u64 = 64;
+
+pub(super) struct ValueCache {
+    capacity: u64,
+    clock: Mutex<Clock>,
+    pub(super) hits: AtomicU64,
+    pub(super) misses: AtomicU64,
+}
+
+#[derive(Default)]
+struct Clock {
+    slots: Vec<Option<Entry>>,
+    /// Slot of every cached key
+    map: HashMap<Vec<u8>, usize>,
+    free: Vec<usize>,
+    hand: usize,
+    size: u64,
+}
+
+struct Entry {
+    key: Vec<u8>,
+    version: u64,
+    value: Vec<u8>,
+    referenced: bool,
+}
+
+impl ValueCache {
+    /// A cache holding up to `capacity` bytes of keys and values
+    pub(super) fn new(capacity: u64) -> Self {
+        ValueCache {
+            capacity,
+            clock: Mutex::new(Clock::default()),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
+
+    /// The value of `key` at `version`, if cached
+    pub(super) fn get(&self, key: &[u8], version: u64) -> Option<Vec<u8>> {
+        let mut clock = self.lock();
+        let value = clock.map.get(key).copied()
+            .and_then(|slot| clock.slots[slot].as_mut())
+            .filter(|entry| entry.version == version)
+            .map(|entry| {
+                entry.referenced = true;
+                entry.value.clone()
+            });
+        let counter = if value.is_some() { &self.hits } else { &self.misses };
+        counter.fetch_add(1, Ordering::Relaxed);
+        value
+    }
+
+    /// Cache the value of `key` at `version`, evicting entries to make room
+    pub(super) fn insert(&self, key: &[u8], version: u64, value: &[u8]) {
+        let size = entry_size(key, value);
+        if size > self.capacity {
+            return;
+        }
+        let mut clock = self.lock();
+        clock.remove(key);
+        while clock.size + size > self.capacity {
+            clock.evict();
+        }
+        let entry = Entry { key: key.to_vec(), version, value: value.to_vec(), referenced: false };
+        let slot = match clock.free.pop() {
+            Some(slot) => {
+                clock.slots[slot] = Some(entry);
+                slot
+            },
+            None => {
+                clock.slots.push(Some(entry));
+                clock.slots.len() - 1
+            },
+        };
+        clock.map.insert(key.to_vec(), slot);
+        clock.size += size;
+    }
+
+    /// Drop the entry of `key`, if any
+    pub(super) fn remove(&self, key: &[u8]) {
+        self.lock().remove(key);
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Clock> {
+        self.clock.lock().expect("Can't lock value cache")
+    }
+}
+
+impl Clock {
+    fn remove(&mut self, key: &[u8]) {
+        if let Some(slot) = self.map.remove(key) {
+            let entry = self.slots[slot].take().expect("Cached key without entry");
+            self.size -= entry_size(&entry.key, &entry.value);
+            self.free.push(slot);
+        }
+    }
+
+    /// Evict the next unreferenced entry, the cache holds at least one
+    fn evict(&mut self) {
+        loop {
+            self.hand = (self.hand + 1) % self.slots.len();
+            match &mut self.slots[self.hand] {
+                Some(entry) if entry.referenced => entry.referenced = false,
+                Some(entry) => {
+                    let key = entry.key.clone();
+                    self.remove(&key);
+                    return;
+                },
+                None => {},
+            }
+        }
+    }
+}
+
+fn entry_size(key: &[u8], value: &[u8]) -> u64 {
+    (key.len() + value.len()) as u64 + ENTRY_OVERHEAD
+}
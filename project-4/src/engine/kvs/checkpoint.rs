@@ -0,0 +1,113 @@
+//! Online copy of a store directory
+//!
+//! Sealed logs are never written again, so they are hard linked, falling back to a copy
+//! on another file system. The active log is copied up to its length at the start,
+//! where the writer lock puts it at the end of a complete record or batch.
+//!
+//! Compaction is held back while logs are listed and linked, as it creates and deletes them.
+//! The active log is opened meanwhile, a compaction deleting it later does not stop the copy.
+//! Blob files are handled the same way, the active one is copied up to its length too.
+//! The manifest, taken with the active log, is written last, so an interrupted checkpoint
+//! can not be opened.
+use std::{fs::{self, File}, io::{self, Read, Write}, path::Path};
+use crate::error::*;
+use super::{Core, lock, log_path};
+use super::manifest::Manifest;
+use super::blob::blob_path;
+use super::hint::hint_path;
+
+impl Core {
+    /// Used by `KvsEngine::checkpoint`
+    pub(super) fn checkpoint(&self, dest: &Path) -> Result<()> {
+        if dest.exists() && fs::read_dir(dest)?.next().is_some() {
+            return Err(err_msg(format!("Checkpoint destination {} is not empty", dest.display())));
+        }
+        fs::create_dir_all(dest)?;
+
+        self.pause_compaction()?;
+        let linked = self.link_sealed(dest);
+        self.resume_compaction();
+        let (active, blob, manifest) = linked?;
+
+        let path = log_path(dest, active.1);
+        copy_active(active, &path)?;
+        if let Some(blob) = blob {
+            let path = blob_path(dest, blob.1);
+            copy_active(blob, &path)?;
+        }
+        manifest.save(dest)
+    }
+
+    /// Link sealed logs, their hint files and sealed blob files into `dest`,
+    /// return the active log and blob file opened with their ids and lengths, and the manifest
+    fn link_sealed(&self, dest: &Path) -> Result<(Active, Option<Active>, Manifest)> {
+        let (active, blob, manifest) = {
+            let mut writer = lock(&self.writer);
+            let mut blobs = lock(&self.blob_writer);
+            writer.flush()?;
+            blobs.flush(false)?;
+            let blob = match blobs.active() {
+                Some((id, len)) => Some((File::open(blob_path(&self.dir_path, id))?, id, len)),
+                None => None,
+            };
+            let manifest = lock(&self.manifest).clone();
+            ((File::open(log_path(&self.dir_path, writer.id))?, writer.id, writer.pos), blob, manifest)
+        };
+        for &i in manifest.segments.iter() {
+            link_or_copy(&log_path(&self.dir_path, i), &log_path(dest, i))?;
+            let hint = hint_path(&self.dir_path, i);
+            if hint.exists() {
+                link_or_copy(&hint, &hint_path(dest, i))?;
+            }
+        }
+        for &i in manifest.blobs.iter() {
+            if blob.as_ref().map(|blob| blob.1) != Some(i) {
+                link_or_copy(&blob_path(&self.dir_path, i), &blob_path(dest, i))?;
+            }
+        }
+        Ok((active, blob, manifest))
+    }
+
+    /// Wait for a running compaction and keep new ones from starting, see `resume_compaction`
+    fn pause_compaction(&self) -> Result<()> {
+        let mut state = lock(&self.compaction);
+        while state.running {
+            state = self.compaction_done.wait(state).expect("Can't wait for compaction");
+        }
+        if let Some(e) = &state.failed {
+            // its logs are left for the next `open` to recover
+            return Err(err_msg(format!("Compaction failed: {}", e)));
+        }
+        state.running = true;
+        Ok(())
+    }
+
+    fn resume_compaction(&self) {
+        let mut state = lock(&self.compaction);
+        state.running = false;
+        self.compaction_done.notify_all();
+    }
+}
+
+/// An active file opened with its id and the length to copy
+type Active = (File, u64, u64);
+
+/// Copy an active file up to its length at the start of the checkpoint
+fn copy_active((mut file, _, len): Active, to: &Path) -> Result<()> {
+    let mut copy = File::create(to)?;
+    let copied = io::copy(&mut (&mut file).take(len), &mut copy)?;
+    if copied != len {
+        return Err(err_msg("Active file shorter than written"));
+    }
+    copy.sync_all()?;
+    Ok(())
+}
+
+/// Hard link `from` to `to`, copy it if they are not on the same file system
+fn link_or_copy(from: &Path, to: &Path) -> Result<()> {
+    if fs::hard_link(from, to).is_err() {
+        fs::copy(from, to)?;
+        File::open(to)?.sync_all()?;
+    }
+    Ok(())
+}
@@ -0,0 +1,154 @@
+//! Group commit of concurrent writes
+//!
+//! Writers queue their commands, the first one finding no running leader becomes it.
+//! The leader writes every queued command, flushes and syncs once for the whole group,
+//! applies the index updates in log order, then wakes up the waiters.
+//! Commands queued meanwhile are written by the next leader, picked among the waiters.
+//!
+//! A batch is written as one unit between begin and commit records, see `record` module.
+use std::{collections::HashMap, io::Write, mem, sync::atomic::Ordering};
+use crate::error::*;
+use super::{Cmd, CmdPos, CmdWriter, Core, lock};
+use super::blob::BlobWriter;
+use super::record::{self, Record};
+
+/// What a writer appends to the log
+pub(super) enum LogWrite {
+    Cmd(Cmd),
+    /// Commands applied all or none after a crash
+    Batch(Vec<Cmd>),
+}
+
+/// Queue of commands waiting to be written
+#[derive(Default)]
+pub(super) struct CommitState {
+    pending: Vec<(u64, LogWrite)>,
+    /// Results of written commands, not yet taken by their writers
+    done: HashMap<u64, std::result::Result<(), String>>,
+    next_ticket: u64,
+    leading: bool,
+}
+
+impl Core {
+    /// Used by `set` and `remove`, write the command and update `self.index` once it is in the log
+    pub(super) fn append(&self, cmd: Cmd) -> Result<()> {
+        self.submit(LogWrite::Cmd(cmd))
+    }
+
+    /// Like `append` for the commands of a batch
+    pub(super) fn append_batch(&self, cmds: Vec<Cmd>) -> Result<()> {
+        self.submit(LogWrite::Batch(cmds))
+    }
+
+    fn submit(&self, write: LogWrite) -> Result<()> {
+        let mut state = lock(&self.commit);
+        let ticket = state.next_ticket;
+        state.next_ticket += 1;
+        state.pending.push((ticket, write));
+        loop {
+            if let Some(result) = state.done.remove(&ticket) {
+                return result.map_err(err_msg);
+            }
+            if state.leading {
+                state = self.committed.wait(state).expect("Can't wait for group commit");
+                continue;
+            }
+
+            state.leading = true;
+            let group = mem::take(&mut state.pending);
+            drop(state);
+            let tickets: Vec<u64> = group.iter().map(|(ticket, _)| *ticket).collect();
+            // every command of the group shares the result of the single flush
+            let result = self.write_group(group).map_err(|e| e.to_string());
+            state = lock(&self.commit);
+            state.leading = false;
+            for ticket in tickets {
+                state.done.insert(ticket, result.clone());
+            }
+            self.committed.notify_all();
+        }
+    }
+
+    /// Write a group of commands with `self.writer` lock to ensure data consistency
+    fn write_group(&self, group: Vec<(u64, LogWrite)>) -> Result<()> {
+        let mut writer = lock(&self.writer);
+        self.write_locked(&mut writer, group.into_iter().map(|(_, write)| write))
+    }
+
+    /// Write commands then update the index, the caller holds `self.writer` lock
+    ///
+    /// The index only changes under that lock, so a caller can check it before writing.
+    /// A full active log is rolled first, unless a compaction is running, see `segment` module.
+    pub(super) fn write_locked(&self, writer: &mut CmdWriter, writes: impl Iterator<Item = LogWrite>) -> Result<()> {
+        if writer.pos >= self.options.max_segment_size && !lock(&self.compaction).running {
+            self.roll(writer)?;
+        }
+        let start = writer.pos;
+        let mut blobs = lock(&self.blob_writer);
+        let mut written = Vec::new();
+        for write in writes {
+            match write {
+                LogWrite::Cmd(cmd) => self.write_cmd(writer, &mut blobs, cmd, &mut written)?,
+                LogWrite::Batch(cmds) => {
+                    let seq = self.seq.fetch_add(1, Ordering::SeqCst);
+                    writer.write_all(&record::encode_batch_begin(seq, cmds.len() as u32))?;
+                    for cmd in cmds {
+                        self.write_cmd(writer, &mut blobs, cmd, &mut written)?;
+                    }
+                    let seq = self.seq.fetch_add(1, Ordering::SeqCst);
+                    writer.write_all(&record::encode_batch_commit(seq))?;
+                },
+            }
+        }
+        // values are readable and durable before the records pointing to them
+        blobs.flush(false)?;
+        writer.flush()?;
+        // the values in blob files count as written too, not only the records pointing to them
+        let blob_bytes: u64 = written.iter()
+            .map(|(cmd, _)| match cmd {
+                Cmd::Blob { blob, .. } => blob.len,
+                _ => 0,
+            })
+            .sum();
+        if self.sync.wrote(writer.pos - start + blob_bytes) {
+            blobs.flush(true)?;
+            writer.writer.get_ref().sync_data()?;
+        }
+        drop(blobs);
+        self.uncompacted.fetch_add(writer.pos - start, Ordering::Relaxed);
+
+        // in log order, so the last write to a key wins as it does on replay
+        for (cmd, pos) in written {
+            if let Some(cache) = &self.cache {
+                cache.remove(cmd.key());
+            }
+            match cmd {
+                Cmd::Set { key, .. } | Cmd::Blob { key, .. } => {
+                    self.retain_version(&key, pos.version, false)?;
+                    self.index.insert(key, pos)?;
+                },
+                Cmd::Rm { key } => {
+                    self.retain_version(&key, pos.version, true)?;
+                    self.index.remove(&key)?;
+                },
+            }
+        }
+        Ok(())
+    }
+
+    /// Write a single command record, its index update is pushed to `written`
+    ///
+    /// A large value goes to the active blob file first, see `blob` module.
+    fn write_cmd(&self, writer: &mut CmdWriter, blobs: &mut BlobWriter, cmd: Cmd, written: &mut Vec<(Cmd, CmdPos)>) -> Result<()> {
+        let pos = writer.pos;
+        let seq = self.seq.fetch_add(1, Ordering::SeqCst);
+        let record = Record::new(seq, self.separate(blobs, seq, cmd)?);
+        writer.write_all(&self.encode_counted(&record))?;
+        let expires = match &record.cmd {
+            Cmd::Set { expires, .. } | Cmd::Blob { expires, .. } => *expires,
+            Cmd::Rm { .. } => None,
+        };
+        written.push((record.cmd, CmdPos::new(writer.id, pos, writer.pos - pos, expires, record.seq)));
+        Ok(())
+    }
+}
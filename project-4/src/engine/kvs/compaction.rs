@@ -0,0 +1,128 @@
+//! Crash safety of the compaction process
+//!
+//! Before touching any log file, `compact` writes its intent into the manifest
+//! and updates it after every step that can not be redone blindly, see `manifest` module.
+//! On `open`, `recover` reads it back to finish or roll back the interrupted compaction.
+//!
+//! Compaction itself runs on the thread owned by `Compactor`.
+use std::{fs, path::Path, thread::{self, JoinHandle}};
+use crossbeam_channel::{Sender, bounded};
+use serde::{Serialize, Deserialize};
+use crate::error::*;
+use super::{Core, log_path};
+use super::hint::hint_path;
+use super::manifest::Manifest;
+use super::recovery::CompactionRecovery;
+
+/// How far a compaction has gone
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) enum CompactProgress {
+    /// Lock taken, `targets` may be partially written
+    Started,
+    /// `targets` are complete and synced and replace `sources` in the manifest,
+    /// the active segment follows them, `sources` can be deleted
+    TargetWritten,
+}
+
+/// Intent of a compaction, kept in `Manifest::compaction`
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub(super) struct CompactIntent {
+    /// The sealed log files being merged
+    pub(super) sources: Vec<u64>,
+    /// The log files live records are copied into, each one is added before it is created
+    pub(super) targets: Vec<u64>,
+    pub(super) progress: CompactProgress,
+}
+
+/// Bring log files back to a consistent layout after an interrupted compaction.
+///
+/// Depending on how far it went, the compaction is
+/// - finished, when `targets` were completely written. They already replace the sources
+///   in the manifest, the remaining sources are deleted.
+/// - rolled back, otherwise. Sources are left untouched and new writes went to other
+///   segments, so the partial targets are just deleted.
+///
+/// The intent is then cleared from `manifest`, which is saved.
+pub(super) fn recover(dir: &Path, manifest: &mut Manifest) -> Result<CompactionRecovery> {
+    let intent = match manifest.compaction.take() {
+        Some(intent) => intent,
+        None => return Err(err_msg("No compaction to recover")),
+    };
+    let recovery = match intent.progress {
+        CompactProgress::TargetWritten => {
+            if let Some(id) = intent.targets.iter().find(|id| !log_path(dir, **id).is_file()) {
+                return Err(err_msg(format!("Compact target log file {} is missing", id)));
+            }
+            for id in intent.sources {
+                remove_segment(dir, id)?;
+            }
+            CompactionRecovery::Finished
+        },
+        CompactProgress::Started => {
+            for id in intent.targets {
+                remove_segment(dir, id)?;
+            }
+            CompactionRecovery::RolledBack
+        },
+    };
+
+    manifest.save(dir)?;
+    Ok(recovery)
+}
+
+/// Delete a log file if it exists, with its hint file
+pub(super) fn remove_segment(dir: &Path, id: u64) -> Result<()> {
+    for path in [log_path(dir, id), hint_path(dir, id)].iter() {
+        if path.is_file() {
+            fs::remove_file(path)?;
+        }
+    }
+    Ok(())
+}
+
+/// Background thread running compactions of a `KvStore`
+///
+/// The thread stops and is joined when the `Compactor` is dropped.
+pub(super) struct Compactor {
+    signal: Option<Sender<()>>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl Compactor {
+    pub(super) fn spawn(core: Core) -> Result<Self> {
+        // a pending signal is enough, requests coming meanwhile are merged into it
+        let (tx, rx) = bounded::<()>(1);
+        let handle = thread::Builder::new()
+            .name("kvs-compactor".to_owned())
+            .spawn(move || {
+                while rx.recv().is_ok() {
+                    // a failure is kept in `core` and reported to writers
+                    let _ = core.compact();
+                }
+            })?;
+        Ok(Compactor {
+            signal: Some(tx),
+            handle: Some(handle),
+        })
+    }
+
+    /// Ask for a compaction, never blocks
+    pub(super) fn signal(&self) {
+        if let Some(signal) = &self.signal {
+            // `Full` means a compaction is already pending
+            let _ = signal.try_send(());
+        }
+    }
+}
+
+impl Drop for Compactor {
+    fn drop(&mut self) {
+        // disconnect the channel, the thread exits after the running compaction
+        self.signal.take();
+        if let Some(handle) = self.handle.take() {
+            if handle.join().is_err() && !thread::panicking() {
+                panic!("Compaction thread panicked");
+            }
+        }
+    }
+}
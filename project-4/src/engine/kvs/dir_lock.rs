@@ -0,0 +1,45 @@
+//! Exclusion between processes opening a same store directory
+//!
+//! A writable store holds an exclusive advisory lock on the `LOCK` file of its directory,
+//! a read-only one a shared lock, from `open` until its last clone is dropped.
+//! Locks are taken per open, so a second `open` of the directory in the same process
+//! is refused as well.
+//!
+//! `LOCK` is created by the first writable `open` and never deleted. A read-only store
+//! writes nothing, so it only locks a `LOCK` already there, and takes no lock otherwise.
+use std::{fs::{File, OpenOptions, TryLockError}, io, path::{Path, PathBuf}};
+use crate::error::*;
+
+const LOCK: &str = "LOCK";
+
+/// Lock on a store directory, released when dropped
+pub(super) struct DirLock {
+    /// `None` for a read-only store of a directory without `LOCK`
+    _file: Option<File>,
+}
+
+impl DirLock {
+    /// Lock `dir`, shared when `read_only`
+    ///
+    /// # Errors
+    ///
+    /// `KvsError::InUse` is returned when another store holds a conflicting lock.
+    pub(super) fn acquire(dir: &Path, read_only: bool) -> Result<Self> {
+        let path = dir.join(LOCK);
+        let file = if read_only {
+            match File::open(&path) {
+                Ok(file) => file,
+                Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(DirLock { _file: None }),
+                Err(e) => return Err(e.into()),
+            }
+        } else {
+            OpenOptions::new().create(true).truncate(false).write(true).open(&path)?
+        };
+        let locked = if read_only { file.try_lock_shared() } else { file.try_lock() };
+        match locked {
+            Ok(()) => Ok(DirLock { _file: Some(file) }),
+            Err(TryLockError::WouldBlock) => Err(KvsError::InUse { path: PathBuf::from(dir) }.into()),
+            Err(TryLockError::Error(e)) => Err(e.into()),
+        }
+    }
+}
@@ -0,0 +1,126 @@
+//! Hint files let `restore` rebuild the index without reading the values of sealed logs
+//!
+//! Compaction writes `N.hint` next to the sealed log `N.log` it produces.
+//! The file starts with a header, followed by one entry per record of the log.
+//! All integers are little-endian.
+//!
+//! ```text
+//! header: | magic "KVSH" | version | reserved | log_len |
+//!         |      4       |    1    |    3     |    8    |
+//! entry:  | crc32 | file_id | offset | len | seq | expires | key_len | key |
+//!         |   4   |    8    |   8    |  8  |  8  |    8    |    4    | ... |
+//! ```
+//!
+//! An entry with `len` 0 is a tombstone, `expires` 0 means the value never expires. `log_len` must match the size of the log,
+//! otherwise the hint file is ignored and the log is replayed instead.
+use std::{convert::TryInto, fs::{self, File, OpenOptions}, io::{BufReader, BufWriter, Read, Write}, path::{Path, PathBuf}};
+use crate::error::*;
+
+const MAGIC: &[u8; 4] = b"KVSH";
+const HINT_VERSION: u8 = 2;
+const HEADER_LEN: usize = 16;
+const ENTRY_HEADER_LEN: usize = 48;
+
+/// Position of a key in a sealed log
+pub(super) struct HintEntry {
+    pub(super) key: Vec<u8>,
+    pub(super) file_id: u64,
+    pub(super) offset: u64,
+    /// 0 for a tombstone
+    pub(super) len: u64,
+    pub(super) seq: u64,
+    pub(super) expires: Option<u64>,
+}
+
+impl HintEntry {
+    pub(super) fn new(key: Vec<u8>, file_id: u64, offset: u64, len: u64, seq: u64, expires: Option<u64>) -> Self {
+        HintEntry { key, file_id, offset, len, seq, expires }
+    }
+}
+
+/// Path of the hint file of log file with given id
+pub(super) fn hint_path(path: &Path, id: u64) -> PathBuf {
+    path.join(id.to_string()+".hint")
+}
+
+/// Write the hint file of log `id`, replacing any existing one atomically
+pub(super) fn write_hint(dir: &Path, id: u64, log_len: u64, entries: &[HintEntry]) -> Result<()> {
+    let tmp_path = dir.join(id.to_string()+".hint.tmp");
+    let file = OpenOptions::new()
+        .create(true).write(true).truncate(true).open(&tmp_path)?;
+    let mut writer = BufWriter::new(file);
+
+    let mut header = [0u8; HEADER_LEN];
+    header[..4].copy_from_slice(MAGIC);
+    header[4] = HINT_VERSION;
+    header[8..].copy_from_slice(&log_len.to_le_bytes());
+    writer.write_all(&header)?;
+
+    for entry in entries {
+        let key = &entry.key[..];
+        let mut buf = Vec::with_capacity(ENTRY_HEADER_LEN + key.len());
+        buf.extend_from_slice(&[0u8; 4]);
+        buf.extend_from_slice(&entry.file_id.to_le_bytes());
+        buf.extend_from_slice(&entry.offset.to_le_bytes());
+        buf.extend_from_slice(&entry.len.to_le_bytes());
+        buf.extend_from_slice(&entry.seq.to_le_bytes());
+        buf.extend_from_slice(&entry.expires.unwrap_or(0).to_le_bytes());
+        buf.extend_from_slice(&(key.len() as u32).to_le_bytes());
+        buf.extend_from_slice(key);
+        let crc = crc32fast::hash(&buf[4..]);
+        buf[..4].copy_from_slice(&crc.to_le_bytes());
+        writer.write_all(&buf)?;
+    }
+    writer.flush()?;
+    writer.get_ref().sync_all()?;
+    fs::rename(tmp_path, hint_path(dir, id))?;
+    Ok(())
+}
+
+/// Load the hint file of log `id`.
+///
+/// Return `None` when there is no usable hint file, the log should be replayed then.
+pub(super) fn read_hint(dir: &Path, id: u64, log_len: u64) -> Result<Option<Vec<HintEntry>>> {
+    let path = hint_path(dir, id);
+    if !path.is_file() {
+        return Ok(None);
+    }
+    let mut data = Vec::new();
+    BufReader::new(File::open(path)?).read_to_end(&mut data)?;
+    Ok(parse(&data, id, log_len))
+}
+
+fn parse(data: &[u8], id: u64, log_len: u64) -> Option<Vec<HintEntry>> {
+    let u64_at = |buf: &[u8], pos: usize| u64::from_le_bytes(buf[pos..pos + 8].try_into().unwrap());
+    if data.len() < HEADER_LEN || &data[..4] != MAGIC || data[4] != HINT_VERSION
+        || u64_at(data, 8) != log_len {
+        return None;
+    }
+
+    let mut entries = Vec::new();
+    let mut rest = &data[HEADER_LEN..];
+    while !rest.is_empty() {
+        if rest.len() < ENTRY_HEADER_LEN {
+            return None;
+        }
+        let key_len = u32::from_le_bytes(rest[44..48].try_into().unwrap()) as usize;
+        if rest.len() < ENTRY_HEADER_LEN + key_len {
+            return None;
+        }
+        let (entry, next) = rest.split_at(ENTRY_HEADER_LEN + key_len);
+        let crc = u32::from_le_bytes(entry[..4].try_into().unwrap());
+        if crc != crc32fast::hash(&entry[4..]) || u64_at(entry, 4) != id {
+            return None;
+        }
+        entries.push(HintEntry {
+            key: entry[ENTRY_HEADER_LEN..].to_vec(),
+            file_id: id,
+            offset: u64_at(entry, 12),
+            len: u64_at(entry, 20),
+            seq: u64_at(entry, 28),
+            expires: Some(u64_at(entry, 36)).filter(|expires| *expires != 0),
+        });
+        rest = next;
+    }
+    Some(entries)
+}
@@ -0,0 +1,192 @@
+//! In-memory index of a `KvStore`, from each key to the position of its last record
+//!
+//! `IndexMode::Ordered` keeps every key in a `SkipMap`, so lookups and scans never read the disk
+//! for keys, at the cost of holding all of them in memory.
+//!
+//! `IndexMode::Hashed` keeps a 64-bit fingerprint of each key instead, with the position of its record.
+//! Keys sharing a fingerprint are told apart by reading the key of their records from the log,
+//! so a lookup reads the header and key of the record found, and a write reads the one it replaces.
+//! It keeps no key order, so scans are refused with `KvsError::ScanUnsupported`.
+//!
+//! The index only changes under `Core::writer` lock, or before the store is shared on `open`,
+//! so a write can resolve its key on disk and then update the map without racing another one.
+//! An ordered index updates the position of a key in place, as replacing a `SkipMap` entry
+//! unlinks the old one first and a concurrent lookup would find the key missing.
+use std::{collections::hash_map::DefaultHasher, hash::Hasher, ops::Bound, path::PathBuf, sync::{Arc, RwLock}};
+use crossbeam_skiplist::SkipMap;
+use dashmap::DashMap;
+use crate::error::*;
+use super::{CmdPos, IndexMode, Readers, log_path, read_lock};
+use super::record::{HEADER_LEN, parse_header};
+
+pub(super) enum Index {
+    Ordered(Box<SkipMap<Vec<u8>, RwLock<CmdPos>>>),
+    Hashed(HashedIndex),
+}
+
+pub(super) struct HashedIndex {
+    slots: DashMap<u64, Slot>,
+    readers: Arc<Readers>,
+    dir: PathBuf,
+}
+
+/// Positions of the keys of a fingerprint, almost always a single one
+#[derive(Clone)]
+enum Slot {
+    One(CmdPos),
+    Many(Vec<CmdPos>),
+}
+
+impl Index {
+    /// `readers` and `dir` are those of the log files, read by `IndexMode::Hashed`
+    pub(super) fn new(mode: IndexMode, readers: Arc<Readers>, dir: PathBuf) -> Self {
+        match mode {
+            IndexMode::Ordered => Index::Ordered(Box::new(SkipMap::new())),
+            IndexMode::Hashed => Index::Hashed(HashedIndex { slots: DashMap::new(), readers, dir }),
+        }
+    }
+
+    pub(super) fn get(&self, key: &[u8]) -> Result<Option<CmdPos>> {
+        match self {
+            Index::Ordered(map) => Ok(map.get(key).map(|entry| *read(entry.value()))),
+            Index::Hashed(hashed) => Ok(hashed.find(key)?.map(|(_, pos)| pos)),
+        }
+    }
+
+    pub(super) fn insert(&self, key: Vec<u8>, pos: CmdPos) -> Result<()> {
+        match self {
+            Index::Ordered(map) => match map.get(&key) {
+                Some(entry) => *entry.value().write().expect("Can't write index entry") = pos,
+                None => {
+                    map.insert(key, RwLock::new(pos));
+                },
+            },
+            Index::Hashed(hashed) => {
+                let found = hashed.find(&key)?;
+                let fingerprint = fingerprint(&key);
+                let mut positions = hashed.positions(fingerprint);
+                match found {
+                    Some((i, _)) => positions[i] = pos,
+                    None => positions.push(pos),
+                }
+                hashed.set(fingerprint, positions);
+            },
+        }
+        Ok(())
+    }
+
+    /// Remove `key`, return its position if it was indexed
+    pub(super) fn remove(&self, key: &[u8]) -> Result<Option<CmdPos>> {
+        match self {
+            Index::Ordered(map) => Ok(map.remove(key).map(|entry| *read(entry.value()))),
+            Index::Hashed(hashed) => {
+                let (i, pos) = match hashed.find(key)? {
+                    Some(found) => found,
+                    None => return Ok(None),
+                };
+                let fingerprint = fingerprint(key);
+                let mut positions = hashed.positions(fingerprint);
+                positions.remove(i);
+                hashed.set(fingerprint, positions);
+                Ok(Some(pos))
+            },
+        }
+    }
+
+    /// Call `f` with the position of every key, in no particular order
+    pub(super) fn for_each(&self, mut f: impl FnMut(&CmdPos)) {
+        match self {
+            Index::Ordered(map) => map.iter().for_each(|entry| f(&read(entry.value()))),
+            Index::Hashed(hashed) => hashed.slots.iter().for_each(|slot| match slot.value() {
+                Slot::One(pos) => f(pos),
+                Slot::Many(positions) => positions.iter().for_each(&mut f),
+            }),
+        }
+    }
+
+    /// The first key after `from` in key order, for `IndexMode::Ordered` only
+    pub(super) fn next_key(&self, from: Bound<&[u8]>, forward: bool) -> Option<Vec<u8>> {
+        let map = match self {
+            Index::Ordered(map) => map,
+            Index::Hashed(_) => panic!("A hashed index has no key order"),
+        };
+        let entry = if forward { map.lower_bound(from) } else { map.upper_bound(from) };
+        entry.map(|entry| entry.key().clone())
+    }
+
+    /// Whether keys can be iterated in order, see `next_key`
+    pub(super) fn is_ordered(&self) -> bool {
+        matches!(self, Index::Ordered(_))
+    }
+}
+
+impl HashedIndex {
+    /// Index in its slot and position of `key`
+    fn find(&self, key: &[u8]) -> Result<Option<(usize, CmdPos)>> {
+        let slot = self.slots.get(&fingerprint(key)).map(|slot| slot.value().clone());
+        let positions = match &slot {
+            Some(Slot::One(pos)) => std::slice::from_ref(pos),
+            Some(Slot::Many(positions)) => positions.as_slice(),
+            None => &[],
+        };
+        for (i, pos) in positions.iter().enumerate() {
+            if self.read_key(pos)? == key {
+                return Ok(Some((i, *pos)));
+            }
+        }
+        Ok(None)
+    }
+
+    fn positions(&self, fingerprint: u64) -> Vec<CmdPos> {
+        match self.slots.get(&fingerprint).as_deref() {
+            Some(Slot::One(pos)) => vec![*pos],
+            Some(Slot::Many(positions)) => positions.clone(),
+            None => Vec::new(),
+        }
+    }
+
+    fn set(&self, fingerprint: u64, mut positions: Vec<CmdPos>) {
+        match positions.len() {
+            0 => {
+                self.slots.remove(&fingerprint);
+            },
+            1 => {
+                self.slots.insert(fingerprint, Slot::One(positions[0]));
+            },
+            _ => {
+                positions.shrink_to_fit();
+                self.slots.insert(fingerprint, Slot::Many(positions));
+            },
+        }
+    }
+
+    /// Read the key of the record at `pos`, without its value
+    fn read_key(&self, pos: &CmdPos) -> Result<Vec<u8>> {
+        let reader = self.readers.get(&pos.id)
+            .expect("Cannot find log reader");
+        let reader = read_lock(&*reader);
+        let mut header = [0u8; HEADER_LEN];
+        reader.read_exact_at(&mut header, pos.pos)?;
+        let key_len = match parse_header(&header) {
+            Some((_, key_len, len)) if len == pos.len => key_len,
+            _ => return Err(KvsError::Corruption {
+                file: log_path(&self.dir, pos.id),
+                offset: pos.pos,
+                kind: CorruptionKind::InvalidData,
+            }.into()),
+        };
+        let mut key = vec![0u8; key_len];
+        reader.read_exact_at(&mut key, pos.pos + HEADER_LEN as u64)?;
+        Ok(key)
+    }
+}
+
+fn read(pos: &RwLock<CmdPos>) -> std::sync::RwLockReadGuard<'_, CmdPos> {
+    pos.read().expect("Can't read index entry")
+}
+
+fn fingerprint(key: &[u8]) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    hasher.write(key);
+    hasher.finish()
+}
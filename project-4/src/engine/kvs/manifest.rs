@@ -0,0 +1,205 @@
+//! Layout of a store directory, kept in `MANIFEST`
+//!
+//! The manifest is a JSON file holding the format version of the store, its sealed log
+//! files, its active log file, its blob files and the intent of a running compaction,
+//! see `compaction` module.
+//! It is replaced atomically on every change: a new one is written to `MANIFEST.tmp`,
+//! synced, and renamed over the old one.
+//!
+//! Log and blob files are listed before they are created, and deleted after the manifest
+//! stops listing them. After a crash, `restore` creates the active log file if it is missing,
+//! deletes the empty log files and the blob files no longer listed, and unlists missing blob files.
+//!
+//! The format version covers the manifest and the files it lists. `open` refuses a store
+//! written by a newer version, and upgrades an older one step by step, see `upgrade`.
+//! The upgraded manifest is only saved once every log file has been read, and the files
+//! of the older version are removed after that, see `cleanup`. Until then the store
+//! keeps its older version, and an interrupted upgrade starts over on next open.
+//!
+//! | version | layout |
+//! |---------|--------|
+//! | 0       | an empty `.kvs` marker, log files found by their `N.log` name, the highest id is active, logs of the first release hold JSON commands, see `convert_json_logs` |
+//! | 1       | `MANIFEST` |
+//! | 2       | `MANIFEST` listing blob files, found by their `N.blob` name before |
+use std::{fs::{self, File, OpenOptions}, io::{self, Read, Write}, path::Path};
+use serde::{Serialize, Deserialize};
+use crate::error::*;
+use super::{Core, KvStoreOptions, lock, log_path};
+use super::blob::blob_ids;
+use super::compaction::CompactIntent;
+use super::record::{convert_json_log, is_json_log, max_seq};
+use super::segment::log_ids;
+
+/// Format version written by this build
+pub(super) const FORMAT_VERSION: u32 = 2;
+
+const MANIFEST: &str = "MANIFEST";
+/// Marker of a version 0 store
+const LEGACY_MARKER: &str = ".kvs";
+/// Intent of a version 0 compaction, which version 1 keeps in the manifest
+const LEGACY_COMPACT_LOCK: &str = ".compact-lock";
+
+/// Content of `MANIFEST`
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub(super) struct Manifest {
+    pub(super) version: u32,
+    /// Sealed log files, in id order
+    pub(super) segments: Vec<u64>,
+    /// The log file receiving new writes
+    pub(super) active: u64,
+    /// Set while a compaction runs, or after it failed until the store is reopened
+    pub(super) compaction: Option<CompactIntent>,
+    /// Blob files, in id order, see `blob` module
+    #[serde(default)]
+    pub(super) blobs: Vec<u64>,
+}
+
+/// Only the version, read first as the rest of the manifest depends on it
+#[derive(Deserialize)]
+struct Versioned {
+    version: u32,
+}
+
+impl Manifest {
+    /// Manifest of a new store, whose first log file is `active`
+    pub(super) fn new(active: u64) -> Self {
+        Manifest { version: FORMAT_VERSION, segments: Vec::new(), active, compaction: None, blobs: Vec::new() }
+    }
+
+    /// Whether `dir` holds a store, of any version
+    pub(super) fn exists(dir: &Path) -> bool {
+        dir.join(MANIFEST).is_file() || dir.join(LEGACY_MARKER).is_file()
+    }
+
+    /// Read the manifest of the store in `dir`, upgrading an older one.
+    ///
+    /// Return it with the version it was upgraded from, if it was. An upgraded manifest
+    /// is not saved here, the caller saves it once the store is loaded, then calls `cleanup`.
+    ///
+    /// # Errors
+    ///
+    /// `KvsError::UnsupportedFormat` is returned for a store written by a newer version,
+    /// or an older one when `KvStoreOptions::upgrade_format` is off. No file is changed then.
+    /// A read-only store is upgraded in memory only, its files are left as they are,
+    /// so one whose logs hold JSON commands is refused.
+    pub(super) fn load(dir: &Path, options: &KvStoreOptions) -> Result<(Manifest, Option<u32>)> {
+        let path = dir.join(MANIFEST);
+        let (version, data) = if path.is_file() {
+            let mut data = Vec::new();
+            File::open(&path)?.read_to_end(&mut data)?;
+            let versioned: Versioned = serde_json::from_slice(&data)
+                .map_err(|_| err_msg("Unreadable store manifest"))?;
+            (versioned.version, data)
+        } else {
+            (0, Vec::new())
+        };
+        if version == FORMAT_VERSION {
+            let manifest = serde_json::from_slice(&data)
+                .map_err(|_| err_msg("Unreadable store manifest"))?;
+            return Ok((manifest, None));
+        }
+        if version > FORMAT_VERSION || !options.upgrade_format {
+            return Err(KvsError::UnsupportedFormat { version }.into());
+        }
+
+        let manifest = upgrade(dir, version, &data, options.read_only)?;
+        Ok((manifest, Some(version)))
+    }
+
+    /// Persist the manifest, replacing the previous one atomically
+    pub(super) fn save(&self, dir: &Path) -> Result<()> {
+        let tmp_path = dir.join(MANIFEST.to_owned() + ".tmp");
+        let mut file = OpenOptions::new()
+            .create(true).write(true).truncate(true).open(&tmp_path)?;
+        serde_json::ser::to_writer(&mut file, self)?;
+        file.flush()?;
+        file.sync_all()?;
+        fs::rename(tmp_path, dir.join(MANIFEST))?;
+        Ok(())
+    }
+
+    /// Whether the log file `id` is listed
+    pub(super) fn contains(&self, id: u64) -> bool {
+        id == self.active || self.segments.contains(&id)
+    }
+}
+
+/// Build the current manifest of a store of an older `version` from its manifest `data`,
+/// without changing any file but the JSON logs of version 0, see `convert_json_logs`
+fn upgrade(dir: &Path, version: u32, data: &[u8], read_only: bool) -> Result<Manifest> {
+    let mut manifest = match version {
+        0 => {
+            if dir.join(LEGACY_COMPACT_LOCK).is_file() {
+                return Err(err_msg("Store has an unfinished compaction, open it with the previous version to recover it before upgrading"));
+            }
+            let mut segments = log_ids(dir)?;
+            convert_json_logs(dir, &segments, read_only)?;
+            let active = match segments.pop() {
+                Some(id) => id,
+                None => return Err(err_msg(".kvs file exist but no log files")),
+            };
+            Manifest { version: 1, segments, active, compaction: None, blobs: Vec::new() }
+        },
+        1 => serde_json::from_slice(data).map_err(|_| err_msg("Unreadable store manifest"))?,
+        _ => return Err(KvsError::UnsupportedFormat { version }.into()),
+    };
+    if manifest.version == 1 {
+        manifest.blobs = blob_ids(dir)?;
+        manifest.version = 2;
+    }
+    Ok(manifest)
+}
+
+/// Rewrite the JSON log files `ids` of a version 0 store as records, keeping their ids
+///
+/// Each one is written to `N.log.upgrade`, read back, and renamed over `N.log`,
+/// so an interrupted conversion leaves whole logs of either format and goes on at next open.
+/// Sequence numbers follow the ids, as a command used to win over those of lower ids.
+///
+/// # Errors
+///
+/// An error is returned for a `read_only` store holding a JSON log, no file is changed then.
+fn convert_json_logs(dir: &Path, ids: &[u64], read_only: bool) -> Result<()> {
+    let mut json = Vec::new();
+    for id in ids {
+        json.push(is_json_log(&log_path(dir, *id))?);
+    }
+    if !json.contains(&true) {
+        return Ok(());
+    }
+    if read_only {
+        return Err(err_msg("Store has log files of the first release, open it writable to upgrade them"));
+    }
+    let mut seq = 0;
+    for (id, json) in ids.iter().zip(json) {
+        let path = log_path(dir, *id);
+        if !json {
+            seq = seq.max(max_seq(&path)?);
+            continue;
+        }
+        let converted = path.with_extension("log.upgrade");
+        seq = convert_json_log(&path, &converted, seq)?;
+        fs::rename(&converted, &path)?;
+        File::open(dir)?.sync_all()?;
+    }
+    Ok(())
+}
+
+/// Remove the `.kvs` marker of version 0, once the upgraded manifest is saved
+///
+/// A marker left by a crash after the save is removed by the next writable open.
+pub(super) fn cleanup(dir: &Path) -> Result<()> {
+    match fs::remove_file(dir.join(LEGACY_MARKER)) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e.into()),
+        _ => Ok(()),
+    }
+}
+
+impl Core {
+    /// Change the manifest and persist it
+    pub(super) fn update_manifest(&self, f: impl FnOnce(&mut Manifest)) -> Result<()> {
+        let mut manifest = lock(&self.manifest);
+        f(&mut manifest);
+        manifest.save(&self.dir_path)
+    }
+}
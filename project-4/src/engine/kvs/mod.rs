@@ -0,0 +1,1151 @@
+use std::{collections::{BTreeMap, HashMap, HashSet}, convert::TryInto, fs::{self, OpenOptions}, io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write}, ops::RangeBounds, path::{Path, PathBuf}, sync::{Arc, Condvar, Mutex, MutexGuard, RwLock, RwLockReadGuard, RwLockWriteGuard, atomic::{AtomicU64, Ordering}}, time::Duration};
+use std::fs::File;
+use crossbeam_skiplist::SkipMap;
+use dashmap::DashMap;
+use fail::fail_point;
+use system_interface::fs::FileIoExt;
+use crate::error::*;
+use crate::engine::{BatchOp, KvsEngine, ReadView, Scan, SyncPolicy, WriteBatch, expiry_after, now_millis, ttl_left};
+use crate::engine::sync::{SyncState, SyncTicker};
+use self::blob::{BlobPos, BlobWriter};
+use self::cache::ValueCache;
+use self::commit::{CommitState, LogWrite};
+use self::compaction::{CompactIntent, CompactProgress, Compactor, remove_segment};
+use self::dir_lock::DirLock;
+use self::hint::HintEntry;
+use self::index::Index;
+use self::manifest::Manifest;
+use self::record::{Record, RecordReader};
+use self::scan::IndexScan;
+use self::segment::log_ids;
+pub use self::options::{Compression, IndexMode, KvStoreOptions};
+pub use self::recovery::{CompactionRecovery, RecoveryReport, TruncatedTail};
+pub use self::snapshot::Snapshot;
+pub use self::stats::KvStoreStats;
+
+mod blob;
+mod cache;
+mod checkpoint;
+mod commit;
+mod compaction;
+mod dir_lock;
+mod hint;
+mod index;
+mod manifest;
+mod options;
+mod record;
+mod recovery;
+mod scan;
+mod segment;
+mod snapshot;
+mod stats;
+
+/// The `KvStore` stores string key-value pairs
+///
+/// This engine act as a simple and weak Log-Structued Database.
+/// Data will be stored on disk in segments named by id number with `.log` extension,
+/// see `segment` module, every command is framed as a checksummed binary record, see `record` module.
+/// It will keep an ordered `SkipMap` in memory for quick indexing and scans,
+/// or only key fingerprints with `IndexMode::Hashed`, see `index` module.
+///
+/// Compaction runs on a background thread, which is stopped when the last clone is dropped.
+#[derive(Clone)]
+pub struct KvStore {
+    core: Core,
+    /// `None` for a read-only store
+    compactor: Option<Arc<Compactor>>,
+    /// Only for `SyncPolicy::Interval`, kept for its `Drop`
+    _ticker: Option<Arc<SyncTicker>>,
+}
+
+/// State shared by all clones of a `KvStore` and its compaction thread
+#[derive(Clone)]
+struct Core {
+    index: Arc<Index>,
+    /// Versions replaced or removed while a snapshot may still read them, see `snapshot` module
+    history: Arc<History>,
+    /// Sequence numbers of live snapshots, with their count
+    snapshots: Arc<Mutex<BTreeMap<u64, usize>>>,
+    writer: Arc<Mutex<CmdWriter>>,
+    commit: Arc<Mutex<CommitState>>,
+    committed: Arc<Condvar>,
+    readers: Arc<Readers>,
+    /// Locked after `writer` when both are
+    blob_writer: Arc<Mutex<BlobWriter>>,
+    blob_readers: Arc<Readers>,
+    dir_path: Arc<PathBuf>,
+    uncompacted: Arc<AtomicU64>,
+    compaction: Arc<Mutex<CompactState>>,
+    compaction_done: Arc<Condvar>,
+    /// Id of the next log file
+    next_id: Arc<AtomicU64>,
+    /// Locked after `writer` when both are
+    manifest: Arc<Mutex<Manifest>>,
+    seq: Arc<AtomicU64>,
+    recovery: Arc<RecoveryReport>,
+    options: Arc<KvStoreOptions>,
+    sync: Arc<SyncState>,
+    stats: Arc<stats::Counters>,
+    /// `None` when `KvStoreOptions::cache_size` is 0
+    cache: Option<Arc<ValueCache>>,
+    /// Held until the last clone is dropped, see `dir_lock` module
+    _dir_lock: Arc<DirLock>,
+}
+
+/// Open files by id
+type Readers = DashMap<u64, Arc<RwLock<CmdReader>>>;
+
+/// Superseded versions by key and version, `None` for a removal
+type History = SkipMap<(Vec<u8>, u64), Option<CmdPos>>;
+
+/// Only one compaction runs at a time, a failed one leaves its intent in the manifest,
+/// so no more compaction is possible until the store is reopened.
+#[derive(Default)]
+struct CompactState {
+    running: bool,
+    failed: Option<String>,
+    /// Log files deleted by the last compaction, whose readers are dropped by the next one
+    /// so a read racing the index update still finds them
+    retired: Vec<u64>,
+    /// Blob files deleted by the last collection, whose readers are dropped by the next compaction
+    /// so the space is freed once no read can still use them
+    retired_blobs: Vec<u64>,
+}
+
+/// Commands stored in log files
+enum Cmd {
+    /// `expires` is in milliseconds since the Unix epoch
+    Set { key: Vec<u8>, value: Vec<u8>, expires: Option<u64> },
+    Rm { key: Vec<u8> },
+    /// A `Set` whose value is in a blob file, see `blob` module
+    Blob { key: Vec<u8>, blob: BlobPos, expires: Option<u64> },
+}
+
+/// Why a record of the source is copied by compaction, in the order
+/// copies of a same record are written
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum CopyReason {
+    /// An older version read by snapshots
+    Retained,
+    /// The last version of a key
+    Live,
+    /// The last version of a key, expired and replaced by a tombstone
+    Expired,
+    /// A tombstone for a key no longer in the index
+    Tombstone,
+}
+
+/// Store command position in files
+#[derive(Clone, Copy)]
+struct CmdPos {
+    id:  u64,
+    pos: u64,
+    len: u64,
+    expires: Option<u64>,
+    /// Sequence number of the record, the version of the key checked by transactions
+    version: u64,
+}
+
+struct CmdWriter {
+    writer: BufWriter<File>,
+    pos: u64,
+    id:  u64
+}
+struct CmdReader {
+    reader: File,
+    pos: u64,
+    id:  u64
+}
+
+impl KvsEngine for KvStore {
+    fn set_bytes(&self, key: Vec<u8>, value: Vec<u8>) -> Result<()> {
+        self.core.set(key, value, None)?;
+        self.schedule_compaction();
+        Ok(())
+    }
+
+    fn set_bytes_with_ttl(&self, key: Vec<u8>, value: Vec<u8>, ttl: Duration) -> Result<()> {
+        self.core.set(key, value, Some(expiry_after(ttl)))?;
+        self.schedule_compaction();
+        Ok(())
+    }
+
+    fn get_bytes(&self, key: Vec<u8>) -> Result<Option<Vec<u8>>> {
+        self.core.get(key)
+    }
+
+    fn ttl_bytes(&self, key: Vec<u8>) -> Result<Option<Duration>> {
+        Ok(self.core.index.get(&key)?.and_then(|pos| pos.ttl(now_millis())))
+    }
+
+    fn remove_bytes(&self, key: Vec<u8>) -> Result<()> {
+        self.core.remove(key)?;
+        self.schedule_compaction();
+        Ok(())
+    }
+
+    fn compare_and_swap_bytes(&self, key: Vec<u8>, expected: Option<Vec<u8>>, new: Option<Vec<u8>>) -> Result<()> {
+        self.core.compare_and_swap(key, expected, new)?;
+        self.schedule_compaction();
+        Ok(())
+    }
+
+    fn apply_batch(&self, batch: WriteBatch) -> Result<()> {
+        self.core.apply_batch(batch)?;
+        self.schedule_compaction();
+        Ok(())
+    }
+
+    fn get_versioned_bytes(&self, key: Vec<u8>) -> Result<(Option<Vec<u8>>, u64)> {
+        self.core.get_versioned(key)
+    }
+
+    fn commit_versioned(&self, versions: Vec<(Vec<u8>, u64)>, batch: WriteBatch) -> Result<()> {
+        self.core.commit_versioned(versions, batch)?;
+        self.schedule_compaction();
+        Ok(())
+    }
+
+    fn read_view(&self) -> Box<dyn ReadView> {
+        Box::new(self.snapshot())
+    }
+
+    /// Sealed logs are hard linked and the active log is copied up to its current length,
+    /// see `checkpoint` module. The copy holds every write finished before the call.
+    fn checkpoint(&self, dest: &Path) -> Result<()> {
+        self.core.checkpoint(dest)
+    }
+
+    fn sync(&self) -> Result<()> {
+        self.core.sync()
+    }
+
+    /// With `IndexMode::Hashed`, the scan only returns `KvsError::ScanUnsupported`
+    fn scan_bytes<R: RangeBounds<Vec<u8>>>(&self, range: R) -> Scan {
+        let (start, end) = (range.start_bound().cloned(), range.end_bound().cloned());
+        Scan::new(IndexScan::new(self.core.clone(), start, end))
+    }
+}
+
+impl KvStore {
+    /// Create new `KvStore` in given path
+    /// 
+    /// This will create the directory if the given one does not exist.
+    /// This will restore exist data if the file exist.
+    /// Or new data file will be created.
+    pub fn open(path: impl Into<PathBuf>) -> Result<KvStore> {
+        KvStore::open_with(path, KvStoreOptions::default())
+    }
+
+    /// Open an existing `KvStore` in given path for reads only, see `KvStoreOptions::read_only`
+    ///
+    /// Several read-only stores can share a directory, but not with a writable one.
+    pub fn open_read_only(path: impl Into<PathBuf>) -> Result<KvStore> {
+        KvStore::open_with(path, KvStoreOptions::default().read_only(true))
+    }
+
+    /// Open a `KvStore` in given path with custom options
+    ///
+    /// # Errors
+    ///
+    /// Error will be returned when the store does not exist and `create_if_missing`
+    /// is off or `read_only` is on, or when the options are inconsistent.
+    /// `KvsError::InUse` is returned when the directory is opened by another writable store,
+    /// or by any other store for a writable one, in this process or another.
+    pub fn open_with(path: impl Into<PathBuf>, options: KvStoreOptions) -> Result<KvStore> {
+        options.validate()?;
+        let core = Core::open(path.into(), options)?;
+        let (compactor, ticker) = match (core.options.read_only, core.sync.policy()) {
+            (true, _) => (None, None),
+            (false, SyncPolicy::Interval(interval)) => {
+                let sync_core = core.clone();
+                let ticker = SyncTicker::spawn(interval, move || sync_core.sync())?;
+                (Some(Arc::new(Compactor::spawn(core.clone())?)), Some(Arc::new(ticker)))
+            },
+            (false, _) => (Some(Arc::new(Compactor::spawn(core.clone())?)), None),
+        };
+        Ok(KvStore { core, compactor, _ticker: ticker })
+    }
+
+    /// Report of the repairs done while opening the store
+    pub fn recovery_report(&self) -> &RecoveryReport {
+        &self.core.recovery
+    }
+
+    /// Seal the active log and merge the sealed logs with enough garbage now, wait for it to finish.
+    ///
+    /// Compaction normally runs on the background thread once enough data is written,
+    /// if it is already running this waits for it instead.
+    pub fn compact(&self) -> Result<()> {
+        if self.core.options.read_only {
+            return Err(KvsError::ReadOnly.into());
+        }
+        self.core.compact()
+    }
+
+    /// Wake up the background thread when enough was written since the last compaction
+    fn schedule_compaction(&self) {
+        if let Some(compactor) = &self.compactor {
+            if self.core.uncompacted.load(Ordering::SeqCst) >= self.core.options.compaction_threshold {
+                compactor.signal();
+            }
+        }
+    }
+}
+
+impl Core {
+    fn set(&self, key: Vec<u8>, value: Vec<u8>, expires: Option<u64>) -> Result<()> {
+        if self.options.read_only {
+            return Err(KvsError::ReadOnly.into());
+        }
+        self.wait_for_compaction()?;
+        self.append(Cmd::set(key, value, expires))
+    }
+
+    fn get(&self, key: Vec<u8>) -> Result<Option<Vec<u8>>> {
+        match self.index.get(&key)? {
+            Some(pos) if !pos.expired(now_millis()) => Ok(Some(self.read_cached(&key, &pos)?)),
+            _ => Ok(None),
+        }
+    }
+
+    /// Like `read_value`, through the value cache if there is one
+    fn read_cached(&self, key: &[u8], pos: &CmdPos) -> Result<Vec<u8>> {
+        let cache = match &self.cache {
+            Some(cache) => cache,
+            None => return self.read_value(pos),
+        };
+        if let Some(value) = cache.get(key, pos.version) {
+            return Ok(value);
+        }
+        let value = self.read_value(pos)?;
+        cache.insert(key, pos.version, &value);
+        Ok(value)
+    }
+
+    /// Read the value of the `Set` command at `pos`, from its blob file if it was separated
+    fn read_value(&self, pos: &CmdPos) -> Result<Vec<u8>> {
+        match self.read_record(pos)?.cmd {
+            Cmd::Set { value, .. } => Ok(value),
+            Cmd::Blob { blob, .. } => self.read_blob(&blob),
+            Cmd::Rm { .. } => Err(err_msg("Unexpected Command")),
+        }
+    }
+
+    /// Read the record at `pos`
+    fn read_record(&self, pos: &CmdPos) -> Result<Record> {
+        let reader = self.readers.get(&pos.id)
+            .expect("Cannot find log reader");
+
+        let reader = read_lock(&*reader);
+        let mut cmd = vec![0u8; pos.len.try_into()?];
+        reader.read_exact_at(&mut cmd, pos.pos)?;
+        let record = Record::decode(&cmd).map_err(|kind| KvsError::Corruption {
+            file: log_path(&self.dir_path, pos.id),
+            offset: pos.pos,
+            kind,
+        })?;
+        Ok(record)
+    }
+
+    fn remove(&self, key: Vec<u8>) -> Result<()> {
+        if self.options.read_only {
+            return Err(KvsError::ReadOnly.into());
+        }
+        if self.index.get(&key)?.is_some_and(|pos| !pos.expired(now_millis())) {
+            self.wait_for_compaction()?;
+            self.append(Cmd::rm(key))
+        } else {
+            let msg = "Key not found";
+            println!("{}", msg);
+            Err(err_msg(msg))
+        }
+    }
+
+    fn apply_batch(&self, batch: WriteBatch) -> Result<()> {
+        if self.options.read_only {
+            return Err(KvsError::ReadOnly.into());
+        }
+        if batch.is_empty() {
+            return Ok(());
+        }
+        let cmds = batch_cmds(batch)?;
+        self.wait_for_compaction()?;
+        self.append_batch(cmds)
+    }
+
+    /// The version of a key is the sequence number of its last record
+    fn get_versioned(&self, key: Vec<u8>) -> Result<(Option<Vec<u8>>, u64)> {
+        match self.index.get(&key)? {
+            Some(pos) if !pos.expired(now_millis()) => Ok((Some(self.read_cached(&key, &pos)?), pos.version)),
+            _ => Ok((None, 0)),
+        }
+    }
+
+    /// Check versions and write the batch under `self.writer` lock, like `compare_and_swap`
+    fn commit_versioned(&self, versions: Vec<(Vec<u8>, u64)>, batch: WriteBatch) -> Result<()> {
+        if self.options.read_only {
+            return Err(KvsError::ReadOnly.into());
+        }
+        let cmds = batch_cmds(batch)?;
+        self.wait_for_compaction()?;
+        let mut writer = lock(&self.writer);
+        let now = now_millis();
+        for (key, version) in versions {
+            let current = match self.index.get(&key)? {
+                Some(pos) if !pos.expired(now) => pos.version,
+                _ => 0,
+            };
+            if current != version {
+                return Err(KvsError::Conflict { key }.into());
+            }
+        }
+        if cmds.is_empty() {
+            return Ok(());
+        }
+        self.write_locked(&mut writer, std::iter::once(LogWrite::Batch(cmds)))
+    }
+
+    /// Check and write under `self.writer` lock, bypassing group commit
+    fn compare_and_swap(&self, key: Vec<u8>, expected: Option<Vec<u8>>, new: Option<Vec<u8>>) -> Result<()> {
+        if self.options.read_only {
+            return Err(KvsError::ReadOnly.into());
+        }
+        self.wait_for_compaction()?;
+        let mut writer = lock(&self.writer);
+        let current = match self.index.get(&key)? {
+            Some(pos) if !pos.expired(now_millis()) => Some(self.read_cached(&key, &pos)?),
+            _ => None,
+        };
+        if current != expected {
+            return Err(KvsError::ConditionFailed { current }.into());
+        }
+        let cmd = match (new, current) {
+            (Some(value), _) => Cmd::set(key, value, None),
+            (None, Some(_)) => Cmd::rm(key),
+            // absent and expected to stay absent
+            (None, None) => return Ok(()),
+        };
+        self.write_locked(&mut writer, std::iter::once(LogWrite::Cmd(cmd)))
+    }
+
+    fn open(path: PathBuf, options: KvStoreOptions) -> Result<Core> {
+        if !Manifest::exists(&path) && (options.read_only || !options.create_if_missing) {
+            return Err(err_msg(format!("No store found in {}", path.display())));
+        }
+        if !options.read_only {
+            fs::create_dir_all(&path)?;
+        }
+        // the store may be created by the holder of the lock meanwhile, so it is checked again
+        let dir_lock = Arc::new(DirLock::acquire(&path, options.read_only)?);
+        if Manifest::exists(&path) {
+            restore(path, options, dir_lock)
+        } else {
+            let default_id = 1;
+            let readers = Arc::new(Readers::new());
+            let index = Index::new(options.index_mode, readers.clone(), path.clone());
+            let manifest = Manifest::new(default_id);
+            manifest.save(&path)?;
+
+            let (reader, writer) = new_log_file(&path, default_id)?;
+            readers.insert(default_id, Arc::new(RwLock::new(reader)));
+            let (blob_readers, blob_writer) = blob::open_blobs(&path, &manifest.blobs)?;
+
+            Ok(Core {
+                index: Arc::new(index),
+                history: Arc::new(SkipMap::new()),
+                snapshots: Arc::new(Mutex::new(BTreeMap::new())),
+                writer: Arc::new(Mutex::new(writer)),
+                commit: Arc::new(Mutex::new(CommitState::default())),
+                committed: Arc::new(Condvar::new()),
+                readers,
+                blob_writer: Arc::new(Mutex::new(blob_writer)),
+                blob_readers: Arc::new(blob_readers),
+                dir_path: Arc::new(path),
+                uncompacted: Arc::new(AtomicU64::new(0)),
+                compaction: Arc::new(Mutex::new(CompactState::default())),
+                compaction_done: Arc::new(Condvar::new()),
+                next_id: Arc::new(AtomicU64::new(default_id + 1)),
+                manifest: Arc::new(Mutex::new(manifest)),
+                seq: Arc::new(AtomicU64::new(1)),
+                recovery: Arc::new(RecoveryReport::default()),
+                sync: Arc::new(SyncState::new(options.sync_policy)),
+                stats: Arc::default(),
+                cache: new_cache(&options),
+                options: Arc::new(options),
+                _dir_lock: dir_lock,
+            })
+        }
+    }
+
+    /// Force the active log and blob file to disk
+    fn sync(&self) -> Result<()> {
+        let mut writer = lock(&self.writer);
+        lock(&self.blob_writer).flush(true)?;
+        writer.flush()?;
+        writer.writer.get_ref().sync_data()?;
+        Ok(())
+    }
+
+    /// Backpressure for writers, block while the active log is too large
+    /// and a compaction is on its way to switch it.
+    fn wait_for_compaction(&self) -> Result<()> {
+        let too_large = || self.uncompacted.load(Ordering::SeqCst) >= self.options.max_segment_size;
+        if !too_large() {
+            return Ok(());
+        }
+        let mut state = lock(&self.compaction);
+        while state.running && too_large() {
+            state = self.compaction_done.wait(state).expect("Can't wait for compaction");
+        }
+        match &state.failed {
+            Some(e) if too_large() => Err(err_msg(format!("Compaction failed: {}", e))),
+            _ => Ok(()),
+        }
+    }
+
+    /// Run a compaction unless one is running, in that case wait for it.
+    ///
+    /// The result is kept in `self.compaction` for writers.
+    fn compact(&self) -> Result<()> {
+        {
+            let mut state = lock(&self.compaction);
+            if let Some(e) = &state.failed {
+                return Err(err_msg(format!("Compaction failed: {}", e)));
+            } else if state.running {
+                while state.running {
+                    state = self.compaction_done.wait(state).expect("Can't wait for compaction");
+                }
+                return Ok(());
+            }
+            state.running = true;
+        }
+
+        let result = self.run_compaction().and_then(|_| self.collect_blobs());
+        let mut state = lock(&self.compaction);
+        state.running = false;
+        if let Err(e) = &result {
+            state.failed = Some(e.to_string());
+        }
+        self.compaction_done.notify_all();
+        result
+    }
+
+    /// Clears stale entries in the log.
+    ///
+    /// The active log is sealed first, then the sealed logs picked by `pick_segments`
+    /// are merged into new ones, see `segment` module.
+    ///
+    /// Every step is recorded in the manifest, see `compaction` module for
+    /// how an interrupted compaction is handled on `open`.
+    fn run_compaction(&self) -> Result<()> {
+        // `self.compaction` is used to keep only one thread run compact at a time
+        // the intent in the manifest protects log files from a compact failure
+        if lock(&self.manifest).compaction.is_some() {
+            return Err(err_msg("Unexpected compaction intent in manifest"));
+        }
+        let (retired, retired_blobs) = {
+            let mut state = lock(&self.compaction);
+            (std::mem::take(&mut state.retired), std::mem::take(&mut state.retired_blobs))
+        };
+        for id in retired {
+            self.readers.remove(&id);
+        }
+        for id in retired_blobs {
+            self.blob_readers.remove(&id);
+        }
+        {
+            let mut writer = lock(&self.writer);
+            self.uncompacted.swap(0, Ordering::SeqCst);
+            if writer.pos > 0 {
+                self.roll(&mut writer)?;
+            }
+        }
+        let sources = self.pick_segments()?;
+        if sources.is_empty() {
+            return Ok(());
+        }
+        let intent = CompactIntent {
+            sources,
+            targets: Vec::new(),
+            progress: CompactProgress::Started,
+        };
+        self.update_manifest(|manifest| manifest.compaction = Some(intent.clone()))?;
+        fail_point!("kvs::compact::after_lock", |_| Err(err_msg("failpoint")));
+
+        self.compact_logs(intent)
+    }
+
+    /// Copy live records from `intent.sources` to new target logs, then delete the sources.
+    ///
+    /// New writes go to the active log, which is rolled once targets are written
+    /// so it keeps the highest id.
+    fn compact_logs(&self, mut intent: CompactIntent) -> Result<()> {
+        let sources: HashSet<u64> = intent.sources.iter().copied().collect();
+        // tombstones may hide records of the sealed logs left out
+        let keep_tombstones = lock(&self.manifest).segments.iter().any(|id| !sources.contains(id));
+
+        // records of the sources still read by snapshots, see `snapshot` module
+        let retained: HashSet<(u64, u64)> = self.history.iter()
+            .filter_map(|entry| entry.value().filter(|pos| sources.contains(&pos.id)).map(|pos| (pos.id, pos.pos)))
+            .collect();
+
+        // replay source files to generate data for compact, every record is verified by its checksum
+        let mut index: HashMap<Vec<u8>, ((u64, u64), Record)> = HashMap::new();
+        let mut copies = Vec::new();
+        for id in intent.sources.iter() {
+            let reader = self.readers.get(id)
+                .expect("Cannot find log reader");
+            let mut reader = read_lock(&reader).try_clone()?;
+            let len = reader.reader.metadata()?.len();
+            // the clone shares its cursor with the handle replayed by `restore`
+            reader.seek(SeekFrom::Start(0))?;
+            let stream = RecordReader::new(BufReader::new(reader), log_path(&self.dir_path, *id), len);
+            for record in stream {
+                let (pos, _, record) = record?;
+                let src = (*id, pos);
+                // the last record of a key is the one with the highest sequence number
+                let older = match index.get(record.cmd.key()) {
+                    Some((_, last)) if last.seq > record.seq => Some((src, record)),
+                    _ => index.insert(record.cmd.key().to_vec(), (src, record)),
+                };
+                if let Some((src, old)) = older {
+                    if retained.contains(&src) {
+                        copies.push((src, old, CopyReason::Retained));
+                    }
+                }
+            }
+        }
+
+        let now = now_millis();
+        for (key, (src, record)) in index {
+            let current = self.index.get(&key)?;
+            let live = current.map(|pos| (pos.id, pos.pos) == src);
+            if let (Cmd::Set { expires, .. }, Some(live)) | (Cmd::Blob { expires, .. }, Some(live)) = (&record.cmd, live) {
+                if live && expires.is_some_and(|expires| expires <= now) {
+                    // an expired value is dropped, the tombstone hides older values of the key
+                    copies.push((src, record, CopyReason::Expired));
+                } else if live {
+                    copies.push((src, record, CopyReason::Live));
+                } else if retained.contains(&src) {
+                    copies.push((src, record, CopyReason::Retained));
+                }
+            } else {
+                if current.is_none() {
+                    copies.push((src, Record::new(record.seq, Cmd::rm(key)), CopyReason::Tombstone));
+                }
+                if retained.contains(&src) {
+                    copies.push((src, record, CopyReason::Retained));
+                }
+            }
+        }
+        // copies of older versions are hidden by the tombstone of their key on replay
+        let versioned: HashSet<Vec<u8>> = copies.iter()
+            .filter(|(_, _, copy)| *copy == CopyReason::Retained)
+            .map(|(_, record, _)| record.cmd.key().to_vec())
+            .collect();
+        let keep_tombstone = |key: &[u8]| keep_tombstones || versioned.contains(key);
+        copies.retain(|(_, record, copy)| *copy != CopyReason::Tombstone || keep_tombstone(record.cmd.key()));
+        // in sequence order, so replaying targets without their hint files reads versions in order
+        copies.sort_by_key(|(_, record, copy)| (record.seq, *copy));
+
+        // writers are not blocked, a key updated meanwhile lives in the active log
+        // whose record has a higher sequence number, so a stale copy in a target is harmless
+        let mut target: Option<CmdWriter> = None;
+        let mut hints = Vec::new();
+        let mut moved = Vec::new();
+        // new position of every source record copied, `None` for an expired one
+        let mut remap = HashMap::new();
+        for (src, record, copy) in copies {
+            let key = record.cmd.key().to_vec();
+            if copy == CopyReason::Expired && !keep_tombstone(&key) {
+                moved.push((key, src, None));
+                remap.insert(src, None);
+                continue;
+            }
+            let full = target.as_ref().is_none_or(|writer| writer.pos >= self.options.max_segment_size);
+            if full {
+                if let Some(writer) = target.take() {
+                    self.seal_target(writer, &mut hints)?;
+                }
+                let id = self.next_id.fetch_add(1, Ordering::SeqCst);
+                intent.targets.push(id);
+                self.update_manifest(|manifest| manifest.compaction = Some(intent.clone()))?;
+                let (reader, writer) = new_log_file(&self.dir_path, id)?;
+                self.readers.insert(id, Arc::new(RwLock::new(reader)));
+                target = Some(writer);
+                fail_point!("kvs::compact::after_create_target", |_| Err(err_msg("failpoint")));
+            }
+            let writer = target.as_mut().expect("No compaction target");
+            let pos = writer.pos;
+            match (copy, &record.cmd) {
+                (CopyReason::Live, Cmd::Set { expires, .. } | Cmd::Blob { expires, .. })
+                | (CopyReason::Retained, Cmd::Set { expires, .. } | Cmd::Blob { expires, .. }) => {
+                    let expires = *expires;
+                    writer.write_all(&record.encode(self.options.compression))?;
+                    let new_pos = CmdPos::new(writer.id, pos, writer.pos - pos, expires, record.seq);
+                    // older versions are only read through history, not on restore
+                    if copy == CopyReason::Live {
+                        hints.push(HintEntry::new(key.clone(), writer.id, pos, writer.pos - pos, record.seq, expires));
+                        moved.push((key, src, Some(new_pos)));
+                    }
+                    remap.insert(src, Some(new_pos));
+                },
+                _ => {
+                    let data = Record::new(record.seq, Cmd::rm(key.clone()));
+                    writer.write_all(&data.encode(self.options.compression))?;
+                    hints.push(HintEntry::new(key.clone(), writer.id, pos, 0, record.seq, None));
+                    if copy == CopyReason::Expired {
+                        moved.push((key, src, None));
+                        remap.insert(src, None);
+                    }
+                },
+            }
+            fail_point!("kvs::compact::during_write", |_| Err(err_msg("failpoint")));
+        }
+        if let Some(writer) = target {
+            self.seal_target(writer, &mut hints)?;
+        }
+        {
+            // writers update the index with `self.writer` lock, so the check and update are atomic
+            let mut writer = lock(&self.writer);
+            for (key, src, pos) in moved {
+                let version = match self.index.get(&key)? {
+                    Some(current) if (current.id, current.pos) == src => current.version,
+                    _ => continue,
+                };
+                match pos {
+                    Some(pos) => {
+                        self.index.insert(key, pos)?;
+                    },
+                    None => {
+                        self.index.remove(&key)?.expect("Key not found");
+                        if let Some(cache) = &self.cache {
+                            cache.remove(&key);
+                        }
+                        self.hide_history(&key, version);
+                    },
+                }
+            }
+            // history only points to records copied above, entries added meanwhile
+            // are versions which were live in the index
+            for entry in self.history.iter() {
+                if let Some(pos) = entry.value().filter(|pos| sources.contains(&pos.id)) {
+                    let pos = remap.get(&(pos.id, pos.pos)).copied().flatten();
+                    self.history.insert(entry.key().clone(), pos);
+                }
+            }
+            // the targets have higher ids than the active log
+            if !intent.targets.is_empty() {
+                self.roll(&mut writer)?;
+            }
+        }
+        fail_point!("kvs::compact::after_switch", |_| Err(err_msg("failpoint")));
+        // targets replace sources in one step
+        intent.progress = CompactProgress::TargetWritten;
+        self.update_manifest(|manifest| {
+            manifest.segments.retain(|id| !sources.contains(id));
+            manifest.segments.extend(intent.targets.iter().copied());
+            manifest.segments.sort_unstable();
+            manifest.compaction = Some(intent.clone());
+        })?;
+        fail_point!("kvs::compact::after_write", |_| Err(err_msg("failpoint")));
+
+        // release source files
+        for id in intent.sources.iter() {
+            let reader = self.readers.get(id)
+                .expect("Cannot remove log reader");
+            // wait for the reads in progress
+            drop(write_lock(&reader));
+            remove_segment(&self.dir_path, *id)?;
+        }
+        lock(&self.compaction).retired.extend(intent.sources.iter().copied());
+        fail_point!("kvs::compact::after_remove_source", |_| Err(err_msg("failpoint")));
+
+        // unlock
+        self.update_manifest(|manifest| manifest.compaction = None)
+    }
+
+    /// Sync a complete target log and write its hint file from `hints`
+    fn seal_target(&self, mut writer: CmdWriter, hints: &mut Vec<HintEntry>) -> Result<()> {
+        writer.flush()?;
+        writer.writer.get_ref().sync_all()?;
+        hint::write_hint(&self.dir_path, writer.id, writer.pos, hints)?;
+        hints.clear();
+        Ok(())
+    }
+}
+
+/// Commands of a batch, which has to fit the record framing it
+fn batch_cmds(batch: WriteBatch) -> Result<Vec<Cmd>> {
+    if batch.len() > u32::MAX as usize {
+        return Err(err_msg("Too many writes in batch"));
+    }
+    Ok(batch.ops.into_iter().map(|op| match op {
+        BatchOp::Set { key, value } => Cmd::set(key, value, None),
+        BatchOp::Remove { key } => Cmd::rm(key),
+    }).collect())
+}
+
+/// Restore logs from exist files.
+/// 
+/// # Errors
+///
+/// Error will be returned when there is no log file.
+/// `KvsError::Corruption` will be returned when a record fails validation,
+/// except for an invalid tail of the active log, with no valid record after it.
+/// That one is left by a crash during `append`, a record cut short or a file extended
+/// before its data was written, so it is cut off and reported.
+/// An interrupted compaction is finished or rolled back before loading files.
+/// The manifest of an upgraded store is saved once every file is loaded, see `manifest` module.
+/// `KvsError::UnsupportedFormat` is returned for a store this build can not open,
+/// see `manifest` module.
+/// 
+/// The log files listed by the manifest are loaded, see `segment` module for how records
+/// of a key in several logs are resolved.
+fn restore(path: PathBuf, options: KvStoreOptions, dir_lock: Arc<DirLock>) -> Result<Core> {
+    let mut report = RecoveryReport::default();
+    let (mut manifest, upgraded_from) = Manifest::load(&path, &options)?;
+    report.upgraded_from = upgraded_from;
+    if manifest.compaction.is_some() {
+        if options.read_only {
+            return Err(err_msg("Log files has an uncompleted compact process, open it writable to recover"));
+        }
+        report.compaction = Some(compaction::recover(&path, &mut manifest)?);
+    }
+
+    // left by a crash between a manifest update and the file change following it
+    for id in log_ids(&path)? {
+        if manifest.contains(id) || options.read_only {
+            continue;
+        }
+        if fs::metadata(log_path(&path, id))?.len() != 0 {
+            return Err(err_msg(format!("Log file {} is not listed in the manifest", id)));
+        }
+        remove_segment(&path, id)?;
+    }
+    if !options.read_only {
+        for id in blob::blob_ids(&path)? {
+            if !manifest.blobs.contains(&id) {
+                fs::remove_file(blob::blob_path(&path, id))?;
+            }
+        }
+    }
+    // listed but never created, so no record points to them
+    let listed = manifest.blobs.len();
+    manifest.blobs.retain(|id| blob::blob_path(&path, *id).is_file());
+    let changed = manifest.blobs.len() != listed || report.upgraded_from.is_some();
+    let active_id = manifest.active;
+    if !log_path(&path, active_id).is_file() {
+        if options.read_only {
+            return Err(err_msg(format!("Active log file {} is missing", active_id)));
+        }
+        new_log_file(&path, active_id)?;
+    }
+    let mut file_list = manifest.segments.clone();
+    file_list.push(active_id);
+    if let Some(id) = file_list.iter().find(|id| !log_path(&path, **id).is_file()) {
+        return Err(err_msg(format!("Log file {} listed in the manifest is missing", id)));
+    }
+    let readers = Arc::new(Readers::new());
+    let index = Index::new(options.index_mode, readers.clone(), path.clone());
+    // sequence number of the last removal of the keys removed so far
+    let mut removed = HashMap::new();
+    let mut uncompacted = 0;
+    let mut seq = 0;
+    let now = now_millis();
+
+    for i in file_list.iter() {
+        let file_path = log_path(&path, *i);
+        let reader = CmdReader::new(file_path.clone(), *i)?;
+        let file_len = reader.reader.metadata()?.len();
+        let mut stream = RecordReader::new(BufReader::new(reader.try_clone()?), file_path.clone(), file_len);
+        let active = *i == active_id;
+        // a hashed index reads the keys of records already indexed
+        readers.insert(i.to_owned(), Arc::new(RwLock::new(reader)));
+
+        // sealed logs are loaded from their hint files when possible
+        let hints = if active { None } else { hint::read_hint(&path, *i, file_len)? };
+        if let Some(entries) = hints {
+            report.hints += 1;
+            for entry in entries {
+                seq = seq.max(entry.seq);
+                let pos = CmdPos::new(entry.file_id, entry.offset, entry.len, entry.expires, entry.seq);
+                let pos = Some(pos).filter(|pos| entry.len != 0 && !pos.expired(now));
+                replay(&index, &mut removed, entry.key, entry.seq, pos)?;
+            }
+            continue;
+        }
+
+        while let Some(record) = stream.next() {
+            let (pos, len, record) = match record {
+                Ok(record) => record,
+                Err(e) if active && is_torn(&e, &file_path)? => {
+                    // the tail starts with a torn record, garbage or an uncommitted batch
+                    let end = stream.committed();
+                    // a read-only store just ignores the tail
+                    if !options.read_only {
+                        let file = OpenOptions::new().write(true).open(&file_path)?;
+                        file.set_len(end)?;
+                        file.sync_all()?;
+                    }
+                    report.truncated = Some(TruncatedTail {
+                        file: file_path.clone(),
+                        offset: end,
+                        dropped: file_len - end,
+                    });
+                    break;
+                },
+                Err(e) => return Err(e),
+            };
+            report.records += 1;
+            seq = seq.max(record.seq);
+            match record.cmd {
+                Cmd::Set {key, expires, ..} | Cmd::Blob {key, expires, ..} => {
+                    let pos = CmdPos::new(*i, pos, len, expires, record.seq);
+                    replay(&index, &mut removed, key, record.seq, Some(pos).filter(|pos| !pos.expired(now)))?;
+                },
+                Cmd::Rm {key} => {
+                    replay(&index, &mut removed, key, record.seq, None)?;
+                }
+            }
+        }
+
+        if active {
+            uncompacted = stream.committed();
+        }
+    }
+
+    // an upgrade is only saved once every log file is read
+    if !options.read_only {
+        if changed {
+            manifest.save(&path)?;
+        }
+        manifest::cleanup(&path)?;
+    }
+
+    // a read-only store keeps a writer which is never written to
+    let write_file = OpenOptions::new()
+        .read(true).write(!options.read_only).open(log_path(&path, active_id))?;
+    let mut writer = CmdWriter::new(write_file, active_id)?;
+    writer.seek(SeekFrom::End(0))?;
+    let (blob_readers, blob_writer) = blob::open_blobs(&path, &manifest.blobs)?;
+
+    Ok(Core{
+        index: Arc::new(index),
+        history: Arc::new(SkipMap::new()),
+        snapshots: Arc::new(Mutex::new(BTreeMap::new())),
+        writer: Arc::new(Mutex::new(writer)),
+        commit: Arc::new(Mutex::new(CommitState::default())),
+        committed: Arc::new(Condvar::new()),
+        readers,
+        blob_writer: Arc::new(Mutex::new(blob_writer)),
+        blob_readers: Arc::new(blob_readers),
+        dir_path: Arc::new(path),
+        uncompacted: Arc::new(AtomicU64::new(uncompacted)),
+        compaction: Arc::new(Mutex::new(CompactState::default())),
+        compaction_done: Arc::new(Condvar::new()),
+        next_id: Arc::new(AtomicU64::new(active_id + 1)),
+        manifest: Arc::new(Mutex::new(manifest)),
+        seq: Arc::new(AtomicU64::new(seq + 1)),
+        recovery: Arc::new(report),
+        sync: Arc::new(SyncState::new(options.sync_policy)),
+        stats: Arc::default(),
+        cache: new_cache(&options),
+        options: Arc::new(options),
+        _dir_lock: dir_lock,
+    })
+}
+
+/// Apply a record met by `restore` to the index, `None` for a removal or an expired value.
+///
+/// It is skipped when the key already holds a record with a higher sequence number.
+fn replay(index: &Index, removed: &mut HashMap<Vec<u8>, u64>, key: Vec<u8>, seq: u64, pos: Option<CmdPos>) -> Result<()> {
+    let last = match removed.get(&key) {
+        Some(last) => Some(*last),
+        None => index.get(&key)?.map(|pos| pos.version),
+    };
+    if last.is_some_and(|last| last > seq) {
+        return Ok(());
+    }
+    match pos {
+        Some(pos) => {
+            removed.remove(&key);
+            index.insert(key, pos)?;
+        },
+        None => {
+            index.remove(&key)?;
+            removed.insert(key, seq);
+        },
+    }
+    Ok(())
+}
+
+fn new_cache(options: &KvStoreOptions) -> Option<Arc<ValueCache>> {
+    Some(options.cache_size).filter(|size| *size > 0).map(|size| Arc::new(ValueCache::new(size)))
+}
+
+/// Whether `e`, met while reading the log file at `path`, is a torn tail
+///
+/// A record cut short is, so is any invalid record followed by no valid one,
+/// like the zeros or garbage of a file extended before its data was written.
+fn is_torn(e: &Error, path: &Path) -> Result<bool> {
+    match e.downcast_ref::<KvsError>() {
+        Some(KvsError::Corruption { kind: CorruptionKind::Truncated, .. }) => Ok(true),
+        Some(KvsError::Corruption { offset, .. }) => {
+            let file = File::open(path)?;
+            file.seek(SeekFrom::Start(*offset))?;
+            let mut tail = Vec::new();
+            file.read_to_end(&mut tail)?;
+            Ok(!record::holds_record(&tail))
+        },
+        _ => Ok(false),
+    }
+}
+
+/// Create a new log file with given id.
+///
+/// Return a pair of `Reader` and `Writer` of that file.
+fn new_log_file(path: &Path, id: u64) -> Result<(CmdReader, CmdWriter)> {
+    let file_path = log_path(path, id);
+    if file_path.exists() {
+        return Err(err_msg("Unexpected exist log file"));
+    }
+    let write_file = OpenOptions::new()
+        .create_new(true).write(true).open(&file_path)?;
+    let writer = CmdWriter::new(write_file, id)?;
+    let reader = CmdReader::new(file_path, id)?;
+    Ok((reader, writer))
+}
+
+/// Path of the log file with given id
+fn log_path(path: &Path, id: u64) -> PathBuf {
+    path.join(id.to_string()+".log")
+}
+
+fn lock<T>(lock: &Arc<Mutex<T>>) -> MutexGuard<'_, T> {
+    lock.lock().unwrap_or_else(|_|
+        panic!("Can't get mutex lock, variable type {}", std::any::type_name::<T>())
+    )
+}
+fn read_lock<T>(lock: &Arc<RwLock<T>>) -> RwLockReadGuard<'_, T> {
+    lock.read().unwrap_or_else(|_|
+        panic!("Can't get read lock, variable type {}", std::any::type_name::<T>())
+    )
+}
+
+fn write_lock<T>(lock: &Arc<RwLock<T>>) -> RwLockWriteGuard<'_, T> {
+    lock.write().unwrap_or_else(|_|
+        panic!("Can't get write lock, variable type {}", std::any::type_name::<T>())
+    )
+}
+
+impl Cmd {
+    /// Create a `Set` command
+    pub fn set(k: Vec<u8>, v: Vec<u8>, expires: Option<u64>) -> Self {
+        Cmd::Set {
+            key: k,
+            value: v,
+            expires,
+        }
+    }
+    /// Create a `Rm` command
+    pub fn rm(k: Vec<u8>) -> Self {
+        Cmd::Rm {
+            key: k
+        }
+    }
+    /// The key this command applies to
+    pub fn key(&self) -> &[u8] {
+        match self {
+            Cmd::Set { key, .. } | Cmd::Rm { key } | Cmd::Blob { key, .. } => key
+        }
+    }
+}
+
+impl  CmdPos {
+    fn new(id: u64, pos: u64, len: u64, expires: Option<u64>, version: u64) -> Self {
+        CmdPos {
+            id, pos, len, expires, version
+        }
+    }
+
+    /// Whether the value has expired at `now`, in milliseconds since the Unix epoch
+    fn expired(&self, now: u64) -> bool {
+        self.expires.is_some_and(|expires| expires <= now)
+    }
+
+    /// Time left at `now` before the key expires, `None` if it has no expiry time or is expired
+    fn ttl(&self, now: u64) -> Option<Duration> {
+        self.expires.filter(|_| !self.expired(now)).map(|expires| ttl_left(expires, now))
+    }
+}
+
+impl CmdWriter {
+    fn new(inner: File, id: u64) -> Result<Self> {
+        let pos = inner.stream_position()?;
+        Ok(CmdWriter {
+            writer: BufWriter::new(inner),
+            pos,
+            id,
+        })
+    }
+}
+
+impl Write for CmdWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let len = self.writer.write(buf)?;
+        self.pos += len as u64;
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+impl Seek for CmdWriter {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.pos = self.writer.seek(pos)?;
+        Ok(self.pos)
+    }
+}
+
+impl CmdReader {
+    fn new(file_path: PathBuf, id: u64) -> Result<Self> {
+        let inner = File::open(&file_path)?;
+        let pos = inner.stream_position()?;
+        Ok(CmdReader {
+            reader: inner,
+            pos,
+            id
+        })
+    }
+
+    fn try_clone(&self) -> Result<Self> {
+        Ok(CmdReader {
+            reader: self.reader.try_clone()?,
+            pos: self.pos,
+            id: self.id,
+        })
+    }
+ 
+    fn read_exact_at(&self, buf: &mut [u8], offset: u64) -> Result<()> {
+        Ok(self.reader.read_exact_at(buf, offset)?)
+    }
+}
+
+impl Read for CmdReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = self.reader.read(buf)?;
+        self.pos += len as u64;
+        Ok(len)
+    }
+}
+
+impl Seek for CmdReader {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.pos = self.reader.seek(pos)?;
+        Ok(self.pos)
+    }
+}
\ No newline at end of file
@@ -0,0 +1,208 @@
+use serde::{Serialize, Deserialize};
+use crate::error::*;
+use crate::engine::SyncPolicy;
+
+/// Options to open a `KvStore` with, see `KvStore::open_with`
+///
+/// ```no_run
+/// # use kvs::{KvStore, KvStoreOptions, SyncPolicy};
+/// let store = KvStore::open_with("db", KvStoreOptions::new()
+///     .compaction_threshold(4 * 1024 * 1024)
+///     .sync_policy(SyncPolicy::EveryWrite))?;
+/// # Ok::<(), failure::Error>(())
+/// ```
+///
+/// It can be deserialized, missing fields take their default value.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct KvStoreOptions {
+    pub(super) compaction_threshold: u64,
+    pub(super) max_segment_size: u64,
+    pub(super) compaction_garbage_ratio: f64,
+    pub(super) sync_policy: SyncPolicy,
+    pub(super) read_only: bool,
+    pub(super) create_if_missing: bool,
+    pub(super) upgrade_format: bool,
+    pub(super) compression: Compression,
+    pub(super) blob_threshold: Option<u64>,
+    pub(super) blob_file_size: u64,
+    pub(super) blob_gc_ratio: f64,
+    pub(super) index_mode: IndexMode,
+    pub(super) cache_size: u64,
+}
+
+/// Codec compressing the values written to the log, see `KvStoreOptions::compression`
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Compression {
+    /// Values are stored as they are
+    #[default]
+    None,
+    /// LZ4, fast with a moderate ratio
+    Lz4,
+    /// Zstandard at its default level, slower with a better ratio
+    Zstd,
+}
+
+/// What the in-memory index holds for each key, see `KvStoreOptions::index_mode`
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum IndexMode {
+    /// Every key, in order
+    #[default]
+    Ordered,
+    /// A fingerprint of every key, keys are read from the log when needed
+    Hashed,
+}
+
+impl Default for KvStoreOptions {
+    fn default() -> Self {
+        KvStoreOptions {
+            compaction_threshold: 1024 * 1024,
+            max_segment_size: 2 * 1024 * 1024,
+            compaction_garbage_ratio: 0.5,
+            sync_policy: SyncPolicy::Never,
+            read_only: false,
+            create_if_missing: true,
+            upgrade_format: true,
+            compression: Compression::None,
+            blob_threshold: None,
+            blob_file_size: 64 * 1024 * 1024,
+            blob_gc_ratio: 0.5,
+            index_mode: IndexMode::Ordered,
+            cache_size: 0,
+        }
+    }
+}
+
+impl KvStoreOptions {
+    /// Default options, same as `KvStore::open`
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Bytes written since the last compaction that start a background one.
+    ///
+    /// Default to 1 MiB.
+    pub fn compaction_threshold(mut self, bytes: u64) -> Self {
+        self.compaction_threshold = bytes;
+        self
+    }
+
+    /// Size of a log segment, the active log is sealed and a new one started once it reaches it.
+    ///
+    /// While a compaction is running, the active log grows beyond it and writers wait
+    /// for the compaction instead. Should not be smaller than `compaction_threshold`.
+    /// Default to 2 MiB.
+    pub fn max_segment_size(mut self, bytes: u64) -> Self {
+        self.max_segment_size = bytes;
+        self
+    }
+
+    /// Share of dead bytes from which a sealed segment is merged by compaction.
+    ///
+    /// 0 merges every sealed segment at each compaction, 1 only fully dead ones.
+    /// Default to 0.5.
+    pub fn compaction_garbage_ratio(mut self, ratio: f64) -> Self {
+        self.compaction_garbage_ratio = ratio;
+        self
+    }
+
+    /// Durability of writes, default to `SyncPolicy::Never`
+    pub fn sync_policy(mut self, policy: SyncPolicy) -> Self {
+        self.sync_policy = policy;
+        self
+    }
+
+    /// Open an existing store without changing any file.
+    ///
+    /// Writes are rejected with `KvsError::ReadOnly` and no compaction is run.
+    /// The directory is locked shared, so other read-only stores can open it but no writable one.
+    pub fn read_only(mut self, read_only: bool) -> Self {
+        self.read_only = read_only;
+        self
+    }
+
+    /// Create the store when the directory holds none, default to `true`
+    pub fn create_if_missing(mut self, create: bool) -> Self {
+        self.create_if_missing = create;
+        self
+    }
+
+    /// Upgrade a store written by an older format version, default to `true`
+    ///
+    /// The upgrade is done on `open` and can not be undone, older builds can not open the store
+    /// afterwards. When off, such a store is refused with `KvsError::UnsupportedFormat`.
+    /// A read-only store is upgraded in memory only.
+    pub fn upgrade_format(mut self, upgrade: bool) -> Self {
+        self.upgrade_format = upgrade;
+        self
+    }
+
+    /// Codec of the values written from now on, default to `Compression::None`
+    ///
+    /// Every record carries its codec, so a store can be reopened with another one.
+    /// A value is stored uncompressed when the codec does not make it smaller.
+    /// Compaction rewrites the values it copies with the current codec.
+    pub fn compression(mut self, compression: Compression) -> Self {
+        self.compression = compression;
+        self
+    }
+
+    /// Write values of at least `bytes` to blob files, the log only holds a pointer to them
+    ///
+    /// Compaction then copies the pointer instead of the value, blob files are
+    /// collected on their own, see `blob_gc_ratio`. Default to `None`, every value in the log.
+    pub fn blob_threshold(mut self, bytes: Option<u64>) -> Self {
+        self.blob_threshold = bytes;
+        self
+    }
+
+    /// Size of a blob file before a new one is started, default to 64 MiB
+    pub fn blob_file_size(mut self, bytes: u64) -> Self {
+        self.blob_file_size = bytes;
+        self
+    }
+
+    /// Share of live bytes under which a blob file is rewritten after a compaction.
+    ///
+    /// Its live values are copied to the active blob file and the file is deleted.
+    /// 0 never collects a file with a live value, 1 collects every file with a dead one.
+    /// Default to 0.5.
+    pub fn blob_gc_ratio(mut self, ratio: f64) -> Self {
+        self.blob_gc_ratio = ratio;
+        self
+    }
+
+    /// Memory layout of the index, default to `IndexMode::Ordered`
+    ///
+    /// `IndexMode::Hashed` fits stores with more keys than memory allows: it takes a fixed
+    /// size per key whatever its length, but lookups and writes read keys from the log,
+    /// and scans are not supported, they return `KvsError::ScanUnsupported`.
+    pub fn index_mode(mut self, mode: IndexMode) -> Self {
+        self.index_mode = mode;
+        self
+    }
+
+    /// Bytes of keys and values read by `get` kept in memory, default to 0, no cache
+    ///
+    /// Hits and misses are counted in `KvStore::stats`. Scans and snapshots do not use it.
+    pub fn cache_size(mut self, bytes: u64) -> Self {
+        self.cache_size = bytes;
+        self
+    }
+
+    pub(super) fn validate(&self) -> Result<()> {
+        if self.compaction_threshold == 0 {
+            Err(err_msg("Compaction threshold must be positive"))
+        } else if self.max_segment_size < self.compaction_threshold {
+            Err(err_msg("Max segment size must not be smaller than compaction threshold"))
+        } else if !(0.0..=1.0).contains(&self.compaction_garbage_ratio) {
+            Err(err_msg("Compaction garbage ratio must be between 0 and 1"))
+        } else if !(0.0..=1.0).contains(&self.blob_gc_ratio) {
+            Err(err_msg("Blob GC ratio must be between 0 and 1"))
+        } else if self.blob_file_size == 0 {
+            Err(err_msg("Blob file size must be positive"))
+        } else {
+            Ok(())
+        }
+    }
+}
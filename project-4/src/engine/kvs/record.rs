@@ -0,0 +1,486 @@
+//! Binary record layout of `KvStore` log files
+//!
+//! Every command is stored as one record, a fixed size header followed by key and value bytes.
+//! All integers are little-endian.
+//!
+//! ```text
+//! | crc32 | version | op | header_check | seq | key_len | value_len | key | value |
+//! |   4   |    1    | 1  |      2       |  8  |    4    |     4     |     ...     |
+//! ```
+//!
+//! A `Set` with an expiry uses op `OP_SET_EX`, its value starts with the expiry time
+//! in milliseconds since the Unix epoch, as 8 bytes.
+//!
+//! A value separated into a blob file is replaced by its position there, the blob file id,
+//! offset and length as 8 bytes each, with op `OP_BLOB`, or `OP_BLOB_EX` after an expiry time.
+//! Blob files hold plain `Set` records of the same sequence number, see `blob` module.
+//!
+//! The low 4 bits of `op` are the operation, the high 4 bits the codec of the value
+//! of a `Set`, after its expiry time. `CODEC_NONE` is 0, so records written before
+//! compression existed read as uncompressed ones.
+//!
+//! The records of a batch are framed by an `OP_BATCH_BEGIN` record, whose value is
+//! the number of records as 4 bytes, and an `OP_BATCH_COMMIT` record, both with no key.
+//! `RecordReader` only yields the records of a batch once its commit is read.
+//!
+//! The checksum covers every byte after itself, so both header and payload are protected.
+//! `header_check` holds the low 16 bits of the CRC32 of the other header fields.
+//! It tells a record cut short by a crash apart from a header with a damaged length.
+//!
+//! `version` is bumped by every change of the format. Records down to `OLDEST_RECORD_VERSION`
+//! are still read, as later versions only add operations and codecs, while an older build
+//! refuses a newer record with `CorruptionKind::UnsupportedVersion`.
+//!
+//! | version | change |
+//! |---------|--------|
+//! | 1       | first binary format, the bytes of `header_check` are reserved |
+//! | 2       | `header_check` |
+//! | 3       | `OP_SET_EX`, a `Set` with an expiry time |
+//! | 4       | `OP_BATCH_BEGIN` and `OP_BATCH_COMMIT` framing batches |
+//! | 5       | codec in the high 4 bits of `op` |
+//! | 6       | `OP_BLOB` and `OP_BLOB_EX`, values in blob files |
+//!
+//! Before this format, log files held the commands as JSON objects one after another,
+//! `{"Set":{"key":..,"value":..}}` or `{"Rm":{"key":..}}`. Such a file is told apart by
+//! `is_json_log`, as the version byte of a record never matches that text, and rewritten
+//! as records by `convert_json_log` when the store is upgraded, see `manifest` module.
+use std::{collections::VecDeque, convert::TryInto, fs::File, io::{self, BufReader, BufWriter, Read, Write}, path::{Path, PathBuf}};
+use serde::Deserialize;
+use crate::error::*;
+use super::{Cmd, Compression};
+use super::blob::BlobPos;
+
+/// Current record format version
+pub(super) const RECORD_VERSION: u8 = 6;
+/// Oldest record format version read
+const OLDEST_RECORD_VERSION: u8 = 2;
+/// Size of the record header in bytes
+pub(super) const HEADER_LEN: usize = 24;
+
+const OP_SET: u8 = 1;
+const OP_RM: u8 = 2;
+const OP_SET_EX: u8 = 3;
+const OP_BATCH_BEGIN: u8 = 4;
+const OP_BATCH_COMMIT: u8 = 5;
+const OP_BLOB: u8 = 6;
+const OP_BLOB_EX: u8 = 7;
+
+/// Bits of the op byte holding the operation, the rest holds the codec
+const OP_MASK: u8 = 0x0f;
+const CODEC_SHIFT: u8 = 4;
+const CODEC_NONE: u8 = 0;
+const CODEC_LZ4: u8 = 1;
+const CODEC_ZSTD: u8 = 2;
+
+/// A `Cmd` with its sequence number
+pub(super) struct Record {
+    pub(super) seq: u64,
+    pub(super) cmd: Cmd,
+}
+
+impl Record {
+    pub(super) fn new(seq: u64, cmd: Cmd) -> Self {
+        Record { seq, cmd }
+    }
+
+    /// Serialize the record into its on-disk bytes, the value of a `Set` is compressed
+    /// by `compression` unless it does not get smaller
+    pub(super) fn encode(&self, compression: Compression) -> Vec<u8> {
+        let expiry;
+        let (op, key, expiry, value) = match &self.cmd {
+            Cmd::Set { key, value, expires: None } => (OP_SET, &key[..], &[][..], &value[..]),
+            Cmd::Set { key, value, expires: Some(expires) } => {
+                expiry = expires.to_le_bytes();
+                (OP_SET_EX, &key[..], &expiry[..], &value[..])
+            },
+            Cmd::Rm { key } => (OP_RM, &key[..], &[][..], &[][..]),
+            Cmd::Blob { key, blob, expires } => {
+                let op = if expires.is_some() { OP_BLOB_EX } else { OP_BLOB };
+                let expiry = expires.map(u64::to_le_bytes);
+                let expiry = expiry.as_ref().map_or(&[][..], |expiry| &expiry[..]);
+                return encode(self.seq, op, key, expiry, &blob.to_bytes());
+            },
+        };
+        match compress(compression, value) {
+            Some((codec, value)) => encode(self.seq, op | codec << CODEC_SHIFT, key, expiry, &value),
+            None => encode(self.seq, op, key, expiry, value),
+        }
+    }
+
+    /// Deserialize a whole record, the checksum is verified before anything else
+    pub(super) fn decode(buf: &[u8]) -> std::result::Result<Record, CorruptionKind> {
+        match decode_entry(buf)? {
+            Entry::Record(record) => Ok(record),
+            _ => Err(CorruptionKind::InvalidData),
+        }
+    }
+}
+
+/// Serialize the record opening a batch of `count` records
+pub(super) fn encode_batch_begin(seq: u64, count: u32) -> Vec<u8> {
+    encode(seq, OP_BATCH_BEGIN, &[], &[], &count.to_le_bytes())
+}
+
+/// Serialize the record closing a batch
+pub(super) fn encode_batch_commit(seq: u64) -> Vec<u8> {
+    encode(seq, OP_BATCH_COMMIT, &[], &[], &[])
+}
+
+fn encode(seq: u64, op: u8, key: &[u8], expiry: &[u8], value: &[u8]) -> Vec<u8> {
+    let value_len = expiry.len() + value.len();
+    let mut buf = Vec::with_capacity(HEADER_LEN + key.len() + value_len);
+    buf.extend_from_slice(&[0u8; 4]);
+    buf.push(RECORD_VERSION);
+    buf.push(op);
+    buf.extend_from_slice(&[0u8; 2]);
+    buf.extend_from_slice(&seq.to_le_bytes());
+    buf.extend_from_slice(&(key.len() as u32).to_le_bytes());
+    buf.extend_from_slice(&(value_len as u32).to_le_bytes());
+    let check = header_check(&buf);
+    buf[6..8].copy_from_slice(&check.to_le_bytes());
+    buf.extend_from_slice(key);
+    buf.extend_from_slice(expiry);
+    buf.extend_from_slice(value);
+    let crc = crc32fast::hash(&buf[4..]);
+    buf[..4].copy_from_slice(&crc.to_le_bytes());
+    buf
+}
+
+/// Codec and compressed bytes of a value, `None` if it is better left uncompressed
+fn compress(compression: Compression, value: &[u8]) -> Option<(u8, Vec<u8>)> {
+    let (codec, compressed) = match compression {
+        _ if value.is_empty() => return None,
+        Compression::None => return None,
+        Compression::Lz4 => (CODEC_LZ4, lz4_flex::compress_prepend_size(value)),
+        Compression::Zstd => (CODEC_ZSTD, zstd::bulk::compress(value, 0).ok()?),
+    };
+    Some((codec, compressed)).filter(|(_, compressed)| compressed.len() < value.len())
+}
+
+fn decompress(codec: u8, value: &[u8]) -> std::result::Result<Vec<u8>, CorruptionKind> {
+    match codec {
+        CODEC_NONE => Ok(value.to_vec()),
+        CODEC_LZ4 => lz4_flex::decompress_size_prepended(value).map_err(|_| CorruptionKind::InvalidData),
+        CODEC_ZSTD => zstd::decode_all(value).map_err(|_| CorruptionKind::InvalidData),
+        codec => Err(CorruptionKind::UnknownCodec(codec)),
+    }
+}
+
+/// A decoded record, either a command or a batch frame
+enum Entry {
+    Record(Record),
+    BatchBegin(u32),
+    BatchCommit,
+}
+
+/// Deserialize a whole record of any kind, the checksum is verified before anything else
+fn decode_entry(buf: &[u8]) -> std::result::Result<Entry, CorruptionKind> {
+    if buf.len() < HEADER_LEN || buf.len() != HEADER_LEN + body_len(&buf[..HEADER_LEN]) {
+        return Err(CorruptionKind::Truncated);
+    }
+    let crc = u32::from_le_bytes(buf[0..4].try_into().unwrap());
+    if crc != crc32fast::hash(&buf[4..]) {
+        return Err(CorruptionKind::ChecksumMismatch);
+    }
+    if !supported(buf[4]) {
+        return Err(CorruptionKind::UnsupportedVersion(buf[4]));
+    }
+    let seq = u64::from_le_bytes(buf[8..16].try_into().unwrap());
+    let key_len = u32::from_le_bytes(buf[16..20].try_into().unwrap()) as usize;
+    let key = buf[HEADER_LEN..HEADER_LEN + key_len].to_vec();
+    let value = &buf[HEADER_LEN + key_len..];
+
+    let (op, codec) = (buf[5] & OP_MASK, buf[5] >> CODEC_SHIFT);
+    if codec != CODEC_NONE && op != OP_SET && op != OP_SET_EX {
+        return Err(CorruptionKind::InvalidData);
+    }
+    let cmd = match op {
+        OP_SET => Cmd::Set {
+            key,
+            value: decompress(codec, value)?,
+            expires: None,
+        },
+        OP_SET_EX if value.len() >= 8 => Cmd::Set {
+            key,
+            value: decompress(codec, &value[8..])?,
+            expires: Some(u64::from_le_bytes(value[..8].try_into().unwrap())),
+        },
+        OP_SET_EX => return Err(CorruptionKind::InvalidData),
+        OP_RM if value.is_empty() => Cmd::Rm { key },
+        OP_RM => return Err(CorruptionKind::InvalidData),
+        OP_BLOB if value.len() == 24 => Cmd::Blob {
+            key,
+            blob: BlobPos::from_bytes(value),
+            expires: None,
+        },
+        OP_BLOB_EX if value.len() == 32 => Cmd::Blob {
+            key,
+            blob: BlobPos::from_bytes(&value[8..]),
+            expires: Some(u64::from_le_bytes(value[..8].try_into().unwrap())),
+        },
+        OP_BLOB | OP_BLOB_EX => return Err(CorruptionKind::InvalidData),
+        OP_BATCH_BEGIN if key.is_empty() && value.len() == 4 =>
+            return Ok(Entry::BatchBegin(u32::from_le_bytes(value.try_into().unwrap()))),
+        OP_BATCH_COMMIT if key.is_empty() && value.is_empty() => return Ok(Entry::BatchCommit),
+        OP_BATCH_BEGIN | OP_BATCH_COMMIT => return Err(CorruptionKind::InvalidData),
+        op => return Err(CorruptionKind::UnknownOp(op)),
+    };
+    Ok(Entry::Record(Record { seq, cmd }))
+}
+
+/// Length of key and value described by a header
+fn body_len(header: &[u8]) -> usize {
+    let key_len = u32::from_le_bytes(header[16..20].try_into().unwrap());
+    let value_len = u32::from_le_bytes(header[20..24].try_into().unwrap());
+    key_len as usize + value_len as usize
+}
+
+/// Checksum of the header fields, excluding the record checksum and itself
+fn header_check(header: &[u8]) -> u16 {
+    let mut hasher = crc32fast::Hasher::new();
+    hasher.update(&header[4..6]);
+    hasher.update(&header[8..HEADER_LEN]);
+    hasher.finalize() as u16
+}
+
+/// Validate a header whose record can not be read completely
+fn header_error(header: &[u8]) -> Option<CorruptionKind> {
+    if u16::from_le_bytes(header[6..8].try_into().unwrap()) != header_check(header) {
+        return Some(CorruptionKind::ChecksumMismatch);
+    }
+    match (header[4], header[5] & OP_MASK) {
+        (version, _) if !supported(version) => Some(CorruptionKind::UnsupportedVersion(version)),
+        (_, OP_SET..=OP_BLOB_EX) => None,
+        (_, op) => Some(CorruptionKind::UnknownOp(op)),
+    }
+}
+
+/// Whether records of `version` are read
+fn supported(version: u8) -> bool {
+    (OLDEST_RECORD_VERSION..=RECORD_VERSION).contains(&version)
+}
+
+/// Whether a valid record starts anywhere in `data` after its first byte
+///
+/// Tells a damaged record followed by others from a damaged tail.
+pub(super) fn holds_record(data: &[u8]) -> bool {
+    (1..data.len()).any(|start| {
+        let data = &data[start..];
+        if data.len() < HEADER_LEN || header_error(&data[..HEADER_LEN]).is_some() {
+            return false;
+        }
+        let len = HEADER_LEN + body_len(&data[..HEADER_LEN]);
+        len <= data.len() && decode_entry(&data[..len]).is_ok()
+    })
+}
+
+/// Sequence number, key length and record length of a valid header, used to
+/// list the records of a blob file without reading their values
+pub(super) fn parse_header(header: &[u8; HEADER_LEN]) -> Option<(u64, usize, u64)> {
+    if header_error(header).is_some() {
+        return None;
+    }
+    let seq = u64::from_le_bytes(header[8..16].try_into().unwrap());
+    let key_len = u32::from_le_bytes(header[16..20].try_into().unwrap()) as usize;
+    Some((seq, key_len, (HEADER_LEN + body_len(header)) as u64))
+}
+
+/// A record with its offset and length in the file
+pub(super) type Located = (u64, u64, Record);
+
+/// Read records one by one from the start of a log file
+///
+/// Yields the offset and length of every record. The iteration stops after the first error.
+/// Records of a batch are held back until its commit, a batch left open at the end
+/// of the file is reported as `CorruptionKind::Truncated` at its start.
+pub(super) struct RecordReader<R: Read> {
+    reader: R,
+    path: PathBuf,
+    pos: u64,
+    len: u64,
+    failed: bool,
+    /// End of the last record yielded or batch committed
+    committed: u64,
+    /// Records of the open batch, with its start and expected record count
+    batch: Option<(u64, u32, Vec<Located>)>,
+    /// Records of a committed batch, not yielded yet
+    ready: VecDeque<Located>,
+}
+
+impl<R: Read> RecordReader<R> {
+    /// `len` is the size of the file, used to reject bogus lengths before allocating
+    pub(super) fn new(reader: R, path: PathBuf, len: u64) -> Self {
+        RecordReader {
+            reader,
+            path,
+            pos: 0,
+            len,
+            failed: false,
+            committed: 0,
+            batch: None,
+            ready: VecDeque::new(),
+        }
+    }
+
+    /// Offset up to which the file holds complete records and committed batches
+    ///
+    /// A torn tail should be truncated there.
+    pub(super) fn committed(&self) -> u64 {
+        self.committed
+    }
+
+    fn corruption(&mut self, kind: CorruptionKind) -> Error {
+        self.failed = true;
+        KvsError::Corruption {
+            file: self.path.clone(),
+            offset: self.pos,
+            kind,
+        }.into()
+    }
+
+    /// Read records until one can be yielded
+    fn next_record(&mut self) -> Result<Option<Located>> {
+        loop {
+            if let Some(record) = self.ready.pop_front() {
+                return Ok(Some(record));
+            }
+            let (pos, len, entry) = match self.read_entry()? {
+                Some(entry) => entry,
+                None => match self.batch.take() {
+                    Some((start, _, _)) => {
+                        self.pos = start;
+                        return Err(self.corruption(CorruptionKind::Truncated));
+                    },
+                    None => return Ok(None),
+                },
+            };
+            match (entry, &mut self.batch) {
+                (Entry::Record(record), None) => {
+                    self.committed = pos + len;
+                    return Ok(Some((pos, len, record)));
+                },
+                (Entry::Record(record), Some((_, _, records))) => records.push((pos, len, record)),
+                (Entry::BatchBegin(count), None) => self.batch = Some((pos, count, Vec::new())),
+                (Entry::BatchCommit, Some((_, count, records))) if records.len() == *count as usize => {
+                    self.ready = self.batch.take().unwrap().2.into();
+                    self.committed = pos + len;
+                },
+                _ => {
+                    self.pos = pos;
+                    return Err(self.corruption(CorruptionKind::InvalidData));
+                },
+            }
+        }
+    }
+
+    fn read_entry(&mut self) -> Result<Option<(u64, u64, Entry)>> {
+        let mut header = [0u8; HEADER_LEN];
+        let read = read_full(&mut self.reader, &mut header)?;
+        if read == 0 {
+            return Ok(None);
+        } else if read < HEADER_LEN {
+            return Err(self.corruption(CorruptionKind::Truncated));
+        }
+
+        let len = (HEADER_LEN + body_len(&header)) as u64;
+        if self.pos + len > self.len {
+            // a torn write leaves a short record with an intact header,
+            // while a damaged header may claim any length
+            let kind = header_error(&header).unwrap_or(CorruptionKind::Truncated);
+            return Err(self.corruption(kind));
+        }
+        let mut buf = header.to_vec();
+        buf.resize(len as usize, 0);
+        if read_full(&mut self.reader, &mut buf[HEADER_LEN..])? < buf.len() - HEADER_LEN {
+            return Err(self.corruption(CorruptionKind::Truncated));
+        }
+
+        match decode_entry(&buf) {
+            Ok(entry) => {
+                let pos = self.pos;
+                self.pos += len;
+                Ok(Some((pos, len, entry)))
+            },
+            Err(kind) => Err(self.corruption(kind))
+        }
+    }
+}
+
+impl<R: Read> Iterator for RecordReader<R> {
+    type Item = Result<Located>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.failed {
+            return None;
+        }
+        self.next_record().transpose()
+    }
+}
+
+/// Whether the log file at `path` holds JSON commands written before this format
+pub(super) fn is_json_log(path: &Path) -> Result<bool> {
+    let mut start = [0u8; 7];
+    let read = read_full(&mut File::open(path)?, &mut start)?;
+    let start = &start[..read];
+    Ok(start.starts_with(br#"{"Set":"#) || start.starts_with(br#"{"Rm":"#))
+}
+
+/// A command of a JSON log file
+#[derive(Deserialize)]
+enum JsonCmd {
+    Set { key: String, value: String },
+    Rm { key: String },
+}
+
+/// Write the commands of the JSON log file at `from` as records to `to`, numbered from
+/// `seq + 1` in file order, then read them back. Return the last sequence number.
+///
+/// `to` is synced, `from` is left as it is.
+pub(super) fn convert_json_log(from: &Path, to: &Path, mut seq: u64) -> Result<u64> {
+    let reader = BufReader::new(File::open(from)?);
+    let mut writer = BufWriter::new(File::create(to)?);
+    let mut count = 0;
+    for cmd in serde_json::Deserializer::from_reader(reader).into_iter() {
+        let cmd = match cmd.map_err(|e| err_msg(format!("Unreadable JSON log {}: {}", from.display(), e)))? {
+            JsonCmd::Set { key, value } => Cmd::Set { key: key.into_bytes(), value: value.into_bytes(), expires: None },
+            JsonCmd::Rm { key } => Cmd::Rm { key: key.into_bytes() },
+        };
+        seq += 1;
+        count += 1;
+        writer.write_all(&Record::new(seq, cmd).encode(Compression::None))?;
+    }
+    writer.flush()?;
+    writer.get_ref().sync_all()?;
+
+    let len = writer.get_ref().metadata()?.len();
+    let records = RecordReader::new(BufReader::new(File::open(to)?), to.to_owned(), len);
+    if records.collect::<Result<Vec<_>>>()?.len() != count {
+        return Err(err_msg(format!("Converted log {} does not read back", to.display())));
+    }
+    Ok(seq)
+}
+
+/// Highest sequence number of the records of the log file at `path`, 0 for an empty one
+pub(super) fn max_seq(path: &Path) -> Result<u64> {
+    let file = File::open(path)?;
+    let len = file.metadata()?.len();
+    let mut seq = 0;
+    for record in RecordReader::new(BufReader::new(file), path.to_owned(), len) {
+        seq = seq.max(record?.2.seq);
+    }
+    Ok(seq)
+}
+
+/// Like `read_exact`, but returns the number of bytes read when EOF comes first
+fn read_full<R: Read>(reader: &mut R, mut buf: &mut [u8]) -> io::Result<usize> {
+    let total = buf.len();
+    while !buf.is_empty() {
+        match reader.read(buf) {
+            Ok(0) => break,
+            Ok(n) => buf = &mut buf[n..],
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {},
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(total - buf.len())
+}
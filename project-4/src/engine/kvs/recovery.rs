@@ -0,0 +1,46 @@
+use std::path::PathBuf;
+
+/// What `KvStore::open` had to repair before the store became usable
+#[derive(Debug, Clone, Default)]
+pub struct RecoveryReport {
+    /// Number of records replayed from log files
+    pub records: u64,
+    /// Number of sealed log files loaded from their hint files instead of being replayed
+    pub hints: u64,
+    /// Torn record dropped from the end of the active log, a read-only store leaves it in the file
+    pub truncated: Option<TruncatedTail>,
+    /// How an interrupted compaction was handled
+    pub compaction: Option<CompactionRecovery>,
+    /// Format version the store was upgraded from, see `KvStoreOptions::upgrade_format`
+    pub upgraded_from: Option<u32>,
+}
+
+/// A partially written record at the end of the active log, or garbage no valid record follows.
+///
+/// It is left by a crash in the middle of an append, so it was never acknowledged
+/// and can be dropped safely.
+#[derive(Debug, Clone)]
+pub struct TruncatedTail {
+    /// The active log file
+    pub file: PathBuf,
+    /// Offset the file was truncated to
+    pub offset: u64,
+    /// Number of bytes dropped
+    pub dropped: u64,
+}
+
+/// Actions taken on a compaction interrupted by a crash
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CompactionRecovery {
+    /// The target logs were complete, only clean up was left
+    Finished,
+    /// The partial target logs were dropped, the merged logs are left as they were
+    RolledBack,
+}
+
+impl RecoveryReport {
+    /// Whether the store was opened without any repair
+    pub fn is_clean(&self) -> bool {
+        self.truncated.is_none() && self.compaction.is_none()
+    }
+}
@@ -0,0 +1,117 @@
+use std::{ops::Bound, sync::Arc};
+use crate::error::*;
+use crate::engine::now_millis;
+use super::Core;
+use super::snapshot::SnapshotGuard;
+
+/// Lazy iteration over a range of `Core::index`
+///
+/// Both ends are cursors looked up again at every step, so the iterator
+/// does not borrow the index and concurrent writes are not blocked.
+/// For a snapshot, keys are looked up in `Core::history` too, see `snapshot` module.
+/// A hashed index has no key order, the first step returns `KvsError::ScanUnsupported`.
+pub(super) struct IndexScan {
+    core: Core,
+    front: Bound<Vec<u8>>,
+    back: Bound<Vec<u8>>,
+    done: bool,
+    /// Sequence number of the snapshot read, kept alive by its guard until the scan ends
+    snapshot: Option<(u64, Arc<SnapshotGuard>)>,
+}
+
+impl IndexScan {
+    pub(super) fn new(core: Core, front: Bound<Vec<u8>>, back: Bound<Vec<u8>>) -> Self {
+        IndexScan { core, front, back, done: false, snapshot: None }
+    }
+
+    /// Read the versions seen by the snapshot at `seq`
+    pub(super) fn at(self, seq: u64, guard: Arc<SnapshotGuard>) -> Self {
+        IndexScan { snapshot: Some((seq, guard)), ..self }
+    }
+
+    /// Whether `key` found from one end is still before the other end
+    fn in_range(key: &[u8], end: &Bound<Vec<u8>>, forward: bool) -> bool {
+        match end {
+            Bound::Unbounded => true,
+            Bound::Included(end) if forward => key <= end.as_slice(),
+            Bound::Excluded(end) if forward => key < end.as_slice(),
+            Bound::Included(end) => key >= end.as_slice(),
+            Bound::Excluded(end) => key > end.as_slice(),
+        }
+    }
+
+    /// The first key after `from` in the index, or in the history for a snapshot
+    fn next_key(&self, from: &Bound<Vec<u8>>, forward: bool) -> Option<Vec<u8>> {
+        let key = self.core.index.next_key(from.as_ref().map(Vec::as_slice), forward);
+        if self.snapshot.is_none() {
+            return key;
+        }
+
+        // every version of a key is between (key, 0) and (key, u64::MAX)
+        let entry = match (from, forward) {
+            (Bound::Unbounded, true) => self.core.history.front(),
+            (Bound::Unbounded, false) => self.core.history.back(),
+            (Bound::Included(key), true) => self.core.history.lower_bound(Bound::Included(&(key.clone(), 0))),
+            (Bound::Excluded(key), true) => self.core.history.lower_bound(Bound::Excluded(&(key.clone(), u64::MAX))),
+            (Bound::Included(key), false) => self.core.history.upper_bound(Bound::Included(&(key.clone(), u64::MAX))),
+            (Bound::Excluded(key), false) => self.core.history.upper_bound(Bound::Excluded(&(key.clone(), 0))),
+        };
+        let old = entry.map(|entry| entry.key().0.clone());
+        match (key, old) {
+            (Some(key), Some(old)) if forward => Some(key.min(old)),
+            (Some(key), Some(old)) => Some(key.max(old)),
+            (key, old) => key.or(old),
+        }
+    }
+
+    fn step(&mut self, forward: bool) -> Option<Result<(Vec<u8>, Vec<u8>)>> {
+        if !self.done && !self.core.index.is_ordered() {
+            self.done = true;
+            return Some(Err(KvsError::ScanUnsupported.into()));
+        }
+        let now = now_millis();
+        while !self.done {
+            let (from, to) = if forward { (&self.front, &self.back) } else { (&self.back, &self.front) };
+            let key = match self.next_key(from, forward) {
+                Some(key) if Self::in_range(&key, to, forward) => key,
+                _ => {
+                    self.done = true;
+                    break;
+                }
+            };
+            let pos = match &self.snapshot {
+                Some((seq, _)) => self.core.lookup_at(&key, *seq),
+                None => self.core.index.get(&key),
+            };
+            let pos = match pos {
+                Ok(pos) => pos,
+                Err(e) => return Some(Err(e)),
+            };
+            if forward {
+                self.front = Bound::Excluded(key.clone());
+            } else {
+                self.back = Bound::Excluded(key.clone());
+            }
+            // skip expired keys, and keys removed since found
+            match pos {
+                Some(pos) if !pos.expired(now) => return Some(self.core.read_value(&pos).map(|value| (key, value))),
+                _ => continue,
+            }
+        }
+        None
+    }
+}
+
+impl Iterator for IndexScan {
+    type Item = Result<(Vec<u8>, Vec<u8>)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.step(true)
+    }
+}
+
+impl DoubleEndedIterator for IndexScan {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        self.step(false)
+    }
+}
@@ -0,0 +1,89 @@
+//! Log segments of a `KvStore`
+//!
+//! Records are appended to the active segment, the log file with the highest id.
+//! The manifest lists the segments, see `manifest` module.
+//! Once it reaches `KvStoreOptions::max_segment_size`, it is synced and sealed, and a new one
+//! is started with the next id. Sealed segments are only read until a compaction merges them.
+//!
+//! Ids only grow, but a segment written by compaction holds older records than the segments
+//! before it, so `restore` keeps the record of a key with the highest sequence number,
+//! whatever the order segments are read in. Records sharing a sequence number are copies
+//! moved by blob collection, the one in the segment with the highest id is the last.
+//!
+//! Compaction merges the sealed segments whose share of dead bytes reaches
+//! `KvStoreOptions::compaction_garbage_ratio`, along with the small ones, into new segments.
+//! Tombstones are copied as long as a sealed segment is left out, or an older version of their key
+//! is copied for snapshots, they may hide those records.
+use std::{collections::HashMap, fs, io::Write, mem, path::Path, sync::{Arc, RwLock, atomic::Ordering}};
+use crate::error::*;
+use super::{CmdWriter, Core, lock, log_path, new_log_file};
+
+impl Core {
+    /// Seal the active segment and start a new one, the caller holds `self.writer` lock.
+    ///
+    /// An empty active segment is deleted instead, no record can point to it.
+    pub(super) fn roll(&self, writer: &mut CmdWriter) -> Result<()> {
+        writer.flush()?;
+        writer.writer.get_ref().sync_data()?;
+        let id = self.next_id.fetch_add(1, Ordering::SeqCst);
+        let empty = writer.pos == 0;
+        self.update_manifest(|manifest| {
+            if !empty {
+                manifest.segments.push(manifest.active);
+            }
+            manifest.active = id;
+        })?;
+        let (reader, new_writer) = new_log_file(&self.dir_path, id)?;
+        self.readers.insert(id, Arc::new(RwLock::new(reader)));
+        let sealed = mem::replace(writer, new_writer);
+        if empty {
+            self.readers.remove(&sealed.id);
+            fs::remove_file(log_path(&self.dir_path, sealed.id))?;
+        }
+        Ok(())
+    }
+
+    /// Sealed segments to merge, in id order.
+    ///
+    /// Live bytes are those the index and history point to. Segments under a quarter of
+    /// `KvStoreOptions::max_segment_size` are merged along with others, or when there are
+    /// two of them, so segments written by compaction do not pile up.
+    pub(super) fn pick_segments(&self) -> Result<Vec<u64>> {
+        let mut live: HashMap<u64, u64> = HashMap::new();
+        self.index.for_each(|pos| *live.entry(pos.id).or_insert(0) += pos.len);
+        for entry in self.history.iter() {
+            if let Some(pos) = entry.value() {
+                *live.entry(pos.id).or_insert(0) += pos.len;
+            }
+        }
+
+        let mut picked = Vec::new();
+        let mut small = Vec::new();
+        let segments = lock(&self.manifest).segments.clone();
+        for id in segments {
+            let len = fs::metadata(log_path(&self.dir_path, id))?.len();
+            let dead = len.saturating_sub(live.get(&id).copied().unwrap_or(0));
+            if dead as f64 >= self.options.compaction_garbage_ratio * len as f64 {
+                picked.push(id);
+            } else if len < self.options.max_segment_size / 4 {
+                small.push(id);
+            }
+        }
+        if !picked.is_empty() || small.len() > 1 {
+            picked.append(&mut small);
+            picked.sort_unstable();
+        }
+        Ok(picked)
+    }
+}
+
+/// Ids of the log files in `dir`, in order
+pub(super) fn log_ids(dir: &Path) -> Result<Vec<u64>> {
+    let mut ids: Vec<u64> = fs::read_dir(dir)?.flatten()
+        .map(|entry| entry.path())
+        .filter(|path| path.is_file() && path.extension() == Some("log".as_ref()))
+        .filter_map(|path| path.file_stem()?.to_str()?.parse().ok())
+        .collect();
+    ids.sort_unstable();
+    Ok(ids)
+}
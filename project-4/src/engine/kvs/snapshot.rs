@@ -0,0 +1,221 @@
+//! Point-in-time reads of a `KvStore`
+//!
+//! A snapshot is the sequence number of the last record written when it was taken.
+//! The index only holds the last version of every key, so while snapshots are alive,
+//! writers move the version they replace into `Core::history`, keyed by key and version.
+//! A removal adds a `None` entry there, hiding the older versions from newer snapshots.
+//!
+//! A snapshot reads the index entry of a key if it is not newer than its sequence number,
+//! otherwise the newest history entry not newer than it.
+//! Entries no live snapshot can read are pruned when a snapshot is dropped,
+//! compaction copies the records history still points to, see `Core::compact_logs`.
+use std::{ops::{Bound, RangeBounds}, path::Path, sync::{Arc, atomic::Ordering}, time::Duration};
+use crate::error::*;
+use crate::engine::{KvsEngine, ReadView, Scan, WriteBatch, now_millis};
+use super::{CmdPos, Core, KvStore, lock};
+use super::scan::IndexScan;
+
+/// Read-only view of a `KvStore` as it was when `KvStore::snapshot` was called
+///
+/// ```no_run
+/// # use kvs::{KvStore, KvsEngine};
+/// let store = KvStore::open("db")?;
+/// let snapshot = store.snapshot();
+/// store.set("key".to_owned(), "new".to_owned())?;
+/// for pair in snapshot.scan(..) {
+///     let (key, value) = pair?;
+///     println!("{} was {}", key, value);
+/// }
+/// # Ok::<(), failure::Error>(())
+/// ```
+///
+/// Writes done after it was taken are not seen, except that keys expiring meanwhile read as missing.
+/// Every write returns `KvsError::ReadOnly`.
+/// Older versions are kept in memory and in the log until the last clone is dropped.
+#[derive(Clone)]
+pub struct Snapshot {
+    core: Core,
+    seq: u64,
+    guard: Arc<SnapshotGuard>,
+}
+
+/// Release the versions kept for a snapshot once every clone and scan of it is dropped
+pub(super) struct SnapshotGuard {
+    core: Core,
+    seq: u64,
+}
+
+impl KvStore {
+    /// Take a consistent read-only view of the store, see `Snapshot`
+    pub fn snapshot(&self) -> Snapshot {
+        // no write is half applied to the index under `writer` lock
+        let _writer = lock(&self.core.writer);
+        let seq = self.core.seq.load(Ordering::SeqCst) - 1;
+        *lock(&self.core.snapshots).entry(seq).or_insert(0) += 1;
+        let guard = SnapshotGuard { core: self.core.clone(), seq };
+        Snapshot { core: self.core.clone(), seq, guard: Arc::new(guard) }
+    }
+}
+
+impl Snapshot {
+    /// Sequence number of the last write seen by the snapshot
+    pub fn seq(&self) -> u64 {
+        self.seq
+    }
+}
+
+impl KvsEngine for Snapshot {
+    fn set_bytes(&self, _key: Vec<u8>, _value: Vec<u8>) -> Result<()> {
+        Err(KvsError::ReadOnly.into())
+    }
+
+    fn set_bytes_with_ttl(&self, _key: Vec<u8>, _value: Vec<u8>, _ttl: Duration) -> Result<()> {
+        Err(KvsError::ReadOnly.into())
+    }
+
+    fn get_bytes(&self, key: Vec<u8>) -> Result<Option<Vec<u8>>> {
+        Ok(self.get_versioned_bytes(key)?.0)
+    }
+
+    fn ttl_bytes(&self, key: Vec<u8>) -> Result<Option<Duration>> {
+        Ok(self.core.lookup_at(&key, self.seq)?.and_then(|pos| pos.ttl(now_millis())))
+    }
+
+    fn remove_bytes(&self, _key: Vec<u8>) -> Result<()> {
+        Err(KvsError::ReadOnly.into())
+    }
+
+    fn compare_and_swap_bytes(&self, _key: Vec<u8>, _expected: Option<Vec<u8>>, _new: Option<Vec<u8>>) -> Result<()> {
+        Err(KvsError::ReadOnly.into())
+    }
+
+    fn apply_batch(&self, _batch: WriteBatch) -> Result<()> {
+        Err(KvsError::ReadOnly.into())
+    }
+
+    fn get_versioned_bytes(&self, key: Vec<u8>) -> Result<(Option<Vec<u8>>, u64)> {
+        match self.core.lookup_at(&key, self.seq)? {
+            Some(pos) if !pos.expired(now_millis()) => Ok((Some(self.core.read_value(&pos)?), pos.version)),
+            _ => Ok((None, 0)),
+        }
+    }
+
+    fn commit_versioned(&self, _versions: Vec<(Vec<u8>, u64)>, _batch: WriteBatch) -> Result<()> {
+        Err(KvsError::ReadOnly.into())
+    }
+
+    fn read_view(&self) -> Box<dyn ReadView> {
+        Box::new(self.clone())
+    }
+
+    fn checkpoint(&self, _dest: &Path) -> Result<()> {
+        Err(err_msg("Checkpoint of a snapshot is not supported, use the store"))
+    }
+
+    fn sync(&self) -> Result<()> {
+        Ok(())
+    }
+
+    fn scan_bytes<R: RangeBounds<Vec<u8>>>(&self, range: R) -> Scan {
+        let (start, end) = (range.start_bound().cloned(), range.end_bound().cloned());
+        Scan::new(IndexScan::new(self.core.clone(), start, end).at(self.seq, self.guard.clone()))
+    }
+}
+
+/// The read view of transactions started on a `KvStore`
+impl ReadView for Snapshot {
+    fn get_versioned(&self, key: Vec<u8>) -> Result<(Option<Vec<u8>>, u64)> {
+        KvsEngine::get_versioned_bytes(self, key)
+    }
+}
+
+impl Drop for SnapshotGuard {
+    fn drop(&mut self) {
+        self.core.release_snapshot(self.seq);
+    }
+}
+
+impl Core {
+    /// Position of the version of `key` seen by the snapshot at `seq`
+    pub(super) fn lookup_at(&self, key: &[u8], seq: u64) -> Result<Option<CmdPos>> {
+        // writers fill history before updating the index, so it is checked second
+        if let Some(pos) = self.index.get(key)? {
+            if pos.version <= seq {
+                return Ok(Some(pos));
+            }
+        }
+        let bound = (key.to_vec(), seq);
+        Ok(self.history.upper_bound(Bound::Included(&bound))
+            .filter(|entry| entry.key().0 == key)
+            .and_then(|entry| *entry.value()))
+    }
+
+    /// Keep the current version of `key` for snapshots before it is replaced
+    /// by the record `seq`, or removed. The caller holds `self.writer` lock.
+    pub(super) fn retain_version(&self, key: &[u8], seq: u64, removed: bool) -> Result<()> {
+        let newest = match lock(&self.snapshots).keys().next_back() {
+            Some(newest) => *newest,
+            None => return Ok(()),
+        };
+        if let Some(pos) = self.index.get(key)? {
+            if pos.version <= newest {
+                self.history.insert((key.to_vec(), pos.version), Some(pos));
+            }
+        }
+        if removed {
+            self.hide_history(key, seq);
+        }
+        Ok(())
+    }
+
+    /// Hide the kept versions of a key removed by the record `seq` from newer snapshots
+    pub(super) fn hide_history(&self, key: &[u8], seq: u64) {
+        let first = (key.to_vec(), 0);
+        let kept = self.history.lower_bound(Bound::Included(&first))
+            .is_some_and(|entry| entry.key().0 == key);
+        if kept {
+            self.history.insert((key.to_vec(), seq), None);
+        }
+    }
+
+    /// Forget a dropped snapshot and prune the versions no other one reads
+    fn release_snapshot(&self, seq: u64) {
+        let _writer = lock(&self.writer);
+        let mut snapshots = lock(&self.snapshots);
+        if let Some(count) = snapshots.get_mut(&seq) {
+            *count -= 1;
+            if *count == 0 {
+                snapshots.remove(&seq);
+            }
+        }
+        if snapshots.is_empty() {
+            self.history.clear();
+            return;
+        }
+
+        let entries: Vec<(Vec<u8>, u64, bool)> = self.history.iter()
+            .map(|entry| (entry.key().0.clone(), entry.key().1, entry.value().is_some()))
+            .collect();
+        for group in entries.chunk_by(|a, b| a.0 == b.0) {
+            let key = &group[0].0;
+            // a key the index fails to read keeps its versions
+            let last = self.index.get(key).ok().flatten().map_or(u64::MAX, |pos| pos.version);
+            // a removal is kept as long as an older version is, new snapshots would see it otherwise
+            let mut older_kept = false;
+            for (i, (_, version, is_value)) in group.iter().enumerate() {
+                // a version is read by the snapshots taken before the next one
+                let next = group.get(i + 1).map_or(last, |(_, next, _)| *next);
+                let kept = if *is_value {
+                    *version < next && snapshots.range(*version..next).next().is_some()
+                } else {
+                    older_kept
+                };
+                if kept {
+                    older_kept = true;
+                } else {
+                    self.history.remove(&(key.clone(), *version));
+                }
+            }
+        }
+    }
+}
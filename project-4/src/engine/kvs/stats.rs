@@ -0,0 +1,67 @@
+//! Counters of a `KvStore` since it was opened
+use std::sync::atomic::{AtomicU64, Ordering};
+use super::{Cmd, Core, KvStore};
+use super::record::{HEADER_LEN, Record};
+
+/// Counters shared by all clones of a store
+#[derive(Default)]
+pub(super) struct Counters {
+    values: AtomicU64,
+    value_bytes: AtomicU64,
+    stored_value_bytes: AtomicU64,
+}
+
+/// Statistics of a `KvStore` since it was opened, see `KvStore::stats`
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct KvStoreStats {
+    /// Number of values written by `set` and batches, copies made by compaction are not counted
+    pub values_written: u64,
+    /// Bytes of these values before compression
+    pub value_bytes: u64,
+    /// Bytes of these values in the log, after compression
+    pub stored_value_bytes: u64,
+    /// Reads served by the value cache, see `KvStoreOptions::cache_size`
+    pub cache_hits: u64,
+    /// Reads which looked up the value cache and went to disk
+    pub cache_misses: u64,
+}
+
+impl KvStoreStats {
+    /// Bytes of values written per byte stored, 1 when nothing was written or compressed
+    pub fn compression_ratio(&self) -> f64 {
+        if self.stored_value_bytes == 0 {
+            return 1.0;
+        }
+        self.value_bytes as f64 / self.stored_value_bytes as f64
+    }
+}
+
+impl KvStore {
+    /// Statistics of the writes and reads done since the store was opened
+    pub fn stats(&self) -> KvStoreStats {
+        let counters = &self.core.stats;
+        let cache = self.core.cache.as_ref();
+        KvStoreStats {
+            values_written: counters.values.load(Ordering::Relaxed),
+            value_bytes: counters.value_bytes.load(Ordering::Relaxed),
+            stored_value_bytes: counters.stored_value_bytes.load(Ordering::Relaxed),
+            cache_hits: cache.map_or(0, |cache| cache.hits.load(Ordering::Relaxed)),
+            cache_misses: cache.map_or(0, |cache| cache.misses.load(Ordering::Relaxed)),
+        }
+    }
+}
+
+impl Core {
+    /// Encode a record written by a user with the codec of the store, and count its value
+    pub(super) fn encode_counted(&self, record: &Record) -> Vec<u8> {
+        let buf = record.encode(self.options.compression);
+        if let Cmd::Set { key, value, expires } = &record.cmd {
+            let expiry = if expires.is_some() { 8 } else { 0 };
+            let stored = buf.len() - HEADER_LEN - key.len() - expiry;
+            self.stats.values.fetch_add(1, Ordering::Relaxed);
+            self.stats.value_bytes.fetch_add(value.len() as u64, Ordering::Relaxed);
+            self.stats.stored_value_bytes.fetch_add(stored as u64, Ordering::Relaxed);
+        }
+        buf
+    }
+}
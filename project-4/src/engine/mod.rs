@@ -1,23 +1,161 @@
+use std::{ops::{Bound, RangeBounds}, path::Path, time::{Duration, SystemTime, UNIX_EPOCH}};
 use crate::error::Result;
 
 /// Trait for a key value store engine
+///
+/// Keys and values are arbitrary bytes, the `String` methods are wrappers
+/// of the `_bytes` ones for UTF-8 data.
 pub trait KvsEngine: Clone + Send + 'static {
     /// Set the pair of key and value
-    fn set(&self, key: String, vale: String) -> Result<()>;
+    fn set_bytes(&self, key: Vec<u8>, value: Vec<u8>) -> Result<()>;
+    /// Set the pair of key and value, the key expires after `ttl`
+    ///
+    /// An expired key is treated as removed. Setting it again without ttl makes it permanent.
+    fn set_bytes_with_ttl(&self, key: Vec<u8>, value: Vec<u8>, ttl: Duration) -> Result<()>;
     /// Get the value by key
     ///
     /// Return `None` if key does not exist
-    fn get(&self, key: String) -> Result<Option<String>>;
+    fn get_bytes(&self, key: Vec<u8>) -> Result<Option<Vec<u8>>>;
+    /// Get the time left before the key expires
+    ///
+    /// Return `None` if key does not exist or was set without ttl
+    fn ttl_bytes(&self, key: Vec<u8>) -> Result<Option<Duration>>;
     /// Remove the value by key
     ///
     /// # Errors
     ///
     /// Error with message `Key not found` will be retured if key does not exist 
-    fn remove(&self, key: String) -> Result<()>;
+    fn remove_bytes(&self, key: Vec<u8>) -> Result<()>;
+    /// Replace the value of `key` by `new` if it currently is `expected`, atomically
+    ///
+    /// `None` stands for a missing key, as `expected` it asks for the key to be absent
+    /// and as `new` it removes the key. A value written this way never expires.
+    ///
+    /// # Errors
+    ///
+    /// `KvsError::ConditionFailed` with the current value is returned if it is not `expected`
+    fn compare_and_swap_bytes(&self, key: Vec<u8>, expected: Option<Vec<u8>>, new: Option<Vec<u8>>) -> Result<()>;
+    /// Set the pair of key and value only if the key does not exist, see `compare_and_swap_bytes`
+    fn set_if_absent_bytes(&self, key: Vec<u8>, value: Vec<u8>) -> Result<()> {
+        self.compare_and_swap_bytes(key, None, Some(value))
+    }
+    /// Remove the key only if its value is `expected`, see `compare_and_swap_bytes`
+    fn remove_if_equals_bytes(&self, key: Vec<u8>, expected: Vec<u8>) -> Result<()> {
+        self.compare_and_swap_bytes(key, Some(expected), None)
+    }
+    /// Apply every write of `batch` in order, atomically
+    ///
+    /// After a crash, either all of them or none are seen.
+    fn apply_batch(&self, batch: WriteBatch) -> Result<()>;
+    /// Get the value by key with its version, used by `Transaction`
+    ///
+    /// The version changes whenever the key is written, 0 stands for a missing key.
+    fn get_versioned_bytes(&self, key: Vec<u8>) -> Result<(Option<Vec<u8>>, u64)>;
+    /// Apply `batch` atomically if every key still has the given version, used by `Transaction`
+    ///
+    /// # Errors
+    ///
+    /// `KvsError::Conflict` is returned with a key whose version changed, nothing is applied then
+    fn commit_versioned(&self, versions: Vec<(Vec<u8>, u64)>, batch: WriteBatch) -> Result<()>;
+    /// Take a view of the store reading it as it is now, used by `Transaction`
+    fn read_view(&self) -> Box<dyn ReadView>;
+    /// Start an optimistic transaction reading the store as it is now, see `Transaction`
+    fn begin(&self) -> Transaction<Self> {
+        Transaction::new(self.clone())
+    }
+    /// Write a copy of the store into the directory `dest`, which can be opened by the same engine
+    ///
+    /// The copy holds every write finished before the call, and each write done meanwhile
+    /// either entirely or not at all. Engines may block writes while copying.
+    ///
+    /// # Errors
+    ///
+    /// Error will be returned if `dest` exists and is not an empty directory
+    fn checkpoint(&self, dest: &Path) -> Result<()>;
+    /// Persist every write done so far, whatever the `SyncPolicy`
+    fn sync(&self) -> Result<()>;
+    /// Iterate the pairs with keys in `range`, in key order
+    fn scan_bytes<R: RangeBounds<Vec<u8>>>(&self, range: R) -> Scan;
+    /// Iterate the pairs with keys starting with `prefix`, in key order
+    fn scan_prefix_bytes(&self, prefix: Vec<u8>) -> Scan {
+        self.scan_bytes(scan::prefix_range(prefix))
+    }
+
+    /// Set the pair of key and value
+    fn set(&self, key: String, value: String) -> Result<()> {
+        self.set_bytes(key.into_bytes(), value.into_bytes())
+    }
+    /// Set the pair of key and value, the key expires after `ttl`
+    fn set_with_ttl(&self, key: String, value: String, ttl: Duration) -> Result<()> {
+        self.set_bytes_with_ttl(key.into_bytes(), value.into_bytes(), ttl)
+    }
+    /// Get the value by key
+    ///
+    /// Return `None` if key does not exist, an error if the value is not UTF-8
+    fn get(&self, key: String) -> Result<Option<String>> {
+        match self.get_bytes(key.into_bytes())? {
+            Some(value) => Ok(Some(String::from_utf8(value)?)),
+            None => Ok(None),
+        }
+    }
+    /// Get the time left before the key expires, see `ttl_bytes`
+    fn ttl(&self, key: String) -> Result<Option<Duration>> {
+        self.ttl_bytes(key.into_bytes())
+    }
+    /// Remove the value by key, see `remove_bytes`
+    fn remove(&self, key: String) -> Result<()> {
+        self.remove_bytes(key.into_bytes())
+    }
+    /// Replace the value of `key` by `new` if it currently is `expected`, see `compare_and_swap_bytes`
+    fn compare_and_swap(&self, key: String, expected: Option<String>, new: Option<String>) -> Result<()> {
+        self.compare_and_swap_bytes(key.into_bytes(), expected.map(String::into_bytes), new.map(String::into_bytes))
+    }
+    /// Set the pair of key and value only if the key does not exist
+    fn set_if_absent(&self, key: String, value: String) -> Result<()> {
+        self.set_if_absent_bytes(key.into_bytes(), value.into_bytes())
+    }
+    /// Remove the key only if its value is `expected`
+    fn remove_if_equals(&self, key: String, expected: String) -> Result<()> {
+        self.remove_if_equals_bytes(key.into_bytes(), expected.into_bytes())
+    }
+    /// Iterate the pairs with keys in `range`, in key order
+    ///
+    /// A pair which is not UTF-8 is yielded as an error.
+    fn scan<R: RangeBounds<String>>(&self, range: R) -> Scan<String> {
+        let bytes = |bound: Bound<&String>| bound.map(|key| key.clone().into_bytes());
+        self.scan_bytes((bytes(range.start_bound()), bytes(range.end_bound()))).into_strings()
+    }
+    /// Iterate the pairs with keys starting with `prefix`, in key order
+    fn scan_prefix(&self, prefix: String) -> Scan<String> {
+        self.scan_prefix_bytes(prefix.into_bytes()).into_strings()
+    }
+}
+
+/// Current time in milliseconds since the Unix epoch, the unit of expiry times
+fn now_millis() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |now| now.as_millis() as u64)
+}
+
+/// Expiry time of a key set now with given ttl
+fn expiry_after(ttl: Duration) -> u64 {
+    now_millis().saturating_add(ttl.as_millis() as u64)
+}
+
+/// Time left at `now` before the expiry time `expires`
+fn ttl_left(expires: u64, now: u64) -> Duration {
+    Duration::from_millis(expires.saturating_sub(now))
 }
 
+mod batch;
 mod kvs;
+mod scan;
 mod sled;
+mod sync;
+mod transaction;
 
-pub use self::kvs::KvStore;
-pub use self::sled::SledKvsEngine;
\ No newline at end of file
+pub use self::batch::{BatchOp, WriteBatch};
+pub use self::kvs::{CompactionRecovery, Compression, IndexMode, KvStore, KvStoreOptions, KvStoreStats, RecoveryReport, Snapshot, TruncatedTail};
+pub use self::scan::Scan;
+pub use self::sled::SledKvsEngine;
+pub use self::sync::SyncPolicy;
+pub use self::transaction::{ReadView, Transaction};
@@ -0,0 +1,58 @@
+use std::ops::Bound;
+use crate::error::*;
+
+/// Iterator over key-value pairs returned by `KvsEngine::scan`, in key order
+///
+/// It is double ended, use `rev` to iterate from the last key.
+/// Pairs are read lazily, so writes done meanwhile may or may not be seen.
+pub struct Scan<T = Vec<u8>> {
+    inner: Box<dyn DoubleEndedIterator<Item = Result<(T, T)>> + Send>,
+}
+
+impl<T> Scan<T> {
+    pub(super) fn new<I>(inner: I) -> Self
+    where
+        I: DoubleEndedIterator<Item = Result<(T, T)>> + Send + 'static,
+    {
+        Scan { inner: Box::new(inner) }
+    }
+}
+
+impl Scan {
+    /// Decode keys and values as UTF-8
+    pub(super) fn into_strings(self) -> Scan<String> {
+        Scan::new(self.map(|pair| {
+            let (key, value) = pair?;
+            Ok((String::from_utf8(key)?, String::from_utf8(value)?))
+        }))
+    }
+}
+
+impl<T> Iterator for Scan<T> {
+    type Item = Result<(T, T)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.inner.next()
+    }
+}
+
+impl<T> DoubleEndedIterator for Scan<T> {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        self.inner.next_back()
+    }
+}
+
+/// Range of keys starting with `prefix`
+///
+/// The end bound is the smallest key greater than every key with the prefix,
+/// the prefix without its trailing `0xff` bytes, with the last byte incremented.
+pub(super) fn prefix_range(prefix: Vec<u8>) -> (Bound<Vec<u8>>, Bound<Vec<u8>>) {
+    let mut end = prefix.clone();
+    while let Some(last) = end.pop() {
+        if last < u8::MAX {
+            end.push(last + 1);
+            return (Bound::Included(prefix), Bound::Excluded(end));
+        }
+    }
+    (Bound::Included(prefix), Bound::Unbounded)
+}
@@ -1,41 +1,440 @@
-use std::path::PathBuf;
-use sled;
+use std::{collections::{BTreeMap, HashMap}, convert::TryInto, fs, iter, ops::RangeBounds, path::{Path, PathBuf}, sync::{Arc, Mutex, MutexGuard, RwLock, RwLockReadGuard, RwLockWriteGuard}, time::Duration};
+use sled::Transactional;
+use sled::transaction::{ConflictableTransactionError, TransactionError};
 use crate::error::*;
-use crate::engine::KvsEngine;
+use crate::engine::{BatchOp, KvsEngine, ReadView, Scan, SyncPolicy, WriteBatch, expiry_after, now_millis, ttl_left};
+use crate::engine::sync::{SyncState, SyncTicker};
+
+/// Name of the tree holding expiry times
+const TTL_TREE: &str = "ttl";
+/// Name of the tree holding versions of keys
+const VERSION_TREE: &str = "version";
+/// Version of a stored key without one in the `version` tree, written before it existed
+const UNTRACKED_VERSION: u64 = 1;
 
 /// This package and implementation `sled` as one of the engines in this crate 
+///
+/// Expiry times of keys set with a ttl live in a separate `ttl` tree,
+/// expired keys are hidden until they are set or removed again.
+/// Writes of keys without one only touch the data tree.
+/// Every write of a key stores a new version of it in the `version` tree, taken from
+/// `Db::generate_id` so it is never reused, which transactions check on commit.
+/// Transactions read through a `SledView` fixed when they begin.
 #[derive(Clone)]
 pub struct SledKvsEngine {
-    store: sled::Db
+    store: sled::Db,
+    ttl: sled::Tree,
+    versions: sled::Tree,
+    /// Held shared by every other write, exclusively by writes giving keys an expiry time,
+    /// so none is given between the check of a write skipping the `ttl` tree and its write.
+    /// Also exclusive for `commit_versioned`, so shared writes can store data and version apart,
+    /// for `checkpoint`, so the copy holds no part of a write,
+    /// and for every write while a `SledView` is live, see `writes_recording`.
+    writes: Arc<RwLock<()>>,
+    views: Arc<Mutex<Views>>,
+    sync: Arc<SyncState>,
+    /// Only for `SyncPolicy::Interval`, kept for its `Drop`
+    _ticker: Option<Arc<SyncTicker>>,
 }
 
 impl KvsEngine for SledKvsEngine {
-    fn set(&self, key: String, value: String) -> Result<()> {
-        self.store.insert(key, value.as_bytes())?;
-        self.store.flush()?;
-        Ok(())
+    fn set_bytes(&self, key: Vec<u8>, value: Vec<u8>) -> Result<()> {
+        let len = key.len() + value.len();
+        let version = self.next_version()?;
+        let _writes = self.writes_recording(&key)?;
+        if self.ttl.contains_key(&key)? {
+            (&*self.store, &self.ttl, &self.versions).transaction(|(data, ttl, versions)| {
+                data.insert(key.as_slice(), value.as_slice())?;
+                ttl.remove(key.as_slice())?;
+                versions.insert(key.as_slice(), &version[..])?;
+                Ok(())
+            }).map_err(transaction_error)?;
+        } else {
+            // the version is written last, see `get_versioned_bytes`
+            self.store.insert(key.as_slice(), value)?;
+            self.versions.insert(key, &version[..])?;
+        }
+        self.flush_if_needed(len)
     }
-    fn get(&self, key: String) -> Result<Option<String>> {
-        Ok(match self.store.get(key)? {
-            Some(bytes) => {
-                Some(std::str::from_utf8(&bytes)?.to_owned())
-            },
-            None => None,
-        })
+    fn set_bytes_with_ttl(&self, key: Vec<u8>, value: Vec<u8>, ttl: Duration) -> Result<()> {
+        let len = key.len() + value.len();
+        let expires = expiry_after(ttl).to_le_bytes();
+        let version = self.next_version()?;
+        let _writes = self.exclusive_writes();
+        self.record_replaced(iter::once(key.as_slice()))?;
+        (&*self.store, &self.ttl, &self.versions).transaction(|(data, ttl, versions)| {
+            data.insert(key.as_slice(), value.as_slice())?;
+            ttl.insert(key.as_slice(), &expires[..])?;
+            versions.insert(key.as_slice(), &version[..])?;
+            Ok(())
+        }).map_err(transaction_error)?;
+        self.flush_if_needed(len)
+    }
+    fn get_bytes(&self, key: Vec<u8>) -> Result<Option<Vec<u8>>> {
+        match self.store.get(&key)? {
+            Some(bytes) if !expired(&self.ttl, &key)? => Ok(Some(bytes.to_vec())),
+            _ => Ok(None),
+        }
+    }
+    fn ttl_bytes(&self, key: Vec<u8>) -> Result<Option<Duration>> {
+        if !self.store.contains_key(&key)? {
+            return Ok(None);
+        }
+        let now = now_millis();
+        Ok(self.ttl.get(&key)?
+            .map(|expires| decode_u64(&expires))
+            .filter(|expires| *expires > now)
+            .map(|expires| ttl_left(expires, now)))
+    }
+    fn remove_bytes(&self, key: Vec<u8>) -> Result<()> {
+        let len = key.len();
+        let _writes = self.writes_recording(&key)?;
+        let found = if self.ttl.contains_key(&key)? {
+            (&*self.store, &self.ttl, &self.versions).transaction(|(data, ttl, versions)| {
+                let value = data.remove(key.as_slice())?;
+                let expires = ttl.remove(key.as_slice())?;
+                versions.remove(key.as_slice())?;
+                Ok(value.is_some() && expires.is_none_or(|expires| decode_u64(&expires) > now_millis()))
+            }).map_err(transaction_error)?
+        } else {
+            let found = self.store.remove(key.as_slice())?.is_some();
+            self.versions.remove(key)?;
+            found
+        };
+        if !found {
+            return Err(err_msg("Key not found"));
+        }
+        self.flush_if_needed(len)
+    }
+
+    fn compare_and_swap_bytes(&self, key: Vec<u8>, expected: Option<Vec<u8>>, new: Option<Vec<u8>>) -> Result<()> {
+        let len = key.len() + new.as_ref().map_or(0, Vec::len);
+        let version = self.next_version()?;
+        let _writes = self.writes_recording(&key)?;
+        (&*self.store, &self.ttl, &self.versions).transaction(|(data, ttl, versions)| {
+            // an expired value is still stored, it is what the swap replaces
+            let expires = ttl.get(&key)?;
+            let expired = expires.as_ref().is_some_and(|expires| decode_u64(expires) <= now_millis());
+            let current = data.get(&key)?.filter(|_| !expired).map(|value| value.to_vec());
+            if current != expected {
+                return Err(ConflictableTransactionError::Abort(current));
+            }
+            match &new {
+                Some(value) => {
+                    data.insert(key.as_slice(), value.as_slice())?;
+                    versions.insert(key.as_slice(), &version[..])?;
+                },
+                None => {
+                    data.remove(key.as_slice())?;
+                    versions.remove(key.as_slice())?;
+                },
+            }
+            if expires.is_some() {
+                ttl.remove(key.as_slice())?;
+            }
+            Ok(())
+        }).map_err(|e| match e {
+            TransactionError::Abort(current) => KvsError::ConditionFailed { current }.into(),
+            TransactionError::Storage(e) => Error::from(e),
+        })?;
+        self.flush_if_needed(len)
+    }
+
+    fn apply_batch(&self, batch: WriteBatch) -> Result<()> {
+        self.commit_versioned(Vec::new(), batch)
+    }
+
+    /// The version is read before the value while writes store it after, so a value paired
+    /// with an older version than its own can only make the commit fail
+    fn get_versioned_bytes(&self, key: Vec<u8>) -> Result<(Option<Vec<u8>>, u64)> {
+        let version = self.versions.get(&key)?;
+        let value = self.get_bytes(key)?;
+        let version = version_of(value.as_deref(), version.as_deref());
+        Ok((value, version))
+    }
+
+    fn commit_versioned(&self, versions: Vec<(Vec<u8>, u64)>, batch: WriteBatch) -> Result<()> {
+        let version = self.next_version()?;
+        let (mut data, mut new_versions) = (sled::Batch::default(), sled::Batch::default());
+        let mut written = Vec::new();
+        let mut len = 0;
+        for op in batch.ops {
+            match op {
+                BatchOp::Set { key, value } => {
+                    len += key.len() + value.len();
+                    written.push(key.clone());
+                    new_versions.insert(key.as_slice(), &version[..]);
+                    data.insert(key, value);
+                },
+                BatchOp::Remove { key } => {
+                    len += key.len();
+                    written.push(key.clone());
+                    new_versions.remove(key.as_slice());
+                    data.remove(key);
+                },
+            }
+        }
+        let _writes = self.exclusive_writes();
+        self.record_replaced(written.iter().map(Vec::as_slice))?;
+        (&*self.store, &self.ttl, &self.versions).transaction(|(data_tree, ttl_tree, version_tree)| {
+            let now = now_millis();
+            for (key, version) in &versions {
+                let expired = ttl_tree.get(key)?.is_some_and(|expires| decode_u64(&expires) <= now);
+                let value = data_tree.get(key)?.filter(|_| !expired);
+                if version_of(value.as_deref(), version_tree.get(key)?.as_deref()) != *version {
+                    return Err(ConflictableTransactionError::Abort(key.clone()));
+                }
+            }
+            data_tree.apply_batch(&data)?;
+            version_tree.apply_batch(&new_versions)?;
+            for key in &written {
+                if ttl_tree.get(key)?.is_some() {
+                    ttl_tree.remove(key.as_slice())?;
+                }
+            }
+            Ok(())
+        }).map_err(|e| match e {
+            TransactionError::Abort(key) => KvsError::Conflict { key }.into(),
+            TransactionError::Storage(e) => Error::from(e),
+        })?;
+        self.flush_if_needed(len)
+    }
+
+    /// Writes are blocked while the view starts, so it holds every write finished before and none after
+    fn read_view(&self) -> Box<dyn ReadView> {
+        let _writes = self.exclusive_writes();
+        let mut views = self.lock_views();
+        views.epoch += 1;
+        let epoch = views.epoch;
+        *views.live.entry(epoch).or_insert(0) += 1;
+        Box::new(SledView { engine: self.clone(), epoch })
     }
-    fn remove(&self, key: String) -> Result<()> {
-        self.store.remove(key)?.ok_or(err_msg("Key not found"))?;
+
+    /// Every tree is exported into a new database, writes are blocked meanwhile
+    /// so the copy holds every write finished before the call and none started after.
+    fn checkpoint(&self, dest: &Path) -> Result<()> {
+        if dest.exists() && fs::read_dir(dest)?.next().is_some() {
+            return Err(err_msg(format!("Checkpoint destination {} is not empty", dest.display())));
+        }
+        let copy = sled::open(dest)?;
+        let _writes = self.exclusive_writes();
+        copy.import(self.store.export());
+        copy.flush()?;
+        Ok(())
+    }
+
+    fn sync(&self) -> Result<()> {
         self.store.flush()?;
         Ok(())
     }
+
+    fn scan_bytes<R: RangeBounds<Vec<u8>>>(&self, range: R) -> Scan {
+        let (start, end) = (range.start_bound().cloned(), range.end_bound().cloned());
+        let ttl = self.ttl.clone();
+        Scan::new(self.store.range::<Vec<u8>, _>((start, end)).filter_map(move |pair| {
+            let pair = pair.map_err(Error::from).and_then(|(key, value)| {
+                Ok((expired(&ttl, &key)?, key, value))
+            });
+            match pair {
+                Ok((true, _, _)) => None,
+                Ok((false, key, value)) => Some(Ok((key.to_vec(), value.to_vec()))),
+                Err(e) => Some(Err(e)),
+            }
+        }))
+    }
 }
 
 impl SledKvsEngine {
     /// Create a `SledKvsEngine` with given path
+    ///
+    /// Every write is flushed, same as `SyncPolicy::EveryWrite`.
     pub fn open(dir_path: impl Into<PathBuf>) -> Result<SledKvsEngine> {
-        let dir_path = dir_path.into();
+        SledKvsEngine::open_with(dir_path, SyncPolicy::EveryWrite)
+    }
+
+    /// Create a `SledKvsEngine` with given path and durability policy
+    ///
+    /// With `SyncPolicy::Never`, only the periodic flush of sled itself persists data.
+    pub fn open_with(dir_path: impl Into<PathBuf>, policy: SyncPolicy) -> Result<SledKvsEngine> {
+        let store = sled::open(dir_path.into())?;
+        let ttl = store.open_tree(TTL_TREE)?;
+        let versions = store.open_tree(VERSION_TREE)?;
+        let ticker = if let SyncPolicy::Interval(interval) = policy {
+            let sync_store = store.clone();
+            Some(Arc::new(SyncTicker::spawn(interval, move || {
+                sync_store.flush()?;
+                Ok(())
+            })?))
+        } else {
+            None
+        };
         Ok(SledKvsEngine{
-            store: sled::open(dir_path)?
+            store,
+            ttl,
+            versions,
+            writes: Arc::new(RwLock::new(())),
+            views: Arc::new(Mutex::new(Views::default())),
+            sync: Arc::new(SyncState::new(policy)),
+            _ticker: ticker,
         })
-    } 
-}
\ No newline at end of file
+    }
+
+    /// Version for a write, never given before
+    fn next_version(&self) -> Result<[u8; 8]> {
+        Ok((self.store.generate_id()? + UNTRACKED_VERSION + 1).to_le_bytes())
+    }
+
+    fn shared_writes(&self) -> RwLockReadGuard<'_, ()> {
+        self.writes.read().expect("Can't lock sled writes")
+    }
+
+    fn exclusive_writes(&self) -> RwLockWriteGuard<'_, ()> {
+        self.writes.write().expect("Can't lock sled writes")
+    }
+
+    /// Lock `writes` for a write of `key`, shared unless a `SledView` is live
+    ///
+    /// Views start under the exclusive lock, so none can start while the shared one is held.
+    /// Otherwise the write is serialized with the others and the state of `key` it replaces is saved.
+    fn writes_recording(&self, key: &[u8]) -> Result<WritesGuard<'_>> {
+        let shared = self.shared_writes();
+        if self.lock_views().live.is_empty() {
+            return Ok(WritesGuard::Shared { _guard: shared });
+        }
+        drop(shared);
+        let exclusive = self.exclusive_writes();
+        self.record_replaced(iter::once(key))?;
+        Ok(WritesGuard::Exclusive { _guard: exclusive })
+    }
+
+    /// Save the current state of `keys` for live views, called under the exclusive `writes` lock
+    /// before writing them
+    fn record_replaced<'a>(&self, keys: impl IntoIterator<Item = &'a [u8]>) -> Result<()> {
+        let mut views = self.lock_views();
+        if views.live.is_empty() {
+            return Ok(());
+        }
+        let epoch = views.epoch;
+        for key in keys {
+            let history = views.history.entry(key.to_vec()).or_default();
+            // a view reads the state the first write since it started replaced
+            if history.last().is_some_and(|state| state.epoch == epoch) {
+                continue;
+            }
+            let value = self.store.get(key)?.map(|value| value.to_vec());
+            let version = version_of(value.as_deref(), self.versions.get(key)?.as_deref());
+            let expires = self.ttl.get(key)?.map(|expires| decode_u64(&expires));
+            history.push(ReplacedState { epoch, value, version, expires });
+        }
+        Ok(())
+    }
+
+    fn lock_views(&self) -> MutexGuard<'_, Views> {
+        self.views.lock().expect("Can't lock sled views")
+    }
+
+    fn flush_if_needed(&self, bytes: usize) -> Result<()> {
+        if self.sync.wrote(bytes as u64) {
+            self.store.flush()?;
+        }
+        Ok(())
+    }
+}
+/// Guard of `SledKvsEngine::writes_recording`
+enum WritesGuard<'a> {
+    Shared { _guard: RwLockReadGuard<'a, ()> },
+    Exclusive { _guard: RwLockWriteGuard<'a, ()> },
+}
+
+/// Live `SledView`s and the states of keys written since they started
+#[derive(Default)]
+struct Views {
+    /// Bumped by every view started, states are saved under the current one
+    epoch: u64,
+    /// Number of live views by epoch they started at
+    live: BTreeMap<u64, usize>,
+    /// States replaced by writes while views are live, at most one per epoch, oldest first
+    history: HashMap<Vec<u8>, Vec<ReplacedState>>,
+}
+
+/// Stored value, version and expiry time of a key before a write replaced it
+struct ReplacedState {
+    epoch: u64,
+    value: Option<Vec<u8>>,
+    version: u64,
+    expires: Option<u64>,
+}
+
+/// Read view of a transaction on a `SledKvsEngine`, fixed when it started
+///
+/// sled has no snapshots, so while a view is live every write first saves the state it replaces,
+/// see `SledKvsEngine::record_replaced`. A key written since the view started reads as
+/// the first state saved at its epoch or later, any other key reads from the trees.
+/// Keys expiring meanwhile read as missing, same as with a `Snapshot`.
+struct SledView {
+    engine: SledKvsEngine,
+    epoch: u64,
+}
+
+impl ReadView for SledView {
+    fn get_versioned(&self, key: Vec<u8>) -> Result<(Option<Vec<u8>>, u64)> {
+        // no write runs between the history lookup and the tree reads
+        let _writes = self.engine.shared_writes();
+        let views = self.engine.lock_views();
+        let saved = views.history.get(&key).and_then(|history| history.iter().find(|state| state.epoch >= self.epoch));
+        match saved {
+            Some(state) if state.expires.is_none_or(|expires| expires > now_millis()) => Ok((state.value.clone(), state.version)),
+            Some(_) => Ok((None, 0)),
+            None => {
+                drop(views);
+                self.engine.get_versioned_bytes(key)
+            },
+        }
+    }
+}
+
+impl Drop for SledView {
+    /// Drop the states no live view can read anymore
+    fn drop(&mut self) {
+        let mut views = self.engine.lock_views();
+        let live = views.live.entry(self.epoch).or_insert(1);
+        *live -= 1;
+        if *live == 0 {
+            views.live.remove(&self.epoch);
+        }
+        match views.live.keys().next().copied() {
+            Some(oldest) => views.history.retain(|_, history| {
+                history.retain(|state| state.epoch >= oldest);
+                !history.is_empty()
+            }),
+            None => views.history.clear(),
+        }
+    }
+}
+
+/// Whether `key` has an expiry time in the past
+fn expired(ttl: &sled::Tree, key: &[u8]) -> Result<bool> {
+    Ok(ttl.get(key)?.is_some_and(|expires| decode_u64(&expires) <= now_millis()))
+}
+
+/// Value of the `ttl` and `version` trees, 0 if malformed
+fn decode_u64(bytes: &[u8]) -> u64 {
+    bytes.try_into().map_or(0, u64::from_le_bytes)
+}
+
+/// Version of a key checked by transactions, from its `version` tree entry,
+/// 0 for a missing key whatever entry a crash between the two writes left
+fn version_of(value: Option<&[u8]>, version: Option<&[u8]>) -> u64 {
+    match (value, version) {
+        (None, _) => 0,
+        (Some(_), None) => UNTRACKED_VERSION,
+        (Some(_), Some(version)) => decode_u64(version),
+    }
+}
+
+fn transaction_error(e: TransactionError<()>) -> Error {
+    match e {
+        TransactionError::Abort(()) => err_msg("Transaction aborted"),
+        TransactionError::Storage(e) => e.into(),
+    }
+}
@@ -0,0 +1,101 @@
+//! Durability policy shared by the engines
+use std::{sync::atomic::{AtomicU64, Ordering}, thread::{self, JoinHandle}, time::Duration};
+use crossbeam_channel::{RecvTimeoutError, Sender, bounded};
+use serde::{Serialize, Deserialize};
+use crate::error::*;
+
+/// When an engine forces written data to disk
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SyncPolicy {
+    /// Never sync explicitly, data survives a process crash but may be lost on power loss
+    Never,
+    /// Sync before every write returns
+    EveryWrite,
+    /// Sync from a background thread at the given interval
+    Interval(Duration),
+    /// Sync once the given number of bytes was written since the last sync
+    EveryNBytes(u64),
+}
+
+/// Decides which writes have to be synced
+pub(super) struct SyncState {
+    policy: SyncPolicy,
+    unsynced: AtomicU64,
+}
+
+impl SyncState {
+    pub(super) fn new(policy: SyncPolicy) -> Self {
+        SyncState {
+            policy,
+            unsynced: AtomicU64::new(0),
+        }
+    }
+
+    pub(super) fn policy(&self) -> SyncPolicy {
+        self.policy
+    }
+
+    /// Record a write of `bytes`, return whether it should be synced before returning
+    pub(super) fn wrote(&self, bytes: u64) -> bool {
+        match self.policy {
+            SyncPolicy::Never | SyncPolicy::Interval(_) => false,
+            SyncPolicy::EveryWrite => true,
+            SyncPolicy::EveryNBytes(limit) => {
+                // adding and resetting in one step, so a concurrent write is never lost
+                // and only one of the writes crossing the limit syncs
+                let reset = |unsynced: u64| {
+                    let unsynced = unsynced.saturating_add(bytes);
+                    if unsynced >= limit { 0 } else { unsynced }
+                };
+                let previous = self.unsynced
+                    .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |unsynced| Some(reset(unsynced)))
+                    .unwrap_or_else(|unsynced| unsynced);
+                previous.saturating_add(bytes) >= limit
+            }
+        }
+    }
+}
+
+/// Background thread of `SyncPolicy::Interval`
+///
+/// It syncs one last time when dropped, then it is joined.
+pub(super) struct SyncTicker {
+    stop: Option<Sender<()>>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl SyncTicker {
+    pub(super) fn spawn<F>(interval: Duration, sync: F) -> Result<Self>
+    where
+        F: Fn() -> Result<()> + Send + 'static,
+    {
+        let (tx, rx) = bounded::<()>(0);
+        let handle = thread::Builder::new()
+            .name("kvs-sync".to_owned())
+            .spawn(move || loop {
+                // a failed sync is retried at next tick
+                match rx.recv_timeout(interval) {
+                    Err(RecvTimeoutError::Timeout) => { let _ = sync(); },
+                    _ => {
+                        let _ = sync();
+                        break;
+                    }
+                }
+            })?;
+        Ok(SyncTicker {
+            stop: Some(tx),
+            handle: Some(handle),
+        })
+    }
+}
+
+impl Drop for SyncTicker {
+    fn drop(&mut self) {
+        self.stop.take();
+        if let Some(handle) = self.handle.take() {
+            if handle.join().is_err() && !thread::panicking() {
+                panic!("Sync thread panicked");
+            }
+        }
+    }
+}
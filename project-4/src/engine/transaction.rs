@@ -0,0 +1,120 @@
+use std::collections::BTreeMap;
+use crate::error::*;
+use crate::engine::{KvsEngine, WriteBatch};
+
+/// Optimistic transaction over several keys with snapshot isolation, started by `KvsEngine::begin`
+///
+/// ```no_run
+/// # use kvs::{KvStore, KvsEngine};
+/// let store = KvStore::open("db")?;
+/// let mut txn = store.begin();
+/// let from: u32 = txn.get("from".to_owned())?.unwrap_or_default().parse()?;
+/// txn.set("from".to_owned(), (from - 1).to_string())?;
+/// txn.set("to".to_owned(), "1".to_owned())?;
+/// txn.commit()?;
+/// # Ok::<(), failure::Error>(())
+/// ```
+///
+/// Reads come from a `ReadView` of the store taken by `begin`, so they see no write committed after it,
+/// and writes are buffered until `commit` and seen by reads of the transaction.
+/// The version of every key read or written is recorded as seen in the view,
+/// `commit` fails with `KvsError::Conflict` if any of them changed since `begin`.
+/// Dropping it without commit discards its writes.
+pub struct Transaction<E: KvsEngine> {
+    engine: E,
+    view: Box<dyn ReadView>,
+    versions: BTreeMap<Vec<u8>, u64>,
+    /// Last write of each key, `None` removes it
+    writes: BTreeMap<Vec<u8>, Option<Vec<u8>>>,
+}
+
+/// Reads of a store as it was when the view was taken, see `KvsEngine::read_view`
+pub trait ReadView: Send {
+    /// Get the value by key with its version, as `KvsEngine::get_versioned_bytes` did then
+    fn get_versioned(&self, key: Vec<u8>) -> Result<(Option<Vec<u8>>, u64)>;
+}
+
+impl<E: KvsEngine> Transaction<E> {
+    pub(super) fn new(engine: E) -> Self {
+        Transaction {
+            view: engine.read_view(),
+            engine,
+            versions: BTreeMap::new(),
+            writes: BTreeMap::new(),
+        }
+    }
+
+    /// Get the value by key, as written by this transaction if it did
+    pub fn get_bytes(&mut self, key: Vec<u8>) -> Result<Option<Vec<u8>>> {
+        if let Some(value) = self.writes.get(&key) {
+            return Ok(value.clone());
+        }
+        let (value, version) = self.view.get_versioned(key.clone())?;
+        self.versions.insert(key, version);
+        Ok(value)
+    }
+
+    /// Set the pair of key and value on commit
+    pub fn set_bytes(&mut self, key: Vec<u8>, value: Vec<u8>) -> Result<()> {
+        self.touch(&key)?;
+        self.writes.insert(key, Some(value));
+        Ok(())
+    }
+
+    /// Remove the key on commit
+    ///
+    /// # Errors
+    ///
+    /// Error with message `Key not found` will be returned if key does not exist
+    pub fn remove_bytes(&mut self, key: Vec<u8>) -> Result<()> {
+        if self.get_bytes(key.clone())?.is_none() {
+            return Err(err_msg("Key not found"));
+        }
+        self.writes.insert(key, None);
+        Ok(())
+    }
+
+    /// Get the value by key, an error is returned if the value is not UTF-8
+    pub fn get(&mut self, key: String) -> Result<Option<String>> {
+        match self.get_bytes(key.into_bytes())? {
+            Some(value) => Ok(Some(String::from_utf8(value)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Set the pair of key and value on commit
+    pub fn set(&mut self, key: String, value: String) -> Result<()> {
+        self.set_bytes(key.into_bytes(), value.into_bytes())
+    }
+
+    /// Remove the key on commit, see `remove_bytes`
+    pub fn remove(&mut self, key: String) -> Result<()> {
+        self.remove_bytes(key.into_bytes())
+    }
+
+    /// Apply the writes atomically if no key touched has changed
+    ///
+    /// # Errors
+    ///
+    /// `KvsError::Conflict` is returned with a key written by another writer since `begin`,
+    /// nothing is applied then and the transaction may be retried from the start.
+    pub fn commit(self) -> Result<()> {
+        let mut batch = WriteBatch::new();
+        for (key, value) in self.writes {
+            match value {
+                Some(value) => batch.set(key, value),
+                None => batch.remove(key),
+            }
+        }
+        self.engine.commit_versioned(self.versions.into_iter().collect(), batch)
+    }
+
+    /// Record the version of a key written without being read
+    fn touch(&mut self, key: &[u8]) -> Result<()> {
+        if !self.versions.contains_key(key) {
+            let (_, version) = self.view.get_versioned(key.to_vec())?;
+            self.versions.insert(key.to_vec(), version);
+        }
+        Ok(())
+    }
+}
@@ -1,3 +1,94 @@
+use std::{fmt, path::PathBuf};
+
 pub use failure::{Error, err_msg};
 /// Result type for kvs
-pub type Result<T> = std::result::Result<T, Error>;
\ No newline at end of file
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// Typed errors raised by kvs engines
+///
+/// They are carried inside `failure::Error`, use `Error::downcast_ref` to match on them.
+#[derive(Debug)]
+pub enum KvsError {
+    /// A record in a log file failed validation
+    Corruption {
+        /// The log file holding the broken record
+        file: PathBuf,
+        /// Byte offset of the broken record inside `file`
+        offset: u64,
+        /// What is wrong with the record
+        kind: CorruptionKind,
+    },
+    /// A write was sent to a store opened read-only, or to a `Snapshot`
+    ReadOnly,
+    /// A conditional write found another value than expected
+    ConditionFailed {
+        /// The value found, `None` if the key does not exist
+        current: Option<Vec<u8>>,
+    },
+    /// A transaction touched a key written by someone else before its commit
+    Conflict {
+        /// The key that changed
+        key: Vec<u8>,
+    },
+    /// Another store holds the lock of the directory, see `KvStore::open_with`
+    InUse {
+        /// The store directory
+        path: PathBuf,
+    },
+    /// The store was written by another format version, which this build does not open
+    UnsupportedFormat {
+        /// Format version found in the store directory
+        version: u32,
+    },
+    /// A scan of a store with `IndexMode::Hashed`, whose index keeps no key order
+    ScanUnsupported,
+}
+
+/// Reasons for a record to be rejected
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CorruptionKind {
+    /// The file ends in the middle of the record
+    Truncated,
+    /// The stored CRC32 does not match the record content
+    ChecksumMismatch,
+    /// The record was written by an unknown format version
+    UnsupportedVersion(u8),
+    /// The operation type is not known
+    UnknownOp(u8),
+    /// The value is compressed by an unknown codec
+    UnknownCodec(u8),
+    /// The checksum matches but the content can not be decoded
+    InvalidData,
+}
+
+impl fmt::Display for KvsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            KvsError::Corruption { file, offset, kind } =>
+                write!(f, "Corrupted record in {} at offset {}: {}", file.display(), offset, kind),
+            KvsError::ReadOnly => write!(f, "Store is opened read-only"),
+            KvsError::ConditionFailed { .. } => write!(f, "Condition failed"),
+            KvsError::Conflict { .. } => write!(f, "Transaction conflict"),
+            KvsError::InUse { path } =>
+                write!(f, "Database in use: {} is opened by another store", path.display()),
+            KvsError::UnsupportedFormat { version } =>
+                write!(f, "Store format version {} is not supported", version),
+            KvsError::ScanUnsupported => write!(f, "Scans are not supported by a hashed index"),
+        }
+    }
+}
+
+impl std::error::Error for KvsError {}
+
+impl fmt::Display for CorruptionKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CorruptionKind::Truncated => write!(f, "record is truncated"),
+            CorruptionKind::ChecksumMismatch => write!(f, "checksum mismatch"),
+            CorruptionKind::UnsupportedVersion(v) => write!(f, "unsupported record version {}", v),
+            CorruptionKind::UnknownOp(op) => write!(f, "unknown op type {}", op),
+            CorruptionKind::UnknownCodec(codec) => write!(f, "unknown compression codec {}", codec),
+            CorruptionKind::InvalidData => write!(f, "invalid record data"),
+        }
+    }
+}
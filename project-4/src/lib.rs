@@ -4,9 +4,10 @@
 mod error;
 mod engine;
 mod protocol;
-/// 
+pub mod dump;
+/// Thread pools used by the server
 pub mod thread_pool;
 
-pub use engine::{KvsEngine, KvStore, SledKvsEngine};
-pub use error::Result;
+pub use engine::{BatchOp, CompactionRecovery, Compression, IndexMode, KvsEngine, KvStore, KvStoreOptions, KvStoreStats, ReadView, RecoveryReport, Scan, SledKvsEngine, Snapshot, SyncPolicy, Transaction, TruncatedTail, WriteBatch};
+pub use error::{Result, KvsError, CorruptionKind};
 pub use protocol::{Protocol, Request, Response};
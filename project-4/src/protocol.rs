@@ -1,8 +1,9 @@
-use std::io::{Read, Write};
+use std::{io::{Read, Write}, path::PathBuf};
 use serde::{Serialize, Deserialize};
+use crate::engine::WriteBatch;
 use crate::error::Result;
 
-const VERSION: &str = "0.2";
+const VERSION: &str = "0.3";
 
 /// Protocol used by server and client
 ///
@@ -38,9 +39,9 @@ impl<'a, T: ProtocolPayload + Serialize + Deserialize<'a>> Protocol<T> {
     /// You could think that as "Ok? really?", so you return "Ok, but one more" to keep listening
     pub fn listen<R: Read, F>(reader: &mut R, mut handler: F) -> Result<()> 
       where F: FnMut(Protocol<T>) -> Result<bool> {
-        let mut stream = serde_json::Deserializer::from_reader(reader).into_iter();
+        let stream = serde_json::Deserializer::from_reader(reader).into_iter();
             
-        while let Some(cmd) = stream.next() {
+        for cmd in stream {
             if handler(cmd?)? {
                 break;
             }
@@ -50,8 +51,10 @@ impl<'a, T: ProtocolPayload + Serialize + Deserialize<'a>> Protocol<T> {
     }
 
     /// Send message to writer with given payload
+    ///
+    /// The message is written at once, so a socket does not hold back its last part.
     pub fn send<W: Write>(writer: &mut W, data: Self) -> Result<()> {
-        serde_json::ser::to_writer(writer, &data)?;
+        writer.write_all(&serde_json::ser::to_vec(&data)?)?;
         Ok(())
     }
 }
@@ -65,20 +68,81 @@ pub enum Request {
     Shutdown,
     /// set key to value
     Set {
-        /// 
-        key: String,
-        /// 
-        value: String
+        /// key to set
+        key: Vec<u8>,
+        /// value to set
+        value: Vec<u8>
+    },
+    /// set key to value, expiring after a time to live
+    SetEx {
+        /// key to set
+        key: Vec<u8>,
+        /// value to set
+        value: Vec<u8>,
+        /// time to live in milliseconds
+        ttl_ms: u64
     },
     /// get value by key
     Get {
-        /// 
-        key: String
+        /// key to get
+        key: Vec<u8>
     },
     /// rm value by key
     Rm {
-        /// 
-        key: String
+        /// key to remove
+        key: Vec<u8>
+    },
+    /// replace value of key if it is the expected one, `None` stands for a missing key
+    ///
+    /// Response is `ConditionFailed` when the value is not the expected one
+    CompareAndSwap {
+        /// key to swap
+        key: Vec<u8>,
+        /// value expected before
+        expected: Option<Vec<u8>>,
+        /// value to set, `None` removes key
+        new: Option<Vec<u8>>
+    },
+    /// set key to value if key does not exist
+    SetIfAbsent {
+        /// key to set
+        key: Vec<u8>,
+        /// value to set
+        value: Vec<u8>
+    },
+    /// rm key if its value is the expected one
+    RemoveIfEquals {
+        /// key to remove
+        key: Vec<u8>,
+        /// value expected before
+        expected: Vec<u8>
+    },
+    /// apply every write of batch atomically
+    Batch {
+        /// writes to apply
+        batch: WriteBatch
+    },
+    /// start a transaction for the rest of the session
+    ///
+    /// Until `Commit` or `Rollback`, `Get`, `Set` and `Rm` go through the transaction,
+    /// reading the store as it was at `Begin`, other writes are refused.
+    /// Closing the connection rolls it back.
+    Begin,
+    /// commit the transaction of the session
+    ///
+    /// Response is `Conflict` when a key touched by the transaction changed meanwhile
+    Commit,
+    /// drop the transaction of the session and its writes
+    Rollback,
+    /// copy the store into a new directory on the server host, an admin request
+    ///
+    /// Writes of other clients continue meanwhile with the `kvs` engine, `sled` blocks them.
+    /// The copy is made in the backup directory of the server configuration,
+    /// `dest` has to be a relative path without `..` and is refused otherwise.
+    /// Refused unless the server was started with `--allow-checkpoints`.
+    Checkpoint {
+        /// directory to create in the backup directory, or an empty one
+        dest: PathBuf
     }
 }
 impl ProtocolPayload for Request {}
@@ -90,14 +154,24 @@ pub enum Response {
     Pong(i8),
     /// Both shutdown connection
     Shutdown,
-    /// Request command success, response with String when needed
+    /// Request command success, response with value when needed
     Success {
-        /// 
-        value: Option<String>
+        /// value of a `Get` request
+        value: Option<Vec<u8>>
+    },
+    /// Conditional request command not applied, response with current value
+    ConditionFailed {
+        /// value found, `None` if key does not exist
+        current: Option<Vec<u8>>
+    },
+    /// Transaction not committed, a key it touched was changed by another client
+    Conflict {
+        /// key that changed
+        key: Vec<u8>
     },
     /// Request command failed, response with error message
     Error {
-        /// 
+        /// error message
         msg: String
     }
 }
@@ -54,10 +54,8 @@ impl SharedQueueThreadPool {
             let shared = Sentinel::new(shared);
 
             loop {
-                let job = match {
-                    let receiver = &shared.data.job;
-                    receiver.recv()
-                } {
+                let job = shared.data.job.recv();
+                let job = match job {
                     Ok(job) => job,
                     Err(_) => break
                 };
@@ -1,6 +1,12 @@
+// Written before `args` took arrays by value
+#![allow(clippy::needless_borrows_for_generic_args)]
+
 use assert_cmd::prelude::*;
+use kvs::{KvStore, KvsEngine, Protocol, Request, Response, SledKvsEngine, WriteBatch};
 use predicates::str::{contains, is_empty};
 use std::fs::{self, File};
+use std::net::TcpStream;
+use std::path::Path;
 use std::process::Command;
 use std::sync::mpsc;
 use std::thread;
@@ -151,6 +157,25 @@ fn server_cli_version() {
         .stdout(contains(env!("CARGO_PKG_VERSION")));
 }
 
+// `kvs-server` should refuse a malformed `kvs.conf` and leave it as it is.
+#[test]
+fn server_cli_invalid_conf() {
+    let temp_dir = TempDir::new().unwrap();
+    let conf_path = temp_dir.path().join("kvs.conf");
+    fs::write(&conf_path, "(engine: \"kvs\", kvs: (sync_policy: Sometimes))").unwrap();
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(&["--engine", "kvs", "--addr", "127.0.0.1:4026"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("kvs.conf"));
+    assert_eq!(
+        fs::read_to_string(&conf_path).unwrap(),
+        "(engine: \"kvs\", kvs: (sync_policy: Sometimes))"
+    );
+}
+
 #[test]
 fn cli_log_configuration() {
     let temp_dir = TempDir::new().unwrap();
@@ -164,6 +189,7 @@ fn cli_log_configuration() {
         .unwrap();
     thread::sleep(Duration::from_secs(1));
     child.kill().expect("server exited before killed");
+    child.wait().expect("unable to wait for server");
 
     let content = fs::read_to_string(&stderr_path).expect("unable to read from stderr file");
     assert!(content.contains(env!("CARGO_PKG_VERSION")));
@@ -184,6 +210,7 @@ fn cli_wrong_engine() {
             .unwrap();
         thread::sleep(Duration::from_secs(1));
         child.kill().expect("server exited before killed");
+        child.wait().expect("unable to wait for server");
 
         let mut cmd = Command::cargo_bin("kvs-server").unwrap();
         cmd.args(&["--engine", "kvs", "--addr", "127.0.0.1:4003"])
@@ -203,6 +230,7 @@ fn cli_wrong_engine() {
             .unwrap();
         thread::sleep(Duration::from_secs(1));
         child.kill().expect("server exited before killed");
+        child.wait().expect("unable to wait for server");
 
         let mut cmd = Command::cargo_bin("kvs-server").unwrap();
         cmd.args(&["--engine", "sled", "--addr", "127.0.0.1:4003"])
@@ -224,6 +252,7 @@ fn cli_access_server(engine: &str, addr: &str) {
     let handle = thread::spawn(move || {
         let _ = receiver.recv(); // wait for main thread to finish
         child.kill().expect("server exited before killed");
+        child.wait().expect("unable to wait for server");
     });
     thread::sleep(Duration::from_secs(1));
 
@@ -305,6 +334,7 @@ fn cli_access_server(engine: &str, addr: &str) {
     let handle = thread::spawn(move || {
         let _ = receiver.recv(); // wait for main thread to finish
         child.kill().expect("server exited before killed");
+        child.wait().expect("unable to wait for server");
     });
     thread::sleep(Duration::from_secs(1));
 
@@ -335,3 +365,686 @@ fn cli_access_server_kvs_engine() {
 fn cli_access_server_sled_engine() {
     cli_access_server("sled", "127.0.0.1:4005");
 }
+
+// Binary keys and values should round-trip through hex and base64 options
+fn cli_binary_values(engine: &str, addr: &str) {
+    let (sender, receiver) = mpsc::sync_channel(0);
+    let temp_dir = TempDir::new().unwrap();
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(["--engine", engine, "--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    let handle = thread::spawn(move || {
+        let _ = receiver.recv(); // wait for main thread to finish
+        child.kill().expect("server exited before killed");
+        child.wait().expect("unable to wait for server");
+    });
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["set", "00ff", "c328ff00", "--input", "hex", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(is_empty());
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "AP8=", "--input", "base64", "--output", "hex", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("c328ff00\n");
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "00ff", "--input", "hex", "--output", "base64", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("wyj/AA==\n");
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "00ff", "--input", "hex", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("UTF-8"));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["set", "key1", "zz", "--input", "hex", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure();
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["rm", "00ff", "--input", "hex", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(is_empty());
+
+    sender.send(()).unwrap();
+    handle.join().unwrap();
+}
+
+#[test]
+fn cli_binary_values_kvs_engine() {
+    cli_binary_values("kvs", "127.0.0.1:4006");
+}
+
+#[test]
+fn cli_binary_values_sled_engine() {
+    cli_binary_values("sled", "127.0.0.1:4007");
+}
+
+// A value set with `--ttl` should expire
+fn cli_ttl(engine: &str, addr: &str) {
+    let (sender, receiver) = mpsc::sync_channel(0);
+    let temp_dir = TempDir::new().unwrap();
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(["--engine", engine, "--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    let handle = thread::spawn(move || {
+        let _ = receiver.recv(); // wait for main thread to finish
+        child.kill().expect("server exited before killed");
+        child.wait().expect("unable to wait for server");
+    });
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["set", "key1", "value1", "--ttl", "0.5", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(is_empty());
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "key1", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("value1\n");
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["set", "key1", "value1", "--ttl", "-1", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure();
+
+    // below a millisecond, the key would be stored already expired
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["set", "key2", "value2", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+    for ttl in &["0.0001", "inf", "NaN"] {
+        Command::cargo_bin("kvs-client")
+            .unwrap()
+            .args(["set", "key2", "value3", "--ttl", ttl, "--addr", addr])
+            .current_dir(&temp_dir)
+            .assert()
+            .failure()
+            .stderr(contains("at least 0.001"));
+    }
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "key2", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("value2\n");
+
+    thread::sleep(Duration::from_millis(600));
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "key1", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("Key not found\n");
+
+    sender.send(()).unwrap();
+    handle.join().unwrap();
+}
+
+#[test]
+fn cli_ttl_kvs_engine() {
+    cli_ttl("kvs", "127.0.0.1:4008");
+}
+
+#[test]
+fn cli_ttl_sled_engine() {
+    cli_ttl("sled", "127.0.0.1:4009");
+}
+
+// `cas` should only replace the expected value
+fn cli_compare_and_swap(engine: &str, addr: &str) {
+    let (sender, receiver) = mpsc::sync_channel(0);
+    let temp_dir = TempDir::new().unwrap();
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(["--engine", engine, "--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    let handle = thread::spawn(move || {
+        let _ = receiver.recv(); // wait for main thread to finish
+        child.kill().expect("server exited before killed");
+        child.wait().expect("unable to wait for server");
+    });
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["cas", "key1", "--new", "value1", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(is_empty());
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["cas", "key1", "--new", "value2", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("Condition failed"));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["cas", "key1", "--expected", "value1", "--new", "value2", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "key1", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("value2\n");
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["cas", "key1", "--expected", "value2", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "key1", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("Key not found\n");
+
+    sender.send(()).unwrap();
+    handle.join().unwrap();
+}
+
+#[test]
+fn cli_compare_and_swap_kvs_engine() {
+    cli_compare_and_swap("kvs", "127.0.0.1:4010");
+}
+
+#[test]
+fn cli_compare_and_swap_sled_engine() {
+    cli_compare_and_swap("sled", "127.0.0.1:4011");
+}
+
+// The server should apply a `Batch` request as a whole
+fn server_batch(engine: &str, addr: &str) {
+    let (sender, receiver) = mpsc::sync_channel(0);
+    let temp_dir = TempDir::new().unwrap();
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(["--engine", engine, "--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    let handle = thread::spawn(move || {
+        let _ = receiver.recv(); // wait for main thread to finish
+        child.kill().expect("server exited before killed");
+        child.wait().expect("unable to wait for server");
+    });
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["set", "from", "value1", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+
+    let mut batch = WriteBatch::new();
+    batch.set(b"to".to_vec(), b"value1".to_vec());
+    batch.remove(b"from".to_vec());
+    let mut stream = TcpStream::connect(addr).unwrap();
+    Protocol::send(&mut stream, Protocol::new(Request::Batch { batch })).unwrap();
+    Protocol::listen(&mut stream.try_clone().unwrap(), |res: Protocol<Response>| {
+        assert!(matches!(res.payload, Response::Success { value: None }));
+        Ok(true)
+    }).unwrap();
+    Protocol::send(&mut stream, Protocol::new(Request::Shutdown)).unwrap();
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "from", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("Key not found\n");
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "to", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("value1\n");
+
+    sender.send(()).unwrap();
+    handle.join().unwrap();
+}
+
+#[test]
+fn server_batch_kvs_engine() {
+    server_batch("kvs", "127.0.0.1:4012");
+}
+
+#[test]
+fn server_batch_sled_engine() {
+    server_batch("sled", "127.0.0.1:4013");
+}
+
+/// Send a request on a session and wait for its response
+fn request(stream: &mut TcpStream, req: Request) -> Response {
+    Protocol::send(stream, Protocol::new(req)).unwrap();
+    let mut res = None;
+    Protocol::listen(&mut stream.try_clone().unwrap(), |data: Protocol<Response>| {
+        res = Some(data.payload);
+        Ok(true)
+    }).unwrap();
+    res.expect("no response")
+}
+
+// Concurrent transactions of several sessions should conflict instead of losing updates
+fn server_transactions(engine: &str, addr: &str) {
+    let (sender, receiver) = mpsc::sync_channel(0);
+    let temp_dir = TempDir::new().unwrap();
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(["--engine", engine, "--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    let handle = thread::spawn(move || {
+        let _ = receiver.recv(); // wait for main thread to finish
+        child.kill().expect("server exited before killed");
+        child.wait().expect("unable to wait for server");
+    });
+    thread::sleep(Duration::from_secs(1));
+
+    // two sessions writing the same key, the second commit conflicts
+    let mut first = TcpStream::connect(addr).unwrap();
+    let mut second = TcpStream::connect(addr).unwrap();
+    for stream in [&mut first, &mut second] {
+        assert!(matches!(request(stream, Request::Begin), Response::Success { .. }));
+        assert!(matches!(request(stream, Request::Get { key: b"key".to_vec() }), Response::Success { value: None }));
+        assert!(matches!(request(stream, Request::Set { key: b"key".to_vec(), value: b"value".to_vec() }),
+            Response::Success { .. }));
+    }
+    assert!(matches!(request(&mut first, Request::Commit), Response::Success { .. }));
+    assert!(matches!(request(&mut second, Request::Commit), Response::Conflict { key } if key == b"key"));
+    assert!(matches!(request(&mut second, Request::Commit), Response::Error { .. }));
+    for stream in [&mut first, &mut second] {
+        Protocol::send(stream, Protocol::new(Request::Shutdown)).unwrap();
+    }
+
+    // sessions incrementing two counters together, retrying on conflicts
+    let threads: Vec<_> = (0..4).map(|_| {
+        let addr = addr.to_owned();
+        thread::spawn(move || {
+            let mut stream = TcpStream::connect(&addr).unwrap();
+            for _ in 0..20 {
+                loop {
+                    request(&mut stream, Request::Begin);
+                    for key in [b"a", b"b"] {
+                        let value = match request(&mut stream, Request::Get { key: key.to_vec() }) {
+                            Response::Success { value } => value.map_or(0, |v| String::from_utf8(v).unwrap().parse().unwrap()),
+                            Response::Conflict { .. } => break,
+                            res => panic!("unexpected response {:?}", res),
+                        };
+                        let value = (value + 1).to_string().into_bytes();
+                        request(&mut stream, Request::Set { key: key.to_vec(), value });
+                    }
+                    match request(&mut stream, Request::Commit) {
+                        Response::Success { .. } => break,
+                        Response::Conflict { .. } => continue,
+                        res => panic!("unexpected response {:?}", res),
+                    }
+                }
+            }
+            Protocol::send(&mut stream, Protocol::new(Request::Shutdown)).unwrap();
+        })
+    }).collect();
+    for thread in threads {
+        thread.join().unwrap();
+    }
+
+    for key in ["a", "b"] {
+        Command::cargo_bin("kvs-client")
+            .unwrap()
+            .args(["get", key, "--addr", addr])
+            .current_dir(&temp_dir)
+            .assert()
+            .success()
+            .stdout("80\n");
+    }
+
+    sender.send(()).unwrap();
+    handle.join().unwrap();
+}
+
+#[test]
+fn server_transactions_kvs_engine() {
+    server_transactions("kvs", "127.0.0.1:4014");
+}
+
+#[test]
+fn server_transactions_sled_engine() {
+    server_transactions("sled", "127.0.0.1:4015");
+}
+
+/// Start `kvs-server` in `dir`, it is killed once a message is sent to the returned channel
+fn spawn_server(dir: &Path, args: &[&str]) -> (mpsc::SyncSender<()>, thread::JoinHandle<()>) {
+    let (sender, receiver) = mpsc::sync_channel(0);
+    let mut child = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(args)
+        .current_dir(dir)
+        .spawn()
+        .unwrap();
+    let handle = thread::spawn(move || {
+        let _ = receiver.recv(); // wait for main thread to finish
+        child.kill().expect("server exited before killed");
+        child.wait().expect("unable to wait for server");
+    });
+    thread::sleep(Duration::from_secs(1));
+    (sender, handle)
+}
+
+// `kvs-client backup` should copy the store of a running server which allows it,
+// a server can start from the copy
+fn cli_backup(engine: &str, addr: &str, backup_addr: &str) {
+    let temp_dir = TempDir::new().unwrap();
+    let dest = temp_dir.path().join("backups").join("backup");
+    let (sender, handle) = spawn_server(temp_dir.path(), &["--engine", engine, "--addr", addr, "--allow-checkpoints"]);
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["set", "key1", "value1", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["backup", "backup", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(is_empty());
+
+    // only inside the backup directory
+    let outside = temp_dir.path().join("outside");
+    for dest in [outside.to_str().unwrap(), "../outside", "backup/../../outside", "."] {
+        Command::cargo_bin("kvs-client")
+            .unwrap()
+            .args(["backup", dest, "--addr", addr])
+            .current_dir(&temp_dir)
+            .assert()
+            .failure()
+            .stderr(contains("inside the backup directory"));
+    }
+    assert!(!outside.exists());
+
+    // written after the backup
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["set", "key2", "value2", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["backup", "backup", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("not empty"));
+    sender.send(()).unwrap();
+    handle.join().unwrap();
+
+    let (sender, handle) = spawn_server(&dest, &["--engine", engine, "--addr", backup_addr]);
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "key1", "--addr", backup_addr])
+        .current_dir(&dest)
+        .assert()
+        .success()
+        .stdout("value1\n");
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "key2", "--addr", backup_addr])
+        .current_dir(&dest)
+        .assert()
+        .success()
+        .stdout("Key not found\n");
+
+    // started without `--allow-checkpoints`
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["backup", "backup", "--addr", backup_addr])
+        .current_dir(&dest)
+        .assert()
+        .failure()
+        .stderr(contains("Checkpoints are disabled"));
+    assert!(!dest.join("backups").exists());
+    sender.send(()).unwrap();
+    handle.join().unwrap();
+}
+
+#[test]
+fn cli_backup_kvs_engine() {
+    cli_backup("kvs", "127.0.0.1:4016", "127.0.0.1:4017");
+}
+
+#[test]
+fn cli_backup_sled_engine() {
+    cli_backup("sled", "127.0.0.1:4018", "127.0.0.1:4019");
+}
+
+// A dump of either engine should load into the other one, in every format,
+// keys set with a ttl keeping the time they have left
+#[test]
+fn dump_and_load() {
+    let temp_dir = TempDir::new().unwrap();
+    let source = temp_dir.path().join("source");
+    let ttl = Duration::from_secs(3600);
+    // in key order
+    let pairs = vec![
+        (vec![0, 159, 146, 150], vec![255, 0]),
+        (b"key1".to_vec(), b"value1".to_vec()),
+        (b"key2".to_vec(), b"multi\nline \"value\"".to_vec()),
+        (b"key3".to_vec(), b"expiring".to_vec()),
+    ];
+    {
+        let store = KvStore::open(&source).unwrap();
+        for (key, value) in &pairs[..3] {
+            store.set_bytes(key.clone(), value.clone()).unwrap();
+        }
+        store.set_with_ttl("key3".to_owned(), "expiring".to_owned(), ttl).unwrap();
+        store.set("removed".to_owned(), "value".to_owned()).unwrap();
+        store.remove("removed".to_owned()).unwrap();
+    }
+    fn check<E: KvsEngine>(store: &E, ttl: Duration) {
+        assert_eq!(store.ttl("key1".to_owned()).unwrap(), None);
+        let left = store.ttl("key3".to_owned()).unwrap().expect("ttl lost by the dump");
+        assert!(left <= ttl && left > ttl - Duration::from_secs(60), "{:?}", left);
+    }
+
+    Command::cargo_bin("kvs-dump")
+        .unwrap()
+        .arg(&source)
+        .assert()
+        .success()
+        .stdout(contains("{\"key\":\"key1\",\"value\":\"value1\"}\n"))
+        .stdout(contains("\"encoding\":\"base64\""))
+        .stdout(contains("{\"key\":\"key3\",\"value\":\"expiring\",\"ttl_ms\":"))
+        .stderr(contains("4 pairs dumped"));
+
+    // a dump writes nothing to the store it reads, so a missing sled store is not created
+    let missing = temp_dir.path().join("missing");
+    Command::cargo_bin("kvs-dump")
+        .unwrap()
+        .args(["--engine", "sled"])
+        .arg(&missing)
+        .assert()
+        .failure()
+        .stderr(contains("No sled store"));
+    assert!(!missing.exists());
+
+    for format in ["jsonl", "ron", "bson"] {
+        let file = temp_dir.path().join(format!("dump.{}", format));
+        Command::cargo_bin("kvs-dump")
+            .unwrap()
+            .args(["--format", format, "--output", file.to_str().unwrap()])
+            .arg(&source)
+            .assert()
+            .success();
+
+        let sled_dir = temp_dir.path().join(format!("sled-{}", format));
+        Command::cargo_bin("kvs-load")
+            .unwrap()
+            .args(["--engine", "sled", "--format", format, "--batch-size", "2", "--input", file.to_str().unwrap()])
+            .arg(&sled_dir)
+            .assert()
+            .success()
+            .stderr(contains("4 pairs loaded"));
+        {
+            let store = SledKvsEngine::open(&sled_dir).unwrap();
+            let loaded: Vec<(Vec<u8>, Vec<u8>)> = store.scan_bytes(..).collect::<kvs::Result<_>>().unwrap();
+            assert_eq!(loaded, pairs);
+            check(&store, ttl);
+        }
+
+        // back from sled through standard input and output
+        let dump = Command::cargo_bin("kvs-dump")
+            .unwrap()
+            .args(["--engine", "sled", "--format", format])
+            .arg(&sled_dir)
+            .output()
+            .unwrap();
+        assert!(dump.status.success());
+        let kvs_dir = temp_dir.path().join(format!("kvs-{}", format));
+        Command::cargo_bin("kvs-load")
+            .unwrap()
+            .args(["--format", format])
+            .arg(&kvs_dir)
+            .with_stdin()
+            .buffer(dump.stdout)
+            .assert()
+            .success();
+        let store = KvStore::open(&kvs_dir).unwrap();
+        let loaded: Vec<(Vec<u8>, Vec<u8>)> = store.scan_bytes(..).collect::<kvs::Result<_>>().unwrap();
+        assert_eq!(loaded, pairs);
+        check(&store, ttl);
+    }
+}
+
+// `kvs-server --migrate` should copy the data of the engine in `kvs.conf` to the requested one
+fn cli_migrate(from: &str, to: &str, addrs: [&str; 3]) {
+    let temp_dir = TempDir::new().unwrap();
+    let client = |args: &[&str], addr: &str| {
+        let mut cmd = Command::cargo_bin("kvs-client").unwrap();
+        cmd.args(args).args(["--addr", addr]).current_dir(&temp_dir);
+        cmd
+    };
+
+    let (sender, handle) = spawn_server(temp_dir.path(), &["--engine", from, "--addr", addrs[0]]);
+    client(&["set", "key1", "value1"], addrs[0]).assert().success();
+    client(&["set", "key2", "value2"], addrs[0]).assert().success();
+    client(&["rm", "key2"], addrs[0]).assert().success();
+    client(&["set", "key4", "value4", "--ttl", "3600"], addrs[0]).assert().success();
+    sender.send(()).unwrap();
+    handle.join().unwrap();
+
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--engine", to, "--addr", addrs[1]])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("wrong engine"));
+
+    // left by an interrupted migration
+    fs::create_dir(temp_dir.path().join(to)).unwrap();
+    fs::write(temp_dir.path().join(to).join("MIGRATING"), "").unwrap();
+    fs::write(temp_dir.path().join(to).join("partial"), "data").unwrap();
+
+    let (sender, handle) = spawn_server(temp_dir.path(), &["--engine", to, "--addr", addrs[1], "--migrate"]);
+    client(&["get", "key1"], addrs[1]).assert().success().stdout("value1\n");
+    client(&["get", "key2"], addrs[1]).assert().success().stdout("Key not found\n");
+    client(&["set", "key3", "value3"], addrs[1]).assert().success();
+    sender.send(()).unwrap();
+    handle.join().unwrap();
+    let conf = fs::read_to_string(temp_dir.path().join("kvs.conf")).unwrap();
+    assert!(conf.contains(&format!("engine:\"{}\"", to)));
+    assert!(temp_dir.path().join(to).is_dir());
+    assert!(!temp_dir.path().join(to).join("MIGRATING").exists());
+    assert!(!temp_dir.path().join(to).join("partial").exists());
+
+    // the new engine is the one of `kvs.conf` now
+    let (sender, handle) = spawn_server(temp_dir.path(), &["--engine", to, "--addr", addrs[2]]);
+    client(&["get", "key1"], addrs[2]).assert().success().stdout("value1\n");
+    client(&["get", "key3"], addrs[2]).assert().success().stdout("value3\n");
+    client(&["get", "key4"], addrs[2]).assert().success().stdout("value4\n");
+    sender.send(()).unwrap();
+    handle.join().unwrap();
+
+    // the ttl was copied too
+    let dir = temp_dir.path().join(to);
+    let ttl = match to {
+        "kvs" => KvStore::open(dir).unwrap().ttl("key4".to_owned()).unwrap(),
+        _ => SledKvsEngine::open(dir).unwrap().ttl("key4".to_owned()).unwrap(),
+    };
+    assert!(ttl.is_some_and(|ttl| ttl > Duration::from_secs(3000)));
+}
+
+#[test]
+fn cli_migrate_kvs_to_sled() {
+    cli_migrate("kvs", "sled", ["127.0.0.1:4020", "127.0.0.1:4021", "127.0.0.1:4022"]);
+}
+
+#[test]
+fn cli_migrate_sled_to_kvs() {
+    cli_migrate("sled", "kvs", ["127.0.0.1:4023", "127.0.0.1:4024", "127.0.0.1:4025"]);
+}
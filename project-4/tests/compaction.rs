@@ -0,0 +1,103 @@
+use kvs::{KvStore, KvsEngine, Result};
+use std::collections::HashMap;
+use std::path::Path;
+use tempfile::TempDir;
+
+// Write some overwritten data, then compact it with the fail point enabled.
+// Return the data expected to survive.
+fn write_and_crash(store: &KvStore, fail_point: &str) -> HashMap<String, String> {
+    let mut expected = HashMap::new();
+    for iter in 0..10 {
+        for key_id in 0..1000 {
+            let key = format!("key{}", key_id);
+            let value = format!("{}", iter);
+            store.set(key.clone(), value.clone()).expect("unable to set");
+            expected.insert(key, value);
+        }
+    }
+    fail::cfg(fail_point, "return").unwrap();
+    assert!(store.compact().is_err());
+    fail::remove(fail_point);
+    // the store refuses to compact again until it is reopened
+    assert!(store.compact().is_err());
+    expected
+}
+
+// `recovered` tells whether an interrupted compaction is expected to be found.
+fn check_data(path: &Path, expected: &HashMap<String, String>, recovered: bool) -> Result<KvStore> {
+    let store = KvStore::open(path)?;
+    assert_eq!(store.recovery_report().compaction.is_some(), recovered);
+    for (key, value) in expected {
+        assert_eq!(store.get(key.to_owned())?, Some(value.to_owned()));
+    }
+    Ok(store)
+}
+
+// Kill compaction at `fail_point`, then check that reopening recovers every written value
+// and that later compactions still work.
+fn crash_and_recover(fail_point: &str, write_after_crash: bool) -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+
+    let mut expected = write_and_crash(&store, fail_point);
+    if write_after_crash {
+        for key_id in 0..100 {
+            let key = format!("key{}", key_id);
+            store.set(key.clone(), "after crash".to_owned())?;
+            expected.insert(key, "after crash".to_owned());
+        }
+    }
+    drop(store);
+
+    let store = check_data(temp_dir.path(), &expected, true)?;
+    for iter in 0..100 {
+        for key_id in 0..1000 {
+            let key = format!("key{}", key_id);
+            let value = format!("after recovery {}", iter);
+            store.set(key.clone(), value.clone())?;
+            expected.insert(key, value);
+        }
+    }
+    store.compact()?;
+    drop(store);
+    check_data(temp_dir.path(), &expected, false)?;
+    Ok(())
+}
+
+fn crash_at(fail_point: &str) -> Result<()> {
+    let scenario = fail::FailScenario::setup();
+    crash_and_recover(fail_point, false)?;
+    crash_and_recover(fail_point, true)?;
+    scenario.teardown();
+    Ok(())
+}
+
+#[test]
+fn crash_after_lock() -> Result<()> {
+    crash_at("kvs::compact::after_lock")
+}
+
+#[test]
+fn crash_after_switch() -> Result<()> {
+    crash_at("kvs::compact::after_switch")
+}
+
+#[test]
+fn crash_after_create_target() -> Result<()> {
+    crash_at("kvs::compact::after_create_target")
+}
+
+#[test]
+fn crash_during_write() -> Result<()> {
+    crash_at("kvs::compact::during_write")
+}
+
+#[test]
+fn crash_after_write() -> Result<()> {
+    crash_at("kvs::compact::after_write")
+}
+
+#[test]
+fn crash_after_remove_source() -> Result<()> {
+    crash_at("kvs::compact::after_remove_source")
+}
@@ -1,9 +1,24 @@
-use kvs::{KvStore, KvsEngine, Result};
+use kvs::{Compression, CorruptionKind, IndexMode, KvStore, KvStoreOptions, KvsEngine, KvsError, Result, SledKvsEngine, Snapshot, SyncPolicy, WriteBatch};
+use std::fs;
+use std::path::Path;
 use std::sync::{Arc, Barrier};
 use std::thread;
+use std::time::Duration;
 use tempfile::TempDir;
 use walkdir::WalkDir;
 
+// sled releases its file lock from a background thread after the last handle is dropped,
+// retry for a while before reopening fails
+fn open_sled(open: impl Fn() -> Result<SledKvsEngine>) -> Result<SledKvsEngine> {
+    for _ in 0..50 {
+        if let Ok(store) = open() {
+            return Ok(store);
+        }
+        thread::sleep(Duration::from_millis(20));
+    }
+    open()
+}
+
 // Should get previously stored value
 #[test]
 fn get_stored_value() -> Result<()> {
@@ -127,20 +142,46 @@ fn compaction() -> Result<()> {
     panic!("No compaction detected");
 }
 
+// Dropping the last clone should wait for the background compaction to finish
+#[test]
+fn drop_stops_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let clone = store.clone();
+    let handle = thread::spawn(move || -> Result<()> {
+        for iter in 0..100 {
+            for key_id in 0..1000 {
+                clone.set(format!("key{}", key_id), format!("{}", iter))?;
+            }
+        }
+        Ok(())
+    });
+    handle.join().unwrap()?;
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert!(store.recovery_report().is_clean());
+    for key_id in 0..1000 {
+        assert_eq!(store.get(format!("key{}", key_id))?, Some("99".to_owned()));
+    }
+    Ok(())
+}
+
 #[test]
 fn concurrent_set() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
     let store = KvStore::open(temp_dir.path())?;
     let barrier = Arc::new(Barrier::new(1001));
+    let mut handles = Vec::new();
     for i in 0..1000 {
         let store = store.clone();
         let barrier = barrier.clone();
-        thread::spawn(move || {
+        handles.push(thread::spawn(move || {
             store
                 .set(format!("key{}", i), format!("value{}", i))
                 .unwrap();
             barrier.wait();
-        });
+        }));
     }
     barrier.wait();
 
@@ -148,7 +189,11 @@ fn concurrent_set() -> Result<()> {
         assert_eq!(store.get(format!("key{}", i))?, Some(format!("value{}", i)));
     }
 
-    // Open from disk again and check persistent data
+    // Open from disk again and check persistent data,
+    // once the clones of the threads are dropped and the directory unlocked
+    for handle in handles {
+        handle.join().unwrap();
+    }
     drop(store);
     let store = KvStore::open(temp_dir.path())?;
     for i in 0..1000 {
@@ -209,3 +254,1345 @@ fn concurrent_get() -> Result<()> {
 
     Ok(())
 }
+
+// Flip the last byte of every log file in the directory
+fn corrupt_last_byte(dir: &std::path::Path) -> Vec<std::path::PathBuf> {
+    let mut corrupted = Vec::new();
+    for entry in WalkDir::new(dir).into_iter().flatten() {
+        let path = entry.path().to_owned();
+        if path.extension() == Some("log".as_ref()) {
+            let mut data = fs::read(&path).expect("unable to read log file");
+            if let Some(byte) = data.last_mut() {
+                *byte ^= 0xff;
+                fs::write(&path, data).expect("unable to write log file");
+                corrupted.push(path);
+            }
+        }
+    }
+    corrupted
+}
+
+// Should report the broken file and offset when a record fails its checksum
+#[test]
+fn get_corrupted_value() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+
+    let corrupted = corrupt_last_byte(temp_dir.path());
+    assert_eq!(corrupted.len(), 1);
+
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    let err = store.get("key2".to_owned()).unwrap_err();
+    match err.downcast_ref::<KvsError>() {
+        Some(KvsError::Corruption { file, offset, kind }) => {
+            assert_eq!(file, &corrupted[0]);
+            assert_eq!(*offset, 34);
+            assert_eq!(*kind, CorruptionKind::ChecksumMismatch);
+        }
+        _ => panic!("unexpected error: {}", err),
+    }
+    Ok(())
+}
+
+// Should refuse to open a store with a corrupted record in a sealed log
+#[test]
+fn open_corrupted_store() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    // seal the log, a damaged last record of the active one is a torn tail
+    store.compact()?;
+    drop(store);
+
+    corrupt_last_byte(temp_dir.path());
+
+    let err = KvStore::open(temp_dir.path()).err().expect("corrupted store opened");
+    match err.downcast_ref::<KvsError>() {
+        Some(KvsError::Corruption { offset, kind, .. }) => {
+            assert_eq!(*offset, 34);
+            assert_eq!(*kind, CorruptionKind::ChecksumMismatch);
+        }
+        _ => panic!("unexpected error: {}", err),
+    }
+    Ok(())
+}
+
+// Should drop a partially written record at the end of the active log
+#[test]
+fn truncate_torn_tail() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    assert!(store.recovery_report().is_clean());
+    drop(store);
+
+    let log = temp_dir.path().join("1.log");
+    let data = fs::read(&log).expect("unable to read log file");
+    fs::write(&log, &data[..data.len() - 3]).expect("unable to write log file");
+
+    let store = KvStore::open(temp_dir.path())?;
+    let tail = store.recovery_report().truncated.clone().expect("torn tail not reported");
+    assert_eq!(tail.file, log);
+    assert_eq!(tail.offset, 34);
+    assert_eq!(tail.dropped, 31);
+    assert_eq!(fs::metadata(&log).expect("unable to read log file").len(), 34);
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert!(store.recovery_report().is_clean());
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+    Ok(())
+}
+
+// Should drop zeros or garbage no valid record follows at the end of the active log
+#[test]
+fn truncate_garbage_tail() -> Result<()> {
+    for fill in [0u8, 0xab] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let store = KvStore::open(temp_dir.path())?;
+        store.set("key1".to_owned(), "value1".to_owned())?;
+        store.set("key2".to_owned(), "value2".to_owned())?;
+        drop(store);
+
+        // the file was extended but the record never written
+        let log = temp_dir.path().join("1.log");
+        let mut data = fs::read(&log).expect("unable to read log file");
+        data.resize(data.len() + 4096, fill);
+        fs::write(&log, data).expect("unable to write log file");
+
+        let store = KvStore::open(temp_dir.path())?;
+        let tail = store.recovery_report().truncated.clone().expect("garbage tail not reported");
+        assert_eq!(tail.offset, 68);
+        assert_eq!(tail.dropped, 4096);
+        assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    }
+    Ok(())
+}
+
+// Should refuse to open a store corrupted in the middle of the active log
+#[test]
+fn open_corrupted_middle() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    drop(store);
+
+    // damage the value length of the second record so it seems to run past the end
+    let log = temp_dir.path().join("1.log");
+    let mut data = fs::read(&log).expect("unable to read log file");
+    data[34 + 23] = 0x7f;
+    fs::write(&log, data).expect("unable to write log file");
+
+    let err = KvStore::open(temp_dir.path()).err().expect("corrupted store opened");
+    match err.downcast_ref::<KvsError>() {
+        Some(KvsError::Corruption { offset, .. }) => assert_eq!(*offset, 34),
+        _ => panic!("unexpected error: {}", err),
+    }
+    Ok(())
+}
+
+// Sealed logs should be loaded from hint files, and replayed when the hint is unusable
+#[test]
+fn restore_from_hint_files() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    // 1.log is sealed as 2.log starts, and merged into 3.log
+    let hint = temp_dir.path().join("3.hint");
+
+    let iter = 10;
+    for i in 0..iter {
+        for key_id in 0..1000 {
+            store.set(format!("key{}", key_id), format!("{}", i))?;
+        }
+        store.remove(format!("key{}", i))?;
+    }
+    store.compact()?;
+    assert!(hint.exists());
+    drop(store);
+
+    let check = |store: &KvStore| -> Result<()> {
+        for key_id in 0..1000 {
+            let expected = if key_id == iter - 1 { None } else { Some(format!("{}", iter - 1)) };
+            assert_eq!(store.get(format!("key{}", key_id))?, expected);
+        }
+        Ok(())
+    };
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.recovery_report().hints, 1);
+    check(&store)?;
+    drop(store);
+
+    let data = fs::read(&hint).expect("unable to read hint file");
+    fs::write(&hint, &data[..data.len() - 1]).expect("unable to write hint file");
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.recovery_report().hints, 0);
+    check(&store)?;
+    Ok(())
+}
+
+// A read-only store should serve reads and reject writes without touching files
+#[test]
+fn open_read_only() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new().read_only(true);
+    assert!(KvStore::open_with(temp_dir.path(), options.clone()).is_err());
+
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    for err in [
+        store.set("key1".to_owned(), "value2".to_owned()).unwrap_err(),
+        store.remove("key1".to_owned()).unwrap_err(),
+        store.compact().unwrap_err(),
+    ] {
+        assert!(matches!(err.downcast_ref::<KvsError>(), Some(KvsError::ReadOnly)));
+    }
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    Ok(())
+}
+
+// A directory should be opened by one writable store, or by several read-only ones
+#[test]
+fn directory_lock() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let in_use = |result: Result<KvStore>| {
+        matches!(result.err().unwrap().downcast_ref::<KvsError>(), Some(KvsError::InUse { .. }))
+    };
+
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    assert!(in_use(KvStore::open(temp_dir.path())));
+    assert!(in_use(KvStore::open_read_only(temp_dir.path())));
+    // clones share the lock
+    let clone = store.clone();
+    drop(store);
+    assert!(in_use(KvStore::open(temp_dir.path())));
+    drop(clone);
+
+    let reader = KvStore::open_read_only(temp_dir.path())?;
+    let other = KvStore::open_read_only(temp_dir.path())?;
+    assert!(in_use(KvStore::open(temp_dir.path())));
+    assert_eq!(reader.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(other.get("key1".to_owned())?, Some("value1".to_owned()));
+    drop(reader);
+    drop(other);
+
+    KvStore::open(temp_dir.path())?.set("key2".to_owned(), "value2".to_owned())?;
+
+    Ok(())
+}
+
+// A read-only store should open a copy of a store in a directory it can not write to,
+// without writing anything there
+#[test]
+#[cfg(unix)]
+fn read_only_directory() -> Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+
+    let copy = temp_dir.path().join("copy");
+    fs::create_dir(&copy)?;
+    for entry in fs::read_dir(temp_dir.path())? {
+        let entry = entry?;
+        if entry.file_type()?.is_file() && entry.file_name() != "LOCK" {
+            fs::copy(entry.path(), copy.join(entry.file_name()))?;
+        }
+    }
+    let listing = |dir: &Path| -> Result<Vec<_>> {
+        let mut names = fs::read_dir(dir)?.map(|entry| Ok(entry?.file_name())).collect::<Result<Vec<_>>>()?;
+        names.sort();
+        Ok(names)
+    };
+    let before = listing(&copy)?;
+    fs::set_permissions(&copy, fs::Permissions::from_mode(0o555))?;
+
+    let opened = KvStore::open_read_only(&copy).and_then(|store| store.get("key1".to_owned()));
+    let after = listing(&copy);
+    fs::set_permissions(&copy, fs::Permissions::from_mode(0o755))?;
+    assert_eq!(opened?, Some("value1".to_owned()));
+    assert_eq!(after?, before);
+    Ok(())
+}
+
+#[test]
+fn open_without_create() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new().create_if_missing(false);
+    assert!(KvStore::open_with(temp_dir.path(), options.clone()).is_err());
+    assert!(!temp_dir.path().join("MANIFEST").exists());
+
+    KvStore::open(temp_dir.path())?.set("key1".to_owned(), "value1".to_owned())?;
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    Ok(())
+}
+
+// Compaction should follow the configured threshold
+#[test]
+fn custom_compaction_threshold() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new()
+        .compaction_threshold(1024)
+        .max_segment_size(1024)
+        .sync_policy(SyncPolicy::EveryWrite);
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    for iter in 0..100 {
+        store.set("key".to_owned(), format!("{}", iter))?;
+    }
+    store.compact()?;
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key".to_owned())?, Some("99".to_owned()));
+    for entry in fs::read_dir(temp_dir.path())? {
+        let path = entry?.path();
+        if path.extension() == Some("log".as_ref()) {
+            assert!(fs::metadata(&path)?.len() <= 1024 + 64, "{} is too large", path.display());
+        }
+    }
+
+    let invalid = KvStoreOptions::new().compaction_threshold(1024).max_segment_size(512);
+    assert!(KvStore::open_with(temp_dir.path(), invalid).is_err());
+    Ok(())
+}
+
+// A store of an older format should be upgraded on open unless disabled,
+// and one of a newer format refused
+#[test]
+fn format_upgrade() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.compact()?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    drop(store);
+
+    // version 0 has a `.kvs` marker and no manifest, its logs are left as records by
+    // an upgrade interrupted after converting them, see `legacy_json_logs`
+    fs::remove_file(temp_dir.path().join("MANIFEST"))?;
+    fs::File::create(temp_dir.path().join(".kvs"))?;
+    let unsupported = |options: KvStoreOptions| -> Option<u32> {
+        match KvStore::open_with(temp_dir.path(), options).err()?.downcast_ref::<KvsError>() {
+            Some(KvsError::UnsupportedFormat { version }) => Some(*version),
+            _ => None,
+        }
+    };
+    assert_eq!(unsupported(KvStoreOptions::new().upgrade_format(false)), Some(0));
+
+    let store = KvStore::open_with(temp_dir.path(), KvStoreOptions::new().read_only(true))?;
+    assert_eq!(store.recovery_report().upgraded_from, Some(0));
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    drop(store);
+    assert!(!temp_dir.path().join("MANIFEST").exists());
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.recovery_report().upgraded_from, Some(0));
+    assert!(store.recovery_report().is_clean());
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    drop(store);
+    assert!(temp_dir.path().join("MANIFEST").exists());
+    assert!(!temp_dir.path().join(".kvs").exists());
+
+    // an empty log file left out of the manifest by a crash is deleted
+    fs::File::create(temp_dir.path().join("100.log"))?;
+    let store = KvStore::open_with(temp_dir.path(), KvStoreOptions::new().upgrade_format(false))?;
+    assert_eq!(store.recovery_report().upgraded_from, None);
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    drop(store);
+    assert!(!temp_dir.path().join("100.log").exists());
+
+    fs::write(temp_dir.path().join("MANIFEST"), r#"{"version":99}"#)?;
+    assert_eq!(unsupported(KvStoreOptions::new()), Some(99));
+    Ok(())
+}
+
+// A store written by the first release, whose logs hold JSON commands, should be converted
+// by a writable open, and refused by a read-only one without changing its files
+#[test]
+fn legacy_json_logs() -> Result<()> {
+    // `1.log` is compacted and `3.log` active, as left by the first release
+    let fixture = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/v0_json");
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    for entry in fs::read_dir(&fixture)? {
+        let entry = entry?;
+        fs::copy(entry.path(), temp_dir.path().join(entry.file_name()))?;
+    }
+    let unchanged = || -> Result<()> {
+        assert!(temp_dir.path().join(".kvs").exists());
+        assert!(!temp_dir.path().join("MANIFEST").exists());
+        for name in &["1.log", "3.log"] {
+            assert_eq!(fs::read(temp_dir.path().join(name))?, fs::read(fixture.join(name))?);
+        }
+        Ok(())
+    };
+    let check = |store: &KvStore| -> Result<()> {
+        for (key, value) in &[("a", Some("2")), ("b", Some("3")), ("c", None), ("d", Some("4")), ("gone", None)] {
+            assert_eq!(store.get(key.to_string())?, value.map(str::to_owned));
+        }
+        Ok(())
+    };
+
+    let err = KvStore::open_read_only(temp_dir.path()).err().expect("legacy store opened read-only");
+    assert!(err.to_string().contains("open it writable"), "{}", err);
+    unchanged()?;
+    let err = KvStore::open_with(temp_dir.path(), KvStoreOptions::new().upgrade_format(false)).err().expect("legacy store opened");
+    assert!(matches!(err.downcast_ref::<KvsError>(), Some(KvsError::UnsupportedFormat { version: 0 })));
+    unchanged()?;
+
+    // left by an upgrade interrupted while converting `3.log`
+    fs::write(temp_dir.path().join("3.log.upgrade"), "partial")?;
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.recovery_report().upgraded_from, Some(0));
+    check(&store)?;
+    store.set("e".to_owned(), "5".to_owned())?;
+    drop(store);
+    assert!(temp_dir.path().join("MANIFEST").exists());
+    assert!(!temp_dir.path().join(".kvs").exists());
+    assert!(!temp_dir.path().join("3.log.upgrade").exists());
+
+    // a marker left by a crash after the manifest was saved
+    fs::File::create(temp_dir.path().join(".kvs"))?;
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.recovery_report().upgraded_from, None);
+    check(&store)?;
+    assert_eq!(store.get("e".to_owned())?, Some("5".to_owned()));
+    drop(store);
+    assert!(!temp_dir.path().join(".kvs").exists());
+    Ok(())
+}
+
+// Compaction should merge the segments with enough garbage only,
+// and the segments left should open whatever their ids
+#[test]
+fn segmented_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions::new().compaction_threshold(4096).max_segment_size(4096);
+    let value = |tag: &str, i: usize| format!("{}-{:017}", tag, i);
+    let logs = || -> Vec<String> {
+        let mut names: Vec<String> = fs::read_dir(temp_dir.path()).unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .filter(|name| name.ends_with(".log"))
+            .collect();
+        names.sort();
+        names
+    };
+
+    let store = KvStore::open_with(temp_dir.path(), options.clone())?;
+    store.set("key".to_owned(), "old".to_owned())?;
+    for i in 0..10 {
+        store.set(format!("f{}", i), value("a", i))?;
+    }
+    // nothing is dead yet, the active log is only sealed
+    store.compact()?;
+    assert_eq!(logs(), ["1.log", "2.log"]);
+
+    let snapshot = store.snapshot();
+    store.set("key".to_owned(), "new".to_owned())?;
+    for i in 0..50 {
+        store.set(format!("c{}", i), value("c", i))?;
+    }
+    store.compact()?;
+    assert_eq!(logs(), ["1.log", "2.log", "3.log"]);
+
+    // 1.log is mostly dead and 3.log is small, 2.log is left out
+    for i in 0..10 {
+        store.set(format!("f{}", i), value("b", i))?;
+    }
+    store.compact()?;
+    assert_eq!(logs(), ["2.log", "5.log", "6.log"]);
+    assert_eq!(snapshot.get("key".to_owned())?, Some("old".to_owned()));
+    assert_eq!(store.get("key".to_owned())?, Some("new".to_owned()));
+    drop(snapshot);
+    drop(store);
+
+    // 5.log holds a copy of the old version, with a lower sequence number than the new one
+    for entry in fs::read_dir(temp_dir.path())? {
+        let path = entry?.path();
+        if path.extension() == Some("hint".as_ref()) {
+            fs::remove_file(path)?;
+        }
+    }
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    assert_eq!(store.recovery_report().hints, 0);
+    assert_eq!(store.get("key".to_owned())?, Some("new".to_owned()));
+    for i in 0..10 {
+        assert_eq!(store.get(format!("f{}", i))?, Some(value("b", i)));
+    }
+    for i in 0..50 {
+        assert_eq!(store.get(format!("c{}", i))?, Some(value("c", i)));
+    }
+
+    let invalid = KvStoreOptions::new().compaction_garbage_ratio(1.5);
+    assert!(KvStore::open_with(temp_dir.path(), invalid).is_err());
+    Ok(())
+}
+
+// Every sync policy should keep data across reopen, for both engines
+#[test]
+fn sync_policies() -> Result<()> {
+    fn check<E: KvsEngine>(open: impl Fn() -> Result<E>) -> Result<()> {
+        let store = open()?;
+        for key_id in 0..100 {
+            store.set(format!("key{}", key_id), format!("value{}", key_id))?;
+        }
+        store.remove("key0".to_owned())?;
+        drop(store);
+
+        let store = open()?;
+        assert_eq!(store.get("key0".to_owned())?, None);
+        for key_id in 1..100 {
+            assert_eq!(store.get(format!("key{}", key_id))?, Some(format!("value{}", key_id)));
+        }
+        Ok(())
+    }
+
+    for policy in [
+        SyncPolicy::Never,
+        SyncPolicy::EveryWrite,
+        SyncPolicy::Interval(Duration::from_millis(10)),
+        SyncPolicy::EveryNBytes(256),
+    ] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        check(|| KvStore::open_with(temp_dir.path(), KvStoreOptions::new().sync_policy(policy)))?;
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        check(|| open_sled(|| SledKvsEngine::open_with(temp_dir.path(), policy)))?;
+    }
+    Ok(())
+}
+
+// Concurrent writes to the same keys should leave the index as a replay of the log sees it
+#[test]
+fn concurrent_overwrite() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open_with(temp_dir.path(), KvStoreOptions::new().sync_policy(SyncPolicy::EveryWrite))?;
+    let handles: Vec<_> = (0..8).map(|thread_id| {
+        let store = store.clone();
+        thread::spawn(move || -> Result<()> {
+            for i in 0..200 {
+                let key = format!("key{}", i % 10);
+                if i % 7 == 0 {
+                    let _ = store.remove(key);
+                } else {
+                    store.set(key, format!("{}-{}", thread_id, i))?;
+                }
+            }
+            Ok(())
+        })
+    }).collect();
+    for handle in handles {
+        handle.join().unwrap()?;
+    }
+
+    let values: Vec<_> = (0..10).map(|i| store.get(format!("key{}", i))).collect::<Result<_>>()?;
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    for (i, value) in values.into_iter().enumerate() {
+        assert_eq!(store.get(format!("key{}", i))?, value);
+    }
+    Ok(())
+}
+
+// Scans should return keys in order, in both directions, for both engines and index modes
+#[test]
+fn scan_ranges() -> Result<()> {
+    fn check<E: KvsEngine>(store: E) -> Result<()> {
+        fn keys(scan: impl Iterator<Item = Result<(String, String)>>) -> Result<Vec<String>> {
+            scan.map(|pair| pair.map(|(key, _)| key)).collect()
+        }
+        for key in &["b", "a", "ab", "abc", "b1", "c", "abd"] {
+            store.set(key.to_string(), format!("value of {}", key))?;
+        }
+        store.remove("abc".to_owned())?;
+
+        assert_eq!(keys(store.scan(..))?, vec!["a", "ab", "abd", "b", "b1", "c"]);
+        assert_eq!(keys(store.scan(..).rev())?, vec!["c", "b1", "b", "abd", "ab", "a"]);
+        assert_eq!(keys(store.scan("ab".to_owned().."b1".to_owned()))?, vec!["ab", "abd", "b"]);
+        assert_eq!(keys(store.scan("ab".to_owned()..="b1".to_owned()).rev())?, vec!["b1", "b", "abd", "ab"]);
+        assert_eq!(keys(store.scan("b0".to_owned()..))?, vec!["b1", "c"]);
+        assert_eq!(keys(store.scan_prefix("ab".to_owned()))?, vec!["ab", "abd"]);
+        assert_eq!(keys(store.scan_prefix("ab".to_owned()).rev())?, vec!["abd", "ab"]);
+        assert_eq!(keys(store.scan_prefix("x".to_owned()))?, Vec::<String>::new());
+        assert_eq!(
+            store.scan_prefix("b1".to_owned()).next().transpose()?,
+            Some(("b1".to_owned(), "value of b1".to_owned()))
+        );
+
+        // both ends meet without yielding a pair twice
+        let mut scan = store.scan(..);
+        assert_eq!(scan.next().transpose()?.map(|(key, _)| key), Some("a".to_owned()));
+        assert_eq!(scan.next_back().transpose()?.map(|(key, _)| key), Some("c".to_owned()));
+        assert_eq!(keys(scan)?, vec!["ab", "abd", "b", "b1"]);
+        Ok(())
+    }
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    check(KvStore::open(temp_dir.path())?)?;
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    check(SledKvsEngine::open(temp_dir.path())?)?;
+
+    // a hashed index keeps no key order and refuses scans
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open_with(temp_dir.path(), KvStoreOptions::new().index_mode(IndexMode::Hashed))?;
+    store.set("a".to_owned(), "1".to_owned())?;
+    for scan in [store.scan(..), store.scan_prefix("a".to_owned()), store.snapshot().scan(..)] {
+        let pairs: Vec<_> = scan.collect();
+        assert_eq!(pairs.len(), 1);
+        let err = pairs.into_iter().next().unwrap().unwrap_err();
+        assert!(matches!(err.downcast_ref::<KvsError>(), Some(KvsError::ScanUnsupported)));
+    }
+    assert!(store.scan(..).next_back().unwrap().is_err());
+    Ok(())
+}
+
+// Arbitrary bytes should round-trip through both engines, and through log and hint files
+#[test]
+fn binary_keys_and_values() -> Result<()> {
+    fn check<E: KvsEngine>(open: impl Fn() -> Result<E>, compact: impl Fn(&E) -> Result<()>) -> Result<()> {
+        let pairs: Vec<(Vec<u8>, Vec<u8>)> = vec![
+            (vec![0xff], vec![0xc3, 0x28]),
+            (vec![0xff, 0xff, 0x00], vec![]),
+            (vec![0x00], vec![0x00; 1000]),
+            (b"text".to_vec(), (0..=255).collect()),
+        ];
+        let store = open()?;
+        for (key, value) in &pairs {
+            store.set_bytes(key.clone(), value.clone())?;
+        }
+        assert!(store.get("text".to_owned()).is_err());
+        store.set_bytes(vec![0xfe], vec![1])?;
+        store.remove_bytes(vec![0xfe])?;
+        compact(&store)?;
+        drop(store);
+
+        let store = open()?;
+        for (key, value) in &pairs {
+            assert_eq!(store.get_bytes(key.clone())?, Some(value.clone()));
+        }
+        assert_eq!(store.get_bytes(vec![0xfe])?, None);
+        let keys: Vec<Vec<u8>> = store.scan_prefix_bytes(vec![0xff])
+            .map(|pair| pair.map(|(key, _)| key))
+            .collect::<Result<_>>()?;
+        assert_eq!(keys, vec![vec![0xff], vec![0xff, 0xff, 0x00]]);
+        Ok(())
+    }
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    check(|| KvStore::open(temp_dir.path()), KvStore::compact)?;
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    check(|| open_sled(|| SledKvsEngine::open(temp_dir.path())), |_| Ok(()))?;
+    Ok(())
+}
+
+// Keys set with a ttl should disappear once expired, also after reopen and compaction
+#[test]
+fn expiring_keys() -> Result<()> {
+    fn check<E: KvsEngine>(open: impl Fn() -> Result<E>, compact: impl Fn(&E) -> Result<()>) -> Result<()> {
+        let store = open()?;
+        store.set("short".to_owned(), "old".to_owned())?;
+        store.set_with_ttl("short".to_owned(), "new".to_owned(), Duration::from_millis(200))?;
+        store.set_with_ttl("long".to_owned(), "value".to_owned(), Duration::from_secs(3600))?;
+        store.set_with_ttl("reset".to_owned(), "old".to_owned(), Duration::from_millis(200))?;
+        store.set("reset".to_owned(), "new".to_owned())?;
+        store.set_with_ttl("removed".to_owned(), "value".to_owned(), Duration::from_millis(200))?;
+        assert_eq!(store.get("short".to_owned())?, Some("new".to_owned()));
+        let ttl = store.ttl("long".to_owned())?.expect("long should have a ttl");
+        assert!(ttl <= Duration::from_secs(3600) && ttl > Duration::from_secs(3500));
+        assert_eq!(store.ttl("reset".to_owned())?, None);
+        assert_eq!(store.ttl("missing".to_owned())?, None);
+        store.remove("removed".to_owned())?;
+        drop(store);
+
+        let store = open()?;
+        assert_eq!(store.get("short".to_owned())?, Some("new".to_owned()));
+        thread::sleep(Duration::from_millis(300));
+        assert_eq!(store.get("short".to_owned())?, None);
+        assert_eq!(store.get("long".to_owned())?, Some("value".to_owned()));
+        assert_eq!(store.get("reset".to_owned())?, Some("new".to_owned()));
+        assert!(store.remove("short".to_owned()).is_err());
+        store.set_with_ttl("expired".to_owned(), "value".to_owned(), Duration::from_millis(1))?;
+        thread::sleep(Duration::from_millis(10));
+        let keys: Vec<String> = store.scan(..)
+            .map(|pair| pair.map(|(key, _)| key))
+            .collect::<Result<_>>()?;
+        assert_eq!(keys, vec!["long", "reset"]);
+        assert_eq!(store.ttl("expired".to_owned())?, None);
+        compact(&store)?;
+        drop(store);
+
+        let store = open()?;
+        assert_eq!(store.get("short".to_owned())?, None);
+        assert_eq!(store.get("expired".to_owned())?, None);
+        assert_eq!(store.get("long".to_owned())?, Some("value".to_owned()));
+        Ok(())
+    }
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    check(|| KvStore::open(temp_dir.path()), KvStore::compact)?;
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    check(|| open_sled(|| SledKvsEngine::open(temp_dir.path())), |_| Ok(()))?;
+    Ok(())
+}
+
+// Conditional writes should only apply when the current value is the expected one
+#[test]
+fn conditional_writes() -> Result<()> {
+    fn current(result: Result<()>) -> Option<Vec<u8>> {
+        match result.expect_err("condition should fail").downcast::<KvsError>() {
+            Ok(KvsError::ConditionFailed { current }) => current,
+            other => panic!("unexpected error {:?}", other),
+        }
+    }
+
+    fn check<E: KvsEngine>(open: impl Fn() -> Result<E>) -> Result<()> {
+        let store = open()?;
+        store.set_if_absent("key1".to_owned(), "value1".to_owned())?;
+        assert_eq!(current(store.set_if_absent("key1".to_owned(), "value2".to_owned())), Some(b"value1".to_vec()));
+        store.compare_and_swap("key1".to_owned(), Some("value1".to_owned()), Some("value2".to_owned()))?;
+        assert_eq!(
+            current(store.compare_and_swap("key1".to_owned(), Some("value1".to_owned()), Some("value3".to_owned()))),
+            Some(b"value2".to_vec()));
+        assert_eq!(current(store.remove_if_equals("key1".to_owned(), "value1".to_owned())), Some(b"value2".to_vec()));
+        assert_eq!(current(store.remove_if_equals("key2".to_owned(), "value1".to_owned())), None);
+        store.compare_and_swap("key2".to_owned(), None, None)?;
+        assert_eq!(store.get("key2".to_owned())?, None);
+
+        // an expired key counts as missing
+        store.set_with_ttl("key3".to_owned(), "value1".to_owned(), Duration::from_millis(1))?;
+        thread::sleep(Duration::from_millis(10));
+        store.set_if_absent("key3".to_owned(), "value2".to_owned())?;
+        // a swapped value has no ttl
+        store.set_with_ttl("key4".to_owned(), "value1".to_owned(), Duration::from_millis(200))?;
+        store.compare_and_swap("key4".to_owned(), Some("value1".to_owned()), Some("value2".to_owned()))?;
+        thread::sleep(Duration::from_millis(300));
+        assert_eq!(store.get("key4".to_owned())?, Some("value2".to_owned()));
+        drop(store);
+
+        let store = open()?;
+        assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
+        assert_eq!(store.get("key3".to_owned())?, Some("value2".to_owned()));
+        store.remove_if_equals("key1".to_owned(), "value2".to_owned())?;
+        assert_eq!(store.get("key1".to_owned())?, None);
+        Ok(())
+    }
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    check(|| KvStore::open(temp_dir.path()))?;
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    check(|| open_sled(|| SledKvsEngine::open(temp_dir.path())))?;
+    Ok(())
+}
+
+// Concurrent read-modify-write with compare-and-swap should not lose updates
+#[test]
+fn concurrent_compare_and_swap() -> Result<()> {
+    fn check<E: KvsEngine + Sync>(store: E) -> Result<()> {
+        store.set("counter".to_owned(), "0".to_owned())?;
+        let barrier = Arc::new(Barrier::new(8));
+        let handles: Vec<_> = (0..8).map(|_| {
+            let (store, barrier) = (store.clone(), barrier.clone());
+            thread::spawn(move || {
+                barrier.wait();
+                for _ in 0..100 {
+                    loop {
+                        let value = store.get("counter".to_owned()).unwrap().unwrap();
+                        let next = (value.parse::<u32>().unwrap() + 1).to_string();
+                        if store.compare_and_swap("counter".to_owned(), Some(value), Some(next)).is_ok() {
+                            break;
+                        }
+                    }
+                }
+            })
+        }).collect();
+        for handle in handles {
+            handle.join().unwrap();
+        }
+        assert_eq!(store.get("counter".to_owned())?, Some("800".to_owned()));
+        Ok(())
+    }
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    check(KvStore::open(temp_dir.path())?)?;
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    check(SledKvsEngine::open(temp_dir.path())?)?;
+    Ok(())
+}
+
+// A batch should apply all its writes in order
+#[test]
+fn write_batches() -> Result<()> {
+    fn check<E: KvsEngine>(open: impl Fn() -> Result<E>, compact: impl Fn(&E) -> Result<()>) -> Result<()> {
+        let store = open()?;
+        store.set("from".to_owned(), "value".to_owned())?;
+        store.set_with_ttl("ttl".to_owned(), "value".to_owned(), Duration::from_millis(200))?;
+        let mut batch = WriteBatch::new();
+        batch.set(b"to".to_vec(), b"value".to_vec());
+        batch.remove(b"from".to_vec());
+        batch.remove(b"missing".to_vec());
+        batch.set(b"twice".to_vec(), b"value1".to_vec());
+        batch.set(b"twice".to_vec(), b"value2".to_vec());
+        batch.set(b"ttl".to_vec(), b"value".to_vec());
+        assert_eq!(batch.len(), 6);
+        store.apply_batch(batch)?;
+        store.apply_batch(WriteBatch::new())?;
+        compact(&store)?;
+        drop(store);
+
+        let store = open()?;
+        thread::sleep(Duration::from_millis(300));
+        assert_eq!(store.get("from".to_owned())?, None);
+        assert_eq!(store.get("to".to_owned())?, Some("value".to_owned()));
+        assert_eq!(store.get("twice".to_owned())?, Some("value2".to_owned()));
+        assert_eq!(store.get("ttl".to_owned())?, Some("value".to_owned()));
+        Ok(())
+    }
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    check(|| KvStore::open(temp_dir.path()), |_| Ok(()))?;
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    check(|| KvStore::open(temp_dir.path()), KvStore::compact)?;
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    check(|| open_sled(|| SledKvsEngine::open(temp_dir.path())), |_| Ok(()))?;
+    Ok(())
+}
+
+// A batch cut short by a crash should be discarded as a whole on open
+#[test]
+fn discard_uncommitted_batch() -> Result<()> {
+    // length of the commit record closing a batch
+    const COMMIT_LEN: usize = 24;
+    for cut in [COMMIT_LEN, COMMIT_LEN + 3] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let store = KvStore::open(temp_dir.path())?;
+        store.set("key1".to_owned(), "value1".to_owned())?;
+        let mut batch = WriteBatch::new();
+        batch.set(b"key1".to_vec(), b"value2".to_vec());
+        batch.set(b"key2".to_vec(), b"value2".to_vec());
+        store.apply_batch(batch)?;
+        drop(store);
+
+        let log = temp_dir.path().join("1.log");
+        let data = fs::read(&log).expect("unable to read log file");
+        fs::write(&log, &data[..data.len() - cut]).expect("unable to write log file");
+
+        let store = KvStore::open(temp_dir.path())?;
+        let tail = store.recovery_report().truncated.clone().expect("uncommitted batch not reported");
+        assert_eq!(tail.offset, 34);
+        assert_eq!(fs::metadata(&log).expect("unable to read log file").len(), 34);
+        assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+        assert_eq!(store.get("key2".to_owned())?, None);
+
+        store.set("key3".to_owned(), "value3".to_owned())?;
+        drop(store);
+        let store = KvStore::open(temp_dir.path())?;
+        assert!(store.recovery_report().is_clean());
+        assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+    }
+    Ok(())
+}
+
+// A transaction should see its writes, apply them on commit and fail on conflicts
+#[test]
+fn transactions() -> Result<()> {
+    fn is_conflict(result: Result<()>, key: &str) -> bool {
+        match result.map_err(|e| e.downcast::<KvsError>()) {
+            Err(Ok(KvsError::Conflict { key: conflict })) => conflict == key.as_bytes(),
+            _ => false,
+        }
+    }
+
+    fn check<E: KvsEngine>(store: E, compact: impl Fn(&E) -> Result<()>) -> Result<()> {
+        store.set("from".to_owned(), "10".to_owned())?;
+        let mut txn = store.begin();
+        let from: u32 = txn.get("from".to_owned())?.unwrap().parse()?;
+        txn.set("from".to_owned(), (from - 3).to_string())?;
+        txn.set("to".to_owned(), "3".to_owned())?;
+        assert_eq!(txn.get("from".to_owned())?, Some("7".to_owned()));
+        assert!(txn.remove("missing".to_owned()).is_err());
+        assert_eq!(store.get("to".to_owned())?, None);
+        compact(&store)?;
+        txn.commit()?;
+        assert_eq!(store.get("from".to_owned())?, Some("7".to_owned()));
+        assert_eq!(store.get("to".to_owned())?, Some("3".to_owned()));
+
+        // a key read by the transaction changed
+        let mut txn = store.begin();
+        txn.get("from".to_owned())?;
+        txn.set("other".to_owned(), "value".to_owned())?;
+        store.set("from".to_owned(), "0".to_owned())?;
+        assert_eq!(txn.get("from".to_owned())?, Some("7".to_owned()));
+        assert!(is_conflict(txn.commit(), "from"));
+        assert_eq!(store.get("other".to_owned())?, None);
+
+        // the first of two transactions writing the same key wins
+        let (mut first, mut second) = (store.begin(), store.begin());
+        first.set("to".to_owned(), "1".to_owned())?;
+        second.remove("to".to_owned())?;
+        first.commit()?;
+        assert!(is_conflict(second.commit(), "to"));
+        assert_eq!(store.get("to".to_owned())?, Some("1".to_owned()));
+
+        // a missing key created meanwhile conflicts too
+        let mut txn = store.begin();
+        assert_eq!(txn.get("new".to_owned())?, None);
+        store.set("new".to_owned(), "value".to_owned())?;
+        txn.set("new".to_owned(), "txn".to_owned())?;
+        assert!(is_conflict(txn.commit(), "new"));
+
+        // a key set to another value and back conflicts too
+        let mut txn = store.begin();
+        txn.get("from".to_owned())?;
+        txn.set("to".to_owned(), "2".to_owned())?;
+        store.set("from".to_owned(), "1".to_owned())?;
+        store.set("from".to_owned(), "0".to_owned())?;
+        assert!(is_conflict(txn.commit(), "from"));
+
+        // dropped without commit
+        let mut txn = store.begin();
+        txn.remove("from".to_owned())?;
+        drop(txn);
+        assert_eq!(store.get("from".to_owned())?, Some("0".to_owned()));
+        Ok(())
+    }
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    check(KvStore::open(temp_dir.path())?, KvStore::compact)?;
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    check(SledKvsEngine::open(temp_dir.path())?, |_| Ok(()))?;
+    Ok(())
+}
+
+// A transaction should keep reading the store as it was at begin while other writers commit
+#[test]
+fn transaction_view() -> Result<()> {
+    fn check<E: KvsEngine>(store: E, compact: impl Fn(&E) -> Result<()>) -> Result<()> {
+        store.set("a".to_owned(), "1".to_owned())?;
+        store.set("b".to_owned(), "1".to_owned())?;
+        store.set("c".to_owned(), "1".to_owned())?;
+        let mut txn = store.begin();
+        assert_eq!(txn.get("a".to_owned())?, Some("1".to_owned()));
+
+        let mut other = store.begin();
+        other.set("a".to_owned(), "2".to_owned())?;
+        other.set("b".to_owned(), "2".to_owned())?;
+        other.set("new".to_owned(), "2".to_owned())?;
+        other.commit()?;
+        store.remove("c".to_owned())?;
+        store.set_with_ttl("b".to_owned(), "3".to_owned(), Duration::from_secs(3600))?;
+        compact(&store)?;
+
+        assert_eq!(txn.get("a".to_owned())?, Some("1".to_owned()));
+        assert_eq!(txn.get("b".to_owned())?, Some("1".to_owned()));
+        assert_eq!(txn.get("c".to_owned())?, Some("1".to_owned()));
+        assert_eq!(txn.get("new".to_owned())?, None);
+        let mut later = store.begin();
+        assert_eq!(later.get("b".to_owned())?, Some("3".to_owned()));
+        assert_eq!(later.get("c".to_owned())?, None);
+        // the keys read still have to be current on commit
+        txn.set("d".to_owned(), "1".to_owned())?;
+        match txn.commit().map_err(|e| e.downcast::<KvsError>()) {
+            Err(Ok(KvsError::Conflict { .. })) => {},
+            other => panic!("expected a conflict, got {:?}", other),
+        }
+        assert_eq!(store.get("d".to_owned())?, None);
+
+        // transfers between two keys keep their sum, every transaction reads it whole
+        store.set("a".to_owned(), "50".to_owned())?;
+        store.set("b".to_owned(), "50".to_owned())?;
+        let writer = {
+            let store = store.clone();
+            thread::spawn(move || -> Result<()> {
+                for i in 0..200 {
+                    let (from, to) = if i % 2 == 0 { ("a", "b") } else { ("b", "a") };
+                    let mut txn = store.begin();
+                    let left: u32 = txn.get(from.to_owned())?.unwrap().parse()?;
+                    let right: u32 = txn.get(to.to_owned())?.unwrap().parse()?;
+                    txn.set(from.to_owned(), (left - 1).to_string())?;
+                    txn.set(to.to_owned(), (right + 1).to_string())?;
+                    txn.commit()?;
+                }
+                Ok(())
+            })
+        };
+        for _ in 0..200 {
+            let mut txn = store.begin();
+            let a: u32 = txn.get("a".to_owned())?.unwrap().parse()?;
+            thread::yield_now();
+            let b: u32 = txn.get("b".to_owned())?.unwrap().parse()?;
+            assert_eq!(a + b, 100);
+        }
+        writer.join().unwrap()?;
+        Ok(())
+    }
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    check(KvStore::open(temp_dir.path())?, KvStore::compact)?;
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    check(SledKvsEngine::open(temp_dir.path())?, |_| Ok(()))?;
+    Ok(())
+}
+
+// A snapshot should keep reading the values it was taken with, through compactions
+#[test]
+fn snapshots() -> Result<()> {
+    fn pairs(snapshot: &Snapshot) -> Result<Vec<(String, String)>> {
+        snapshot.scan(..).collect()
+    }
+
+    fn owned(pairs: &[(&str, &str)]) -> Vec<(String, String)> {
+        pairs.iter().map(|(key, value)| (key.to_string(), value.to_string())).collect()
+    }
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("a".to_owned(), "1".to_owned())?;
+    store.set("b".to_owned(), "2".to_owned())?;
+    store.set("c".to_owned(), "3".to_owned())?;
+
+    let first = store.snapshot();
+    store.set("a".to_owned(), "10".to_owned())?;
+    store.remove("b".to_owned())?;
+    let mut batch = WriteBatch::new();
+    batch.set(b"d".to_vec(), b"4".to_vec());
+    batch.set(b"a".to_vec(), b"11".to_vec());
+    store.apply_batch(batch)?;
+    assert_eq!(first.get("a".to_owned())?, Some("1".to_owned()));
+    assert_eq!(first.get("b".to_owned())?, Some("2".to_owned()));
+    assert_eq!(first.get("d".to_owned())?, None);
+    assert_eq!(pairs(&first)?, owned(&[("a", "1"), ("b", "2"), ("c", "3")]));
+    let reversed: Vec<(String, String)> = first.scan(..).rev().collect::<Result<_>>()?;
+    assert_eq!(reversed, owned(&[("c", "3"), ("b", "2"), ("a", "1")]));
+
+    // older versions are copied by compaction
+    store.compact()?;
+    assert_eq!(pairs(&first)?, owned(&[("a", "1"), ("b", "2"), ("c", "3")]));
+
+    let second = store.snapshot();
+    assert!(second.seq() > first.seq());
+    store.remove("c".to_owned())?;
+    store.set("b".to_owned(), "20".to_owned())?;
+    store.set("a".to_owned(), "100".to_owned())?;
+    assert_eq!(pairs(&second)?, owned(&[("a", "11"), ("c", "3"), ("d", "4")]));
+    assert_eq!(pairs(&first)?, owned(&[("a", "1"), ("b", "2"), ("c", "3")]));
+    let range: Vec<(String, String)> = second.scan("b".to_owned()..).collect::<Result<_>>()?;
+    assert_eq!(range, owned(&[("c", "3"), ("d", "4")]));
+
+    // versions only read by the first snapshot are released with it
+    drop(first);
+    store.compact()?;
+    assert_eq!(pairs(&second)?, owned(&[("a", "11"), ("c", "3"), ("d", "4")]));
+    let err = second.set("a".to_owned(), "value".to_owned()).unwrap_err();
+    assert!(matches!(err.downcast_ref::<KvsError>(), Some(KvsError::ReadOnly)));
+    let scan = second.scan(..);
+    drop(second);
+    assert_eq!(scan.count(), 3);
+    let current: Vec<(String, String)> = store.scan(..).collect::<Result<_>>()?;
+    assert_eq!(current, owned(&[("a", "100"), ("b", "20"), ("d", "4")]));
+    drop(store);
+
+    // copies of older versions are not seen when the logs are replayed
+    for entry in fs::read_dir(temp_dir.path())? {
+        let path = entry?.path();
+        if path.extension() == Some("hint".as_ref()) {
+            fs::remove_file(path)?;
+        }
+    }
+    let store = KvStore::open(temp_dir.path())?;
+    let current: Vec<(String, String)> = store.scan(..).collect::<Result<_>>()?;
+    assert_eq!(current, owned(&[("a", "100"), ("b", "20"), ("d", "4")]));
+    Ok(())
+}
+
+// A checkpoint taken while writes continue should open with every write done before it
+#[test]
+fn checkpoints() -> Result<()> {
+    fn check<E: KvsEngine>(store: E, open: impl Fn(&Path) -> Result<E>, compact: impl Fn(&E) -> Result<()>) -> Result<()> {
+        for i in 0..100 {
+            store.set(format!("key{}", i), format!("value{}", i))?;
+        }
+        compact(&store)?;
+        store.remove("key0".to_owned())?;
+        store.set("key1".to_owned(), "new".to_owned())?;
+
+        let writer = {
+            let store = store.clone();
+            thread::spawn(move || -> Result<()> {
+                for i in 0..200 {
+                    // keys copied apart from each other
+                    let mut batch = WriteBatch::new();
+                    batch.set(format!("later{}", i).into_bytes(), b"value".to_vec());
+                    batch.set(format!("pair{}", i).into_bytes(), b"value".to_vec());
+                    store.apply_batch(batch)?;
+                }
+                Ok(())
+            })
+        };
+        let backup_dir = TempDir::new().expect("unable to create temporary working directory");
+        let dest = backup_dir.path().join("checkpoint");
+        store.checkpoint(&dest)?;
+        assert!(store.checkpoint(&dest).is_err());
+        writer.join().unwrap()?;
+        store.set("key2".to_owned(), "after".to_owned())?;
+        compact(&store)?;
+
+        let copy = open(&dest)?;
+        assert_eq!(copy.get("key0".to_owned())?, None);
+        assert_eq!(copy.get("key1".to_owned())?, Some("new".to_owned()));
+        assert_eq!(copy.get("key2".to_owned())?, Some("value2".to_owned()));
+        for i in 3..100 {
+            assert_eq!(copy.get(format!("key{}", i))?, Some(format!("value{}", i)));
+        }
+        // every write seen by the copy is complete
+        let later: Vec<(String, String)> = copy.scan_prefix("later".to_owned()).collect::<Result<_>>()?;
+        assert!(later.iter().all(|(_, value)| value == "value"));
+        for i in 0..200 {
+            assert_eq!(copy.get(format!("later{}", i))?, copy.get(format!("pair{}", i))?);
+        }
+        // the copy is independent from the store
+        copy.set("key3".to_owned(), "copy".to_owned())?;
+        assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+        assert_eq!(store.get("key2".to_owned())?, Some("after".to_owned()));
+        Ok(())
+    }
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    check(KvStore::open(temp_dir.path())?, |path| KvStore::open(path), KvStore::compact)?;
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    check(SledKvsEngine::open(temp_dir.path())?, |path| SledKvsEngine::open(path), |_| Ok(()))?;
+    Ok(())
+}
+
+// Records of every codec can be mixed in a log, and survive compaction and reopening
+#[test]
+fn compressed_values() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let json = |i: usize| format!(r#"{{"id":{},"name":"user {}","tags":["alpha","beta","gamma"],"bio":"{}"}}"#, i, i, "lorem ipsum ".repeat(20));
+    let log_size = || fs::metadata(temp_dir.path().join("1.log")).map(|m| m.len()).unwrap_or(0);
+
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("a0".to_owned(), json(0))?;
+    let plain = log_size();
+    assert_eq!(store.stats().compression_ratio(), 1.0);
+    drop(store);
+
+    for (i, compression) in [Compression::Lz4, Compression::Zstd].iter().enumerate() {
+        let store = KvStore::open_with(temp_dir.path(), KvStoreOptions::new().compression(*compression))?;
+        let before = log_size();
+        store.set(format!("a{}", i + 1), json(i + 1))?;
+        assert!(log_size() - before < plain / 2, "{:?} did not shrink the record", compression);
+        // values too short to shrink are stored as they are
+        store.set(format!("b{}", i + 1), "x".to_owned())?;
+        store.set_with_ttl(format!("c{}", i + 1), json(i + 1), Duration::from_secs(3600))?;
+
+        let stats = store.stats();
+        assert_eq!(stats.values_written, 3);
+        assert_eq!(stats.value_bytes, 2 * json(i + 1).len() as u64 + 1);
+        assert!(stats.compression_ratio() > 2.0);
+        for j in 0..=i + 1 {
+            assert_eq!(store.get(format!("a{}", j))?, Some(json(j)));
+        }
+        assert_eq!(store.get(format!("b{}", i + 1))?, Some("x".to_owned()));
+        assert_eq!(store.get(format!("c{}", i + 1))?, Some(json(i + 1)));
+    }
+
+    let store = KvStore::open(temp_dir.path())?;
+    store.compact()?;
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    for j in 0..3 {
+        assert_eq!(store.get(format!("a{}", j))?, Some(json(j)));
+    }
+    assert_eq!(store.get("c2".to_owned())?, Some(json(2)));
+    Ok(())
+}
+
+// Large values go to blob files, which are collected once mostly overwritten
+#[test]
+fn blob_values() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = || KvStoreOptions::new().blob_threshold(Some(1024)).blob_file_size(64 * 1024);
+    let value = |i: usize, round: usize| format!("{}-{}-", i, round).repeat(2000);
+    let files = |ext: &str| -> Vec<String> {
+        let mut names: Vec<String> = fs::read_dir(temp_dir.path()).unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .filter(|name| name.ends_with(ext))
+            .collect();
+        names.sort();
+        names
+    };
+    let log_size = || files(".log").iter()
+        .map(|name| fs::metadata(temp_dir.path().join(name)).unwrap().len())
+        .sum::<u64>();
+    // blob files deleted but still held open by this process, `None` without procfs
+    let deleted_open = || fs::read_dir("/proc/self/fd").ok().map(|fds| fds.flatten()
+        .filter_map(|fd| fs::read_link(fd.path()).ok())
+        .filter(|target| target.starts_with(temp_dir.path()) && target.to_string_lossy().ends_with(".blob (deleted)"))
+        .count());
+
+    let store = KvStore::open_with(temp_dir.path(), options())?;
+    for i in 0..20 {
+        store.set(format!("key{}", i), value(i, 0))?;
+    }
+    store.set("small".to_owned(), "value".to_owned())?;
+    let first_blobs = files(".blob");
+    assert!(first_blobs.len() > 1);
+    assert!(log_size() < 4096, "values were written to the log");
+
+    // older versions read by a snapshot keep their blob file alive
+    let snapshot = store.snapshot();
+    for i in 0..18 {
+        store.set(format!("key{}", i), value(i, 1))?;
+    }
+    store.compact()?;
+    for i in 0..20 {
+        assert_eq!(snapshot.get(format!("key{}", i))?, Some(value(i, 0)));
+    }
+    assert!(first_blobs.iter().all(|name| files(".blob").contains(name)));
+
+    drop(snapshot);
+    store.compact()?;
+    assert!(first_blobs.iter().any(|name| !files(".blob").contains(name)), "no blob file collected");
+    // the readers of collected files are dropped by the next compaction
+    store.compact()?;
+    assert!(deleted_open().is_none_or(|count| count == 0), "collected blob files still open");
+    let check = |store: &KvStore| -> Result<()> {
+        for i in 0..20 {
+            let round = if i < 18 { 1 } else { 0 };
+            assert_eq!(store.get(format!("key{}", i))?, Some(value(i, round)));
+        }
+        assert_eq!(store.get("small".to_owned())?, Some("value".to_owned()));
+        Ok(())
+    };
+    check(&store)?;
+
+    let backup = TempDir::new().expect("unable to create temporary working directory");
+    store.checkpoint(&backup.path().join("copy"))?;
+    check(&KvStore::open(backup.path().join("copy"))?)?;
+    drop(store);
+    check(&KvStore::open_with(temp_dir.path(), options())?)?;
+
+    // version 1 found blob files by name, and a file no longer listed is left by a crash
+    let manifest_path = temp_dir.path().join("MANIFEST");
+    let mut manifest: serde_json::Value = serde_json::from_slice(&fs::read(&manifest_path)?)?;
+    let listed = manifest["blobs"].take();
+    manifest["version"] = 1.into();
+    manifest.as_object_mut().unwrap().remove("blobs");
+    fs::write(&manifest_path, serde_json::to_vec(&manifest)?)?;
+    let store = KvStore::open_with(temp_dir.path(), options())?;
+    assert_eq!(store.recovery_report().upgraded_from, Some(1));
+    check(&store)?;
+    drop(store);
+    let manifest: serde_json::Value = serde_json::from_slice(&fs::read(&manifest_path)?)?;
+    assert_eq!(manifest["blobs"], listed);
+    fs::write(temp_dir.path().join("999.blob"), "stale")?;
+    check(&KvStore::open_with(temp_dir.path(), options())?)?;
+    assert!(!temp_dir.path().join("999.blob").exists());
+    Ok(())
+}
+
+// A hashed index should see the same pairs as an ordered one, through compaction and reopening
+#[test]
+fn hashed_index() -> Result<()> {
+    let ordered_dir = TempDir::new().expect("unable to create temporary working directory");
+    let hashed_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = |mode| KvStoreOptions::new().index_mode(mode).compaction_threshold(16 * 1024).max_segment_size(64 * 1024);
+    let ordered = KvStore::open_with(ordered_dir.path(), options(IndexMode::Ordered))?;
+    let hashed = KvStore::open_with(hashed_dir.path(), options(IndexMode::Hashed))?;
+    let check = |hashed: &KvStore| -> Result<()> {
+        for i in 0..300 {
+            let key = format!("key{}", i);
+            assert_eq!(hashed.get(key.clone())?, ordered.get(key)?);
+        }
+        Ok(())
+    };
+
+    let snapshot = hashed.snapshot();
+    for round in 0..20 {
+        for i in 0..200 {
+            let key = format!("key{}", (i * 7 + round * 13) % 300);
+            for store in [&ordered, &hashed].iter() {
+                match i % 5 {
+                    0 => { store.remove(key.clone()).ok(); },
+                    1 => store.set_with_ttl(key.clone(), format!("{}", round), Duration::from_millis(1))?,
+                    _ => store.set(key.clone(), format!("{}-{}", round, i))?,
+                }
+            }
+        }
+    }
+    thread::sleep(Duration::from_millis(5));
+    check(&hashed)?;
+    assert_eq!(snapshot.get("key0".to_owned())?, None);
+    drop(snapshot);
+
+    hashed.compact()?;
+    drop(hashed);
+    let hashed = KvStore::open_with(hashed_dir.path(), options(IndexMode::Hashed))?;
+    check(&hashed)?;
+    // the same log read through an ordered index
+    drop(hashed);
+    let reopened = KvStore::open_with(hashed_dir.path(), options(IndexMode::Ordered))?;
+    let pairs = |store: &KvStore| -> Result<Vec<(String, String)>> { store.scan(..).collect() };
+    assert_eq!(pairs(&reopened)?, pairs(&ordered)?);
+    Ok(())
+}
+
+// Cached values should follow writes, removals and compaction, within the cache size
+#[test]
+fn value_cache() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open_with(temp_dir.path(), KvStoreOptions::new().cache_size(4096))?;
+    let counters = |store: &KvStore| (store.stats().cache_hits, store.stats().cache_misses);
+
+    store.set("key".to_owned(), "v1".to_owned())?;
+    assert_eq!(store.get("key".to_owned())?, Some("v1".to_owned()));
+    assert_eq!(store.get("key".to_owned())?, Some("v1".to_owned()));
+    assert_eq!(counters(&store), (1, 1));
+
+    store.set("key".to_owned(), "v2".to_owned())?;
+    assert_eq!(store.get("key".to_owned())?, Some("v2".to_owned()));
+    assert_eq!(store.get_versioned_bytes(b"key".to_vec())?.0, Some(b"v2".to_vec()));
+    assert_eq!(counters(&store), (2, 2));
+
+    store.compare_and_swap("key".to_owned(), Some("v2".to_owned()), Some("v3".to_owned()))?;
+    store.compact()?;
+    assert_eq!(store.get("key".to_owned())?, Some("v3".to_owned()));
+    assert_eq!(store.get("key".to_owned())?, Some("v3".to_owned()));
+    assert_eq!(counters(&store), (4, 3));
+
+    store.remove("key".to_owned())?;
+    assert_eq!(store.get("key".to_owned())?, None);
+
+    // far more values than the cache holds, every one read twice in a row
+    for i in 0..100 {
+        store.set(format!("key{}", i), format!("{}", i).repeat(100))?;
+    }
+    for i in 0..100 {
+        for _ in 0..2 {
+            assert_eq!(store.get(format!("key{}", i))?, Some(format!("{}", i).repeat(100)));
+        }
+    }
+    assert_eq!(counters(&store), (104, 103));
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key".to_owned(), "value".to_owned())?;
+    store.get("key".to_owned())?;
+    assert_eq!(counters(&store), (0, 0));
+    Ok(())
+}